@@ -0,0 +1,22 @@
+extern crate wna;
+use wna::*;
+
+fn main() {
+    let mut wna = Wna::new();
+    wna
+        .icon(Icon::file("resources/ico.ico"))
+        .tip("Events")
+        .menu_item(MenuItem::action("Hello".to_string(), |_| { }))
+        .menu_item(MenuItem::Separator)
+        .menu_item(MenuItem::action("Quit".to_string(), |_| { }));
+    let mut wna = wna.build_with_events().unwrap();
+    let hello = wna.find_menu_item("Hello");
+    let quit = wna.find_menu_item("Quit");
+    while let Some(event) = wna.recv_event() {
+        match event {
+            Event::Menu(id) if Some(id) == hello => println!("Hello!"),
+            Event::Menu(id) if Some(id) == quit => { let _ = wna.close(); },
+            _ => { }
+        }
+    }
+}
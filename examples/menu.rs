@@ -5,21 +5,18 @@ fn main() {
     println!("hello!");
     let mut wna = Wna::new();
     wna
-        .icon(Icon::File("resources/ico.ico".to_string()))
+        .icon(Icon::file("resources/ico.ico"))
         .tip("Hello!")
         .menu_item(MenuItem::action("Quit".to_string(), quit))
         .menu_item(MenuItem::Separator)
         .menu_item(MenuItem::action("Show balloon".to_string(), move |wna| show_balloon(wna, "Balloon body")))
         .menu_item(MenuItem::Separator)
-        .menu_item(MenuItem::action("Add item".to_string(), add_item));
-    let mut wna = wna.build().unwrap();
-    ::std::thread::sleep(::std::time::Duration::from_millis(5000));
-    let _ = wna.show_balloon("Greeting", "Hello, world!", |_| println!("greeting balloon clicked"));
-    wna.join_event_loop();
-    /*
-    ::std::thread::sleep(::std::time::Duration::from_millis(15000));
-    let _ = wna.close();
-    */
+        .menu_item(MenuItem::action("Add item".to_string(), add_item))
+        .on_ready(|wna| {
+            let _ = wna.show_balloon("Greeting", "Hello, world!", |_| println!("greeting balloon clicked"));
+        });
+    let wna = wna.build().unwrap();
+    wna.run().unwrap();
 }
 
 fn quit(wna: &mut Wna) {
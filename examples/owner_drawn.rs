@@ -0,0 +1,44 @@
+#[cfg(windows)]
+extern crate winapi;
+extern crate wna;
+use wna::*;
+
+#[cfg(windows)]
+use winapi::um::wingdi::{RGB, SetBkMode, SetTextColor, TRANSPARENT};
+#[cfg(windows)]
+use winapi::um::winuser::*;
+
+fn main() {
+    let mut wna = Wna::new();
+    wna
+        .icon(Icon::file("resources/ico.ico"))
+        .tip("Owner-drawn menu")
+        .menu_item(MenuItem::owner_drawn(0, measure, draw, |_| println!("red item clicked")))
+        .menu_item(MenuItem::Separator)
+        .menu_item(MenuItem::action("Quit".to_string(), |wna| { let _ = wna.close(); }));
+    let wna = wna.build().unwrap();
+    wna.join_event_loop();
+}
+
+fn measure(_: &MeasureContext) -> (u32, u32) {
+    (120, 24)
+}
+
+#[cfg(windows)]
+fn draw(ctx: &DrawContext) {
+    let text: Vec<u16> = "Red item".encode_utf16().collect();
+    let mut rect = ctx.rect;
+    rect.left += 24;
+    unsafe {
+        let background = if ctx.selected { COLOR_HIGHLIGHT } else { COLOR_MENU };
+        FillRect(ctx.hdc, &ctx.rect, GetSysColorBrush(background));
+        SetBkMode(ctx.hdc, TRANSPARENT as i32);
+        SetTextColor(ctx.hdc, RGB(0xff, 0, 0));
+        DrawTextW(ctx.hdc, text.as_ptr(), text.len() as i32, &mut rect, DT_SINGLELINE | DT_VCENTER | DT_LEFT);
+    }
+}
+
+// there is no GDI to draw with elsewhere
+#[cfg(not(windows))]
+fn draw(_: &DrawContext) {
+}
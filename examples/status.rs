@@ -0,0 +1,45 @@
+#[cfg(windows)]
+extern crate winapi;
+extern crate wna;
+use wna::*;
+
+use std::collections::HashMap;
+#[cfg(windows)]
+use std::ptr;
+
+#[cfg(windows)]
+use winapi::um::winuser::{IDI_APPLICATION, LoadIconW};
+
+#[derive(Hash, PartialEq, Eq)]
+enum Status {
+    Online,
+    Offline,
+}
+
+fn main() {
+    let mut status_icons = HashMap::new();
+    status_icons.insert(Status::Online, (Icon::file("resources/ico.ico"), "Online".to_string()));
+    status_icons.insert(Status::Offline, (offline_icon(), "Offline".to_string()));
+    let mut wna = Wna::new();
+    wna
+        .icon(Icon::file("resources/ico.ico"))
+        .tip("Online")
+        .status_icons(status_icons)
+        .menu_item(MenuItem::action("Go online".to_string(), |wna| { let _ = wna.set_status(Status::Online); }))
+        .menu_item(MenuItem::action("Go offline".to_string(), |wna| { let _ = wna.set_status(Status::Offline); }))
+        .menu_item(MenuItem::Separator)
+        .menu_item(MenuItem::quit());
+    let wna = wna.build().unwrap();
+    wna.join_event_loop();
+}
+
+// a handle made by the application; the system's icon is shared, so it is never destroyed
+#[cfg(windows)]
+fn offline_icon() -> Icon {
+    Icon::Hicon(unsafe { LoadIconW(ptr::null_mut(), IDI_APPLICATION) })
+}
+
+#[cfg(not(windows))]
+fn offline_icon() -> Icon {
+    Icon::file("resources/ico.ico")
+}
@@ -0,0 +1,156 @@
+#[cfg(any(test, feature = "mock"))]
+use std::sync::Arc;
+#[cfg(not(windows))]
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use super::{HMENU, HWND, Result};
+use super::{DrawAction, Icon, MeasureAction, MenuAlignment, MenuInsertMode, MenuItemInfo, MenuItemState};
+#[cfg(feature = "balloons")]
+use super::{NotificationOptions, SuppressionReason};
+#[cfg(any(test, feature = "mock"))]
+use mock::MockShared;
+
+pub type ThreadCallback = Box<dyn FnOnce() + Send + 'static>;
+
+pub type MenuOpeningCallback = Box<dyn Fn() + Send + Sync + 'static>;
+
+// only the Win32 window reads the entries outside of tests
+#[cfg_attr(not(windows), allow(dead_code))]
+pub enum MenuEntry {
+    Item(u32, String),
+    TriState(u32, String, MenuItemState),
+    OwnerDrawn(u32, usize, MeasureAction, DrawAction),
+    Separator(u32),
+}
+
+#[cfg(windows)]
+pub use window::{lock, pump_once, win32_message};
+
+// Elsewhere there is no window thread to keep serving while waiting for the lock.
+#[cfg(not(windows))]
+pub fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(not(windows))]
+pub fn pump_once() -> bool {
+    false
+}
+
+#[cfg(not(windows))]
+pub fn win32_message(_code: u32) -> String {
+    String::new()
+}
+
+// The operations `Repr` needs from whatever shows the icon and its menu, so that `lib.rs`
+// does not depend on the Win32 window. Implemented by `window::Window` and, with the `mock`
+// feature and in the crate's own tests, by `mock::MockWindow`; `Repr` holds it as a
+// `Box<dyn TrayBackend>`, made by the function `WnaBuilder::build_repr` is given.
+pub trait TrayBackend: Send {
+    fn thread_id(&self) -> Option<u32>;
+    fn hmenu(&self) -> Option<HMENU>;
+    fn is_window_thread(&self) -> bool;
+    // whether the tray lives in a window of the application, with no thread of its own
+    fn is_attached(&self) -> bool;
+    fn run_on_thread(&self, f: ThreadCallback) -> Result<()>;
+    fn set_icon(&self, icon: &Icon) -> Result<()>;
+    fn set_tip(&self, tip: &str) -> Result<()>;
+    fn clear_icon_cache(&self) -> Result<()>;
+    fn start_animation(&self, frames: Vec<Icon>, interval: Duration) -> Result<u32>;
+    fn stop_animation(&self, id: u32) -> Result<()>;
+    fn set_busy_icon(&self, icon: Option<&Icon>) -> Result<()>;
+    fn set_timer(&mut self, interval: Duration) -> Result<u32>;
+    fn kill_timer(&self, id: u32) -> Result<()>;
+    fn add_menu_entry(&mut self, entry: MenuEntry, mode: MenuInsertMode) -> Result<()>;
+    fn insert_separator_after(&mut self, after: u32, id: u32) -> Result<()>;
+    fn set_menu(&mut self, entries: Vec<MenuEntry>) -> Result<()>;
+    fn set_menu_item_state(&mut self, id: u32, state: MenuItemState) -> Result<()>;
+    fn set_menu_item_enabled(&self, id: u32, enabled: bool) -> Result<()>;
+    fn set_menu_item_help(&self, id: u32, help: Option<String>);
+    fn set_menu_item_check_bitmaps(&self, id: u32, checked: Option<Icon>, unchecked: Option<Icon>) -> Result<()>;
+    fn menu_item_ids(&self) -> Result<Vec<u32>>;
+    fn menu_snapshot(&self) -> Result<Vec<MenuItemInfo>>;
+    fn find_menu_item(&self, label: &str) -> Result<Option<u32>>;
+    fn remove_menu_item(&self, id: u32) -> Result<()>;
+    #[cfg(feature = "balloons")]
+    fn show_balloon(&self, title: &str, body: &str, options: &NotificationOptions) -> Result<()>;
+    #[cfg(feature = "balloons")]
+    fn show_balloon_with_tip(&self, tip: &str, title: &str, body: &str, options: &NotificationOptions) -> Result<()>;
+    #[cfg(feature = "balloons")]
+    fn notification_suppression(&self) -> Result<Option<SuppressionReason>>;
+    fn set_visible(&mut self, visible: bool) -> Result<bool>;
+    fn is_icon_visible(&self) -> Result<bool>;
+    fn set_menu_opening_callback(&self, callback: Option<MenuOpeningCallback>);
+    fn set_attention_window(&mut self, hwnd: Option<HWND>);
+    fn set_main_window(&self, hwnd: Option<HWND>, hide_on_focus_loss: bool) -> Result<()>;
+    fn flush(&self) -> Result<()>;
+    fn icon_dpi(&self) -> Result<u32>;
+    fn open_menu_at(&self, x: i32, y: i32, align: MenuAlignment) -> Result<()>;
+    fn request_attention(&self) -> Result<()>;
+    fn is_closed(&self) -> bool;
+    fn close(&mut self);
+    // what a mock backend was asked to show; the real window is not one
+    #[cfg(any(test, feature = "mock"))]
+    fn mock(&self) -> Option<Arc<MockShared>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use mock::MockWindow;
+    use MenuItemKind;
+
+    #[test]
+    fn lock_is_exclusive_across_threads() {
+        let counter = Arc::new(Mutex::new(0));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let counter = Arc::clone(&counter);
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    *lock(&counter) += 1;
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock(&counter), 8000);
+    }
+
+    #[test]
+    fn lock_takes_over_a_poisoned_mutex() {
+        let value = Arc::new(Mutex::new(1));
+        let poisoner = Arc::clone(&value);
+        let _ = thread::spawn(move || {
+            let _guard = lock(&poisoner);
+            panic!("poisoning the lock");
+        }).join();
+        assert!(value.is_poisoned());
+        *lock(&value) += 1;
+        assert_eq!(*lock(&value), 2);
+    }
+
+    #[test]
+    fn repr_only_needs_the_trait() {
+        let mut window: Box<dyn TrayBackend> = Box::new(MockWindow::new());
+        window.set_tip("tip").unwrap();
+        window.add_menu_entry(MenuEntry::Item(0, "Open".to_string()), MenuInsertMode::Append).unwrap();
+        window.add_menu_entry(MenuEntry::Separator(1), MenuInsertMode::Append).unwrap();
+        window.add_menu_entry(MenuEntry::Item(2, "First".to_string()), MenuInsertMode::Prepend).unwrap();
+        assert_eq!(window.menu_item_ids().unwrap(), vec![2, 0, 1]);
+        assert_eq!(window.menu_snapshot().unwrap()[2].kind, MenuItemKind::Separator);
+        assert_eq!(window.find_menu_item("Open").unwrap(), Some(0));
+        window.remove_menu_item(0).unwrap();
+        assert_eq!(window.menu_item_ids().unwrap(), vec![2, 1]);
+        window.close();
+        assert!(window.is_closed());
+        assert!(window.set_tip("tip").is_err());
+    }
+
+}
@@ -1,262 +1,4003 @@
+//! Windows Notification Area: a tray icon with a popup menu and balloon notifications.
+//!
+//! # Cargo features
+//!
+//! * `balloons` (default) — balloon notifications: `Wna::show_balloon`, `Wna::show_notification`
+//!   and `NotificationOptions`. Disable it for an icon-and-menu-only build.
+//! * `mock` — `WnaBuilder::build_mock`, which builds the tray against an in-memory fake
+//!   of the notification area, so that an application can test its tray on any platform.
+//!
+//! # Threading
+//!
+//! The hidden window lives on a thread of its own (`wna-window-loop`), and actions run
+//! on the event loop thread (`wna-event-loop`). `Wna` can be cloned and used from any
+//! thread, as its state is kept behind a mutex. Calls that change the icon, the tooltip
+//! or the menu, or show a balloon, are passed on to the window thread and wait for it
+//! to make them, so that they never race with a menu being shown. Called on the window
+//! thread itself, e.g. from `WnaBuilder::on_menu_opening`, they are made right away.
+//!
+//! The tray stays up as long as a `Wna` for it is around: dropping the last one,
+//! clones included, closes the tray and stops the event loop.
+
 #[macro_use]
 extern crate error_chain;
 extern crate winapi;
 
+mod backend;
+// the crate's own tests run against it too
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+#[cfg(not(windows))]
+mod sys;
+#[cfg(windows)]
 mod window;
 
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "balloons")]
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "balloons")]
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::*;
 use std::thread;
+use std::time::Duration;
+#[cfg(feature = "balloons")]
+use std::time::Instant;
+
+#[cfg(windows)]
+use winapi::shared::windef::{HDC, HICON, HMENU, HWND, RECT};
+#[cfg(windows)]
+use winapi::um::winuser::WS_EX_TOOLWINDOW;
+
+use backend::{MenuEntry, TrayBackend};
+#[cfg(not(windows))]
+use sys::{HDC, HICON, HMENU, HWND, RECT};
 
 error_chain! {
 
+    errors {
+        ActionsNotReplaceable(ids: Vec<u32>) {
+            description("menu item actions not replaceable")
+            display("Actions of toggle, data and quit menu items cannot be replaced: {:?}", ids)
+        }
+        IconFileNotFound(path: String) {
+            description("icon file not found")
+            display("Icon file not found: '{}'", path)
+        }
+        InvalidWindowClass(class: String) {
+            description("invalid window class name")
+            display("Invalid window class name: '{}'", class)
+        }
+        MenuItemNotFound(id: u32) {
+            description("menu item not found")
+            display("Menu item {} not found", id)
+        }
+        MenuItemsNotFound(ids: Vec<u32>) {
+            description("menu items not found")
+            display("Menu items not found: {:?}", ids)
+        }
+        NoInteractiveSession {
+            description("no interactive session")
+            display("The process runs outside an interactive session, e.g. as a service, where there is no notification area")
+        }
+        UnknownStatus {
+            description("unknown status")
+            display("Status is not registered with WnaBuilder::status_icons")
+        }
+        WindowClosed {
+            description("window is closed")
+            display("Window is closed")
+        }
+        WindowCreationTimeout {
+            description("window creation timed out")
+            display("Window thread did not report back in time")
+        }
+        HResult(operation: Op, code: u32) {
+            description("shell call failed")
+            display("wna: failed to {} (HRESULT {:#010x}: {})", operation, code, backend::win32_message(*code))
+        }
+        Win32(operation: Op, code: u32) {
+            description("Win32 call failed")
+            display("wna: failed to {} (Win32 error {}: {})", operation, code, backend::win32_message(*code))
+        }
+    }
+
+}
+
+impl Error {
+
+    /// The `GetLastError` code of a failed Win32 call, if this error came from one.
+    pub fn win32_code(&self) -> Option<u32> {
+        match *self.kind() {
+            ErrorKind::Win32(_, code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The HRESULT returned by a failed shell call, if this error came from one.
+    pub fn hresult(&self) -> Option<u32> {
+        match *self.kind() {
+            ErrorKind::HResult(_, code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// What `wna` was doing when a Win32 or shell call failed, if this error came from one.
+    pub fn operation(&self) -> Option<&Op> {
+        match *self.kind() {
+            ErrorKind::HResult(ref operation, _) | ErrorKind::Win32(ref operation, _) => Some(operation),
+            _ => None,
+        }
+    }
+
+}
+
+/// The operation that failed in an `ErrorKind::Win32` or `ErrorKind::HResult` error.
+///
+/// Error messages are meant for people and may change from one version to the next;
+/// match on this and on `Error::win32_code` or `Error::hresult` instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    AddHelpTool,
+    AddIcon,
+    AddMenuItem,
+    AddMenuSeparator,
+    AddOverflowSubmenu,
+    AddOwnerDrawnMenuItem,
+    CreateBitmap,
+    CreateDeviceContext,
+    CreateHelpTooltip,
+    CreatePopupMenu,
+    CreateWindow,
+    DeleteIcon,
+    GetCursorPos,
+    GetIconRect,
+    GetMenuItemCount,
+    GetMenuItemInfo,
+    GetMessage,
+    GetModuleFileName,
+    GetModuleHandle,
+    /// Loading the icon file at the path.
+    LoadIconFromFile(String),
+    LoadIconFromResource,
+    PlaySound,
+    PostToWindowThread,
+    QueryNotificationState,
+    RegisterWindowClass,
+    RegisterWindowMessage,
+    RemoveMenuItem,
+    SetDpiAwareness,
+    SetFocusHook,
+    SetIcon,
+    SetIconState,
+    SetIconVersion,
+    SetMenuInfo,
+    SetMenuItemState,
+    SetThreadPriority,
+    SetTimer,
+    SetTip,
+    ShowBalloon,
+    SubclassWindow,
+}
+
+impl fmt::Display for Op {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operation = match *self {
+            Op::AddHelpTool => "add menu help tool",
+            Op::AddIcon => "add taskbar icon",
+            Op::AddMenuItem => "add menu item",
+            Op::AddMenuSeparator => "add menu separator",
+            Op::AddOverflowSubmenu => "add overflow submenu",
+            Op::AddOwnerDrawnMenuItem => "add owner-drawn menu item",
+            Op::CreateBitmap => "create bitmap",
+            Op::CreateDeviceContext => "create device context",
+            Op::CreateHelpTooltip => "create menu help tooltip",
+            Op::CreatePopupMenu => "create popup menu",
+            Op::CreateWindow => "create window",
+            Op::DeleteIcon => "delete taskbar icon",
+            Op::GetCursorPos => "get cursor position",
+            Op::GetIconRect => "get taskbar icon rect",
+            Op::GetMenuItemCount => "get menu item count",
+            Op::GetMenuItemInfo => "get menu item info",
+            Op::GetMessage => "get window message",
+            Op::GetModuleFileName => "get executable path",
+            Op::GetModuleHandle => "get current module handle",
+            Op::LoadIconFromFile(ref path) => return write!(f, "load icon from file '{}'", path),
+            Op::LoadIconFromResource => "load icon from resource",
+            Op::PlaySound => "play notification sound",
+            Op::PostToWindowThread => "post to window thread",
+            Op::QueryNotificationState => "query user notification state",
+            Op::RegisterWindowClass => "register window class",
+            Op::RegisterWindowMessage => "register window message",
+            Op::RemoveMenuItem => "remove menu item",
+            Op::SetDpiAwareness => "set process DPI awareness",
+            Op::SetFocusHook => "watch the foreground window",
+            Op::SetIcon => "set taskbar icon",
+            Op::SetIconState => "set taskbar icon state",
+            Op::SetIconVersion => "set taskbar icon version",
+            Op::SetMenuInfo => "set popup menu info",
+            Op::SetMenuItemState => "set menu item state",
+            Op::SetThreadPriority => "set thread priority",
+            Op::SetTimer => "set timer",
+            Op::SetTip => "set taskbar icon tooltip",
+            Op::ShowBalloon => "show balloon",
+            Op::SubclassWindow => "subclass window",
+        };
+        f.write_str(operation)
+    }
+
+}
+
+// how many more times a persistent balloon is shown
+#[cfg(feature = "balloons")]
+const MAX_BALLOON_RESHOWS: u32 = 3;
+
+// the overflow balloon is shown again no sooner than this, and only for a new count
+#[cfg(feature = "balloons")]
+const OVERFLOW_BALLOON_INTERVAL_SECS: u64 = 60;
+
+// RegisterClassW rejects class names longer than this
+const MAX_WINDOW_CLASS_LEN: usize = 256;
+
+// an idle action may itself send the window a message, so it is never run more often,
+// lest the queue never stay empty
+#[cfg(windows)]
+const MIN_IDLE_THROTTLE: Duration = Duration::from_millis(100);
+
+// where the threads `wna` spawns leave the message of a panic that ended them
+type PanicSlot = Arc<Mutex<Option<String>>>;
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<Any>".to_string()
+    }
+}
+
+pub type MenuItemId = u32;
+
+// The application's own command ids are usually small, so an attached tray numbers its
+// items from here to keep clear of them; WM_COMMAND only carries the low 16 bits.
+const ATTACHED_FIRST_MENU_ID: MenuItemId = 0x8000;
+
+pub type Action = Box<dyn Fn(&mut Wna) -> ActionResult + Send + Sync + 'static>;
+
+/// What the event loop should do once an action has run.
+/// Actions returning `()` are treated as `Continue`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionResult {
+    Continue,
+    Quit,
+}
+
+impl From<()> for ActionResult {
+    fn from(_: ()) -> ActionResult {
+        ActionResult::Continue
+    }
 }
 
-pub type Action = Box<Fn(&mut Wna) -> () + Send + Sync + 'static>;
+fn boxed_action<F, R>(action: F) -> Action
+        where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+              R: Into<ActionResult> {
+    Box::new(move |wna| action(wna).into())
+}
 
+#[derive(Clone)]
 pub enum Icon {
     File(String),
     ResourceByName(String),
     ResourceByOrd(u16),
+    /// An icon handle owned by the application, which must keep it alive
+    /// while it is shown and destroy it afterwards.
+    Hicon(HICON),
+    /// An icon handle whose ownership passes to `wna`, see `Icon::owned`.
+    OwnedHicon(Arc<OwnedIconHandle>),
+    /// The executable's own icon, as Explorer shows it, so that a tray application
+    /// needs no separate `.ico` file. Falls back to the system's application icon
+    /// if the executable has none.
+    SelfExe,
 }
 
-pub enum MenuItem {
-    Action(String, Action),
-    Separator,
+// icon handles are not tied to the thread that created them
+unsafe impl Send for Icon { }
+
+impl Icon {
+
+    /// An `.ico` file, relative to the working directory unless the path is absolute.
+    pub fn file<S: Into<String>>(path: S) -> Icon {
+        Icon::File(path.into())
+    }
+
+    /// An icon resource of the executable, e.g. one added by a `.rc` file.
+    pub fn resource_by_name<S: Into<String>>(name: S) -> Icon {
+        Icon::ResourceByName(name.into())
+    }
+
+    pub fn resource_by_ord(ord: u16) -> Icon {
+        Icon::ResourceByOrd(ord)
+    }
+
+    /// Takes over `hicon`, which is destroyed once the icon and all its clones are
+    /// dropped, i.e. once `wna` no longer shows it and the application holds no copy.
+    pub fn owned(hicon: HICON) -> Icon {
+        Icon::OwnedHicon(Arc::new(OwnedIconHandle(hicon)))
+    }
+
 }
 
-impl MenuItem {
+/// An icon handle that belongs to `wna`, shared by the clones of an `Icon::OwnedHicon`.
+pub struct OwnedIconHandle(HICON);
 
-    pub fn action<F>(title: String, action: F) -> MenuItem
-            where F: Fn(&mut Wna) -> () + Send + Sync + 'static {
-        MenuItem::Action(title, Box::new(action))
+// see `Icon`
+unsafe impl Send for OwnedIconHandle { }
+unsafe impl Sync for OwnedIconHandle { }
+
+impl OwnedIconHandle {
+
+    pub fn hicon(&self) -> HICON {
+        self.0
     }
 
 }
 
-pub enum Event {
-    Menu(u32),
-    Balloon,
-    Quit,
+pub type ToggleAction = Box<dyn Fn(&mut Wna, bool) -> ActionResult + Send + Sync + 'static>;
+
+pub type DataAction = Box<dyn Fn(&mut Wna, &(dyn Any + Send + Sync)) -> ActionResult + Send + Sync + 'static>;
+
+pub type UnhandledMenuAction = Box<dyn FnMut(MenuItemId) + Send + 'static>;
+
+pub type CustomEventAction = Box<dyn FnMut(&mut Wna, u32) + Send + 'static>;
+
+pub type MenuOpeningAction = Box<dyn Fn(&mut Wna) + Send + Sync + 'static>;
+
+#[cfg(feature = "balloons")]
+pub type BalloonAction = Box<dyn Fn(&mut Wna, &BalloonContext) -> ActionResult + Send + Sync + 'static>;
+
+pub type MeasureAction = Box<dyn Fn(&MeasureContext) -> (u32, u32) + Send + Sync + 'static>;
+
+pub type DrawAction = Box<dyn Fn(&DrawContext) + Send + Sync + 'static>;
+
+/// Passed to the measure callback of an owner-drawn menu item,
+/// which returns the item's width and height.
+pub struct MeasureContext {
+    pub hdc: HDC,
+    pub data: usize,
 }
 
-pub struct Wna {
-    repr: Arc<Mutex<Repr>>,
-    thread: Option<thread::JoinHandle<()>>,
+/// Passed to the draw callback of an owner-drawn menu item.
+pub struct DrawContext {
+    pub hdc: HDC,
+    pub rect: RECT,
+    pub data: usize,
+    pub selected: bool,
+    pub disabled: bool,
 }
 
-#[derive(Default)]
-pub struct WnaBuilder {
+/// Where `Wna::add_menu_item` puts new items, see `WnaBuilder::menu_insert_mode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MenuInsertMode {
+    #[default]
+    Append,
+    Prepend,
+}
 
-    window_class: Option<&'static str>,
-    icon: Option<Icon>,
-    tip: Option<String>,
-    menu_items: Vec<MenuItem>,
+/// Which corner of the menu is placed at the point passed to `Wna::open_menu_at`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuAlignment {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    /// The point is the center of the menu.
+    Center,
+}
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuItemState {
+    Checked,
+    Unchecked,
+    /// Shown as a dash in place of the checkmark.
+    Indeterminate,
 }
 
-impl Wna {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MenuItemKind {
+    Item,
+    OwnerDrawn,
+    Separator,
+    Submenu(Vec<MenuItemInfo>),
+}
 
-    pub fn new() -> WnaBuilder {
-        WnaBuilder::default()
+/// A menu item as read back from the native menu by `Wna::menu_snapshot`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MenuItemInfo {
+    pub id: MenuItemId,
+    pub label: String,
+    pub kind: MenuItemKind,
+    pub enabled: bool,
+    /// Also set for items in the indeterminate state.
+    pub checked: bool,
+}
+
+/// Scheduling priority of the threads `wna` spawns, see `WnaBuilder::thread_priority`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadPriority {
+    Idle,
+    Lowest,
+    BelowNormal,
+    Normal,
+    AboveNormal,
+    Highest,
+}
+
+/// Labels used by the convenience menu items, for localization. English by default.
+pub struct Strings {
+    pub quit: String,
+    /// Body of the balloon shown by `Wna::prompt_to_pin`.
+    pub pin_prompt: String,
+    /// Label of the submenu holding the items past `WnaBuilder::menu_max_items`.
+    pub more_items: String,
+    /// Body of the balloon shown by `WnaBuilder::overflow_balloon`,
+    /// with `{}` standing for the number of items in that submenu.
+    pub more_items_balloon: String,
+}
+
+impl Default for Strings {
+
+    fn default() -> Strings {
+        Strings {
+            quit: "Quit".to_string(),
+            pin_prompt: "Pin this icon for quick access".to_string(),
+            more_items: "More...".to_string(),
+            more_items_balloon: "{} more items available".to_string(),
+        }
     }
 
-    pub fn set_icon(&mut self, icon: &Icon) -> Result<()> {
-        let mut lock = self.repr.lock().unwrap();
-        lock.set_icon(icon)
+}
+
+pub enum MenuItem {
+    Action(String, Action),
+    /// Closes the tray; labeled with `Strings::quit`.
+    Quit,
+    TriState(String, MenuItemState, Action),
+    /// A checkable item whose check state is flipped before the action
+    /// is called with the new state.
+    Toggle(String, bool, ToggleAction),
+    /// An item whose action is passed the data stored with it.
+    ActionWithData(String, Box<dyn Any + Send + Sync>, DataAction),
+    /// An item with a help text, shown next to it while it is selected.
+    ActionWithHelp(String, String, Action),
+    OwnerDrawn {
+        data: usize,
+        measure: MeasureAction,
+        draw: DrawAction,
+        action: Action,
+    },
+    Separator,
+}
+
+impl MenuItem {
+
+    pub fn action<F, R>(title: String, action: F) -> MenuItem
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        MenuItem::Action(title, boxed_action(action))
     }
 
-    pub fn set_tip(&mut self, tip: &str) -> Result<()> {
-        let mut lock = self.repr.lock().unwrap();
-        lock.set_tip(tip)
+    pub fn quit() -> MenuItem {
+        MenuItem::Quit
     }
 
-    pub fn add_menu_item(&mut self, item: MenuItem) -> Result<()> {
-        let mut lock = self.repr.lock().unwrap();
-        lock.add_menu_item(item)
+    pub fn tri_state<F, R>(title: String, state: MenuItemState, action: F) -> MenuItem
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        MenuItem::TriState(title, state, boxed_action(action))
     }
 
-    pub fn show_balloon<F>(&mut self, title: &str, body: &str, action: F) -> Result<()>
-            where F: Fn(&mut Wna) -> () + Send + Sync + 'static {
-        let mut lock = self.repr.lock().unwrap();
-        lock.show_balloon(title, body, Box::new(action))
+    pub fn toggle<F, R>(title: String, checked: bool, action: F) -> MenuItem
+            where F: Fn(&mut Wna, bool) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        MenuItem::Toggle(title, checked, Box::new(move |wna, checked| action(wna, checked).into()))
     }
 
-    pub fn close(&mut self) -> Result<()> {
-        let mut lock = self.repr.lock().unwrap();
-        lock.close()
+    /// An item that passes `data` to `action`, so that one handler can serve many items.
+    pub fn action_with_data<T, F, R>(title: String, data: T, action: F) -> MenuItem
+            where T: Send + Sync + 'static,
+                  F: Fn(&mut Wna, &T) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        MenuItem::ActionWithData(title, Box::new(data), Box::new(move |wna, data| {
+            match data.downcast_ref::<T>() {
+                Some(data) => action(wna, data).into(),
+                None => ActionResult::Continue,
+            }
+        }))
     }
 
-    pub fn join_event_loop(self) {
-        if let Some(thread) = self.thread {
-            let _ = thread.join();
+    /// Popup menus have no status bar, so the help text is shown
+    /// in a tooltip next to the item while it is selected.
+    pub fn action_with_help<F, R>(title: String, help: String, action: F) -> MenuItem
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        MenuItem::ActionWithHelp(title, help, boxed_action(action))
+    }
+
+    pub fn owner_drawn<M, D, F, R>(data: usize, measure: M, draw: D, action: F) -> MenuItem
+            where M: Fn(&MeasureContext) -> (u32, u32) + Send + Sync + 'static,
+                  D: Fn(&DrawContext) + Send + Sync + 'static,
+                  F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        MenuItem::OwnerDrawn {
+            data,
+            measure: Box::new(measure),
+            draw: Box::new(draw),
+            action: boxed_action(action),
         }
     }
 
 }
 
-impl Clone for Wna {
+/// Why the shell is holding notifications back, see `Wna::show_balloon_checked`.
+#[cfg(feature = "balloons")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SuppressionReason {
+    /// The screen saver is running, the machine is locked or a user switch is in progress.
+    NotPresent,
+    /// A full-screen application is running.
+    Busy,
+    /// A full-screen Direct3D application is running.
+    FullScreenD3d,
+    /// The user has turned on presentation mode.
+    PresentationMode,
+    /// The first hour after a new user's first sign-in.
+    QuietTime,
+    /// A Windows Store app is in the foreground.
+    App,
+    /// The application has muted notifications with `Wna::set_notifications_muted`.
+    Muted,
+}
 
-    fn clone(&self) -> Self {
-        Wna {
-            repr: Arc::clone(&self.repr),
-            thread: None,
-        }
+/// Passed to the action of the balloon that was clicked, as the shell does not tell
+/// which one it was: with several balloons queued, it is the oldest one still pending.
+#[cfg(feature = "balloons")]
+pub struct BalloonContext {
+    /// The title as shown, after `NotificationOptions::no_title` and `tip_as_title`.
+    pub title: String,
+    pub body: String,
+    data: Option<Box<dyn Any + Send + Sync>>,
+}
+
+#[cfg(feature = "balloons")]
+impl BalloonContext {
+
+    /// The data passed to `Wna::show_notification_with_data`,
+    /// or `None` if there is none or it is not a `T`.
+    pub fn data<T: Any>(&self) -> Option<&T> {
+        self.data.as_ref().and_then(|data| data.downcast_ref::<T>())
     }
 
 }
 
-impl WnaBuilder {
+/// What became of a balloon passed to `Wna::show_balloon_checked`.
+#[cfg(feature = "balloons")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BalloonOutcome {
+    /// The shell has accepted the balloon, and the user accepts notifications.
+    Shown,
+    /// The balloon was not shown, as the user does not accept notifications right now,
+    /// or stopped accepting them while it was passed to the shell.
+    Suppressed(SuppressionReason),
+    /// The shell refused the balloon.
+    Failed,
+}
 
-    pub fn window_class(&mut self, class: &'static str) -> &mut Self {
-        self.window_class = Some(class);
+/// The standard icon shown next to the balloon title.
+#[cfg(feature = "balloons")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotificationLevel {
+    None,
+    Info,
+    Warning,
+    Error,
+}
+
+/// How a balloon is shown. Each balloon keeps its own options while it waits
+/// in the shell's queue behind the ones shown before it.
+#[cfg(feature = "balloons")]
+#[derive(Clone, Default)]
+pub struct NotificationOptions {
+    sound: Option<PathBuf>,
+    level: Option<NotificationLevel>,
+    icon: Option<Icon>,
+    timeout: Option<Duration>,
+    no_title: bool,
+    tip_as_title: bool,
+    persistent: bool,
+    shown_action: Option<Arc<Action>>,
+}
+
+#[cfg(feature = "balloons")]
+impl NotificationOptions {
+
+    pub fn new() -> NotificationOptions {
+        NotificationOptions::default()
+    }
+
+    /// Plays a `.wav` file instead of the default notification sound.
+    ///
+    /// This is a convenience: the sound is started asynchronously right after
+    /// the balloon is handed to the shell and is not synchronized with the
+    /// moment the balloon actually appears.
+    pub fn sound<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.sound = Some(path.into());
+        self
+    }
+
+    /// `NotificationLevel::Info` by default.
+    pub fn level(&mut self, level: NotificationLevel) -> &mut Self {
+        self.level = Some(level);
         self
     }
 
+    /// Shows `icon` in place of the level icon.
     pub fn icon(&mut self, icon: Icon) -> &mut Self {
         self.icon = Some(icon);
         self
     }
 
-    pub fn tip(&mut self, tip: &str) -> &mut Self {
-        self.tip = Some(tip.to_string());
+    /// How long the balloon stays up, 30 seconds by default.
+    /// Windows Vista and later ignore it in favour of the accessibility settings.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
         self
     }
 
-    pub fn menu_item(&mut self, item: MenuItem) -> &mut Self {
-        self.menu_items.push(item);
+    /// Leaves the title out, whatever is passed to `Wna::show_notification`;
+    /// the shell then shows the balloon without a title line.
+    pub fn no_title(&mut self) -> &mut Self {
+        self.no_title = true;
         self
     }
 
-    pub fn build(self) -> Result<Wna> {
-        let (sender, reciever) = channel();
-        let window_class = self.window_class.unwrap_or("wna_window_class");
-        let window = window::Window::create(window_class, sender.clone())?;
-        let mut repr = Repr {
-            window: window,
-            last_menu_id: 0,
-            actions: HashMap::new(),
-            balloon_action: None,
-            event_sender: sender,
-        };
-        if let Some(ref icon) = self.icon {
-            repr.set_icon(icon)?;
-        }
-        if let Some(ref tip) = self.tip {
-            repr.set_tip(tip)?;
-        }
-        for item in self.menu_items {
-            repr.add_menu_item(item)?;
+    /// Uses the current tooltip as the title, e.g. to have the application name
+    /// on every balloon without repeating it.
+    pub fn tip_as_title(&mut self) -> &mut Self {
+        self.tip_as_title = true;
+        self
+    }
+
+    /// Shows the balloon again when it times out or is closed without a click, up to
+    /// three more times, or until `Wna::stop_persistent_balloons` is called. A balloon
+    /// that the shell hides for another reason, e.g. as the icon is removed, is let go.
+    ///
+    /// Balloons have no way to stay up until acknowledged, so this is only an emulation:
+    /// the shell and the user's settings still decide how long each one is shown.
+    pub fn persistent(&mut self, persistent: bool) -> &mut Self {
+        self.persistent = persistent;
+        self
+    }
+
+    /// Called when the balloon actually comes up, which may be a while after it is
+    /// passed to the shell if other balloons are queued before it, or the user is busy.
+    pub fn on_shown<F, R>(&mut self, action: F) -> &mut Self
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.shown_action = Some(Arc::new(boxed_action(action)));
+        self
+    }
+
+}
+
+enum MenuOperation {
+    Add(MenuItem),
+    Remove(MenuItemId),
+}
+
+/// Collects menu changes to be applied at once by `Wna::batch`.
+pub struct MenuBatch {
+    operations: Vec<MenuOperation>,
+}
+
+impl MenuBatch {
+
+    pub fn add_menu_item(&mut self, item: MenuItem) -> &mut Self {
+        self.operations.push(MenuOperation::Add(item));
+        self
+    }
+
+    pub fn remove_menu_item(&mut self, id: MenuItemId) -> &mut Self {
+        self.operations.push(MenuOperation::Remove(id));
+        self
+    }
+
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Menu(u32),
+    /// The oldest pending balloon has come up on the screen.
+    #[cfg(feature = "balloons")]
+    BalloonShown,
+    #[cfg(feature = "balloons")]
+    Balloon,
+    /// The balloon timed out or was closed without being clicked.
+    #[cfg(feature = "balloons")]
+    BalloonDismissed,
+    /// The balloon went away for another reason, e.g. as the icon was removed.
+    #[cfg(feature = "balloons")]
+    BalloonHidden,
+    /// The action of the oldest pending balloon was dropped to make room for a new one,
+    /// see `WnaBuilder::max_pending_balloons`.
+    #[cfg(feature = "balloons")]
+    BalloonDropped,
+    Timer(u32),
+    /// The display configuration has changed. The icon is re-added first
+    /// if the shell has dropped it.
+    DisplayChanged,
+    /// The icon is in the notification area and the window thread is handling
+    /// messages. Sent once, after `WnaBuilder::build` has set the icon, tooltip and menu.
+    Ready,
+    /// Posted by the application with `Wna::post_event`.
+    Custom(u32),
+    /// The icon was clicked with the left button, or selected with the keyboard;
+    /// only sent if `WnaBuilder::on_left_click` is set.
+    LeftClick,
+    /// The icon was clicked with the right button, or Shift+F10 or the Apps key
+    /// was pressed on it; only sent if `WnaBuilder::on_right_click` is set.
+    RightClick,
+    /// The menu was opened with this many items in the `Strings::more_items` submenu;
+    /// only sent if `WnaBuilder::overflow_balloon` is set.
+    #[cfg(feature = "balloons")]
+    MenuOverflow(usize),
+    /// The window thread has handled every message in its queue;
+    /// only sent if `WnaBuilder::on_idle` is set.
+    Idle,
+    Quit,
+}
+
+/// Stops its timer when dropped.
+pub struct IntervalHandle {
+    id: u32,
+    repr: Option<Weak<Mutex<Repr>>>,
+}
+
+impl IntervalHandle {
+
+    /// Lets the timer outlive the handle; it then runs until the window is closed.
+    pub fn detach(mut self) {
+        self.repr = None;
+    }
+
+}
+
+impl Drop for IntervalHandle {
+    fn drop(&mut self) {
+        if let Some(repr) = self.repr.take().and_then(|repr| repr.upgrade()) {
+            let mut lock = backend::lock(&repr);
+            let _ = lock.kill_timer(self.id);
         }
-        let repr = Arc::new(Mutex::new(repr));
-        let thread = start_event_loop(reciever, Arc::clone(&repr));
-        Ok(Wna {
-            repr: repr,
-            thread: Some(thread),
-        })
     }
+}
 
+/// Identifies an animation started with `Wna::animate_icon`.
+#[derive(Debug)]
+pub struct AnimationHandle {
+    id: u32,
 }
 
-struct Repr {
-    window: window::Window,
-    last_menu_id: u32,
-    actions: HashMap<u32, Arc<Action>>,
-    balloon_action: Option<Action>,
-    event_sender: Sender<Event>,
+pub struct Wna {
+    repr: Arc<Mutex<Repr>>,
+    // shared by all clones, so that only the first `close` reaches the window
+    closed: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+    events: Option<Receiver<Event>>,
 }
 
-impl Repr {
+#[derive(Default)]
+pub struct WnaBuilder {
 
-    fn next_menu_id(&mut self) -> u32 {
-        let id = self.last_menu_id;
-        self.last_menu_id += 1;
-        id
+    window_class: Option<&'static str>,
+    icon: Option<Icon>,
+    window_icon: Option<Icon>,
+    tip: Option<String>,
+    accessible_name: Option<String>,
+    menu_items: Vec<MenuItem>,
+    quit_action: Option<Action>,
+    unhandled_menu_action: Option<UnhandledMenuAction>,
+    custom_event_action: Option<CustomEventAction>,
+    display_changed_action: Option<Action>,
+    idle_action: Option<(Duration, Action)>,
+    left_click_action: Option<Action>,
+    right_click_action: Option<Action>,
+    ready_action: Option<Action>,
+    busy_icon: Option<Icon>,
+    menu_opening_action: Option<MenuOpeningAction>,
+    #[cfg(feature = "balloons")]
+    max_pending_balloons: Option<usize>,
+    #[cfg(feature = "balloons")]
+    balloon_dropped_action: Option<Action>,
+    #[cfg(feature = "balloons")]
+    app_name: Option<String>,
+    #[cfg(feature = "balloons")]
+    startup_balloon: Option<(String, String)>,
+    #[cfg(feature = "balloons")]
+    overflow_balloon: bool,
+    show_standard_tooltip: Option<bool>,
+    menu_hover_delay: Option<Duration>,
+    menu_max_items: Option<usize>,
+    menu_insert_mode: MenuInsertMode,
+    right_to_left: bool,
+    window_ex_style: Option<u32>,
+    message_only: Option<bool>,
+    dpi_aware: bool,
+    keep_focus: bool,
+    update_debounce: Option<Duration>,
+    creation_timeout: Option<Duration>,
+    thread_name_prefix: Option<String>,
+    thread_priority: Option<ThreadPriority>,
+    strings: Strings,
+    status_icons: Option<Box<dyn Any + Send>>,
+    existing_window: Option<HWND>,
+
+}
+
+impl Wna {
+
+    fn with_repr(repr: Arc<Mutex<Repr>>, thread: Option<thread::JoinHandle<()>>, events: Option<Receiver<Event>>) -> Wna {
+        let closed = Arc::clone(&backend::lock(&repr).closed);
+        Wna {
+            repr,
+            closed,
+            thread,
+            events,
+        }
+    }
+
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> WnaBuilder {
+        WnaBuilder::default()
     }
 
     pub fn set_icon(&mut self, icon: &Icon) -> Result<()> {
-        self.window.set_icon(icon)
+        let mut lock = backend::lock(&self.repr);
+        lock.set_icon(icon)
+    }
+
+    /// Destroys the icons kept from earlier `set_icon` and `set_status` calls.
+    /// Icons loaded from files and resources are kept, so that switching between
+    /// a few of them does not load them again.
+    pub fn clear_icon_cache(&mut self) -> Result<()> {
+        let lock = backend::lock(&self.repr);
+        lock.window.clear_icon_cache()
     }
 
     pub fn set_tip(&mut self, tip: &str) -> Result<()> {
-        self.window.set_tip(tip)
+        let mut lock = backend::lock(&self.repr);
+        lock.set_tip(tip)
     }
 
-    pub fn add_menu_item(&mut self, item: MenuItem) -> Result<()> {
-        match item {
-            MenuItem::Action(title, action) => {
-                let id = self.next_menu_id();
-                self.window.add_menu_item(id, &title)?;
-                self.actions.insert(id, Arc::new(action));
-                Ok(())
-            },
-            MenuItem::Separator => {
-                let id = self.next_menu_id();
-                self.window.add_menu_separator(id)
-            }
-        }
+    /// Replaces the whole menu with `items`.
+    ///
+    /// The new menu is built completely before it is swapped in, so a click never
+    /// sees a partially updated menu. Items of the new menu get fresh ids.
+    pub fn set_menu(&mut self, items: Vec<MenuItem>) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_menu(items)
     }
 
-    pub fn show_balloon(&mut self, title: &str, body: &str, action: Action) -> Result<()> {
-        self.window.show_balloon(title, body)?;
-        self.balloon_action = Some(action);
-        Ok(())
+    /// Sets the check state of a menu item. Any plain item can be checked this way;
+    /// items added as `MenuItem::TriState` start in their given state.
+    pub fn set_menu_item_state(&mut self, id: MenuItemId, state: MenuItemState) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_menu_item_state(id, state)
     }
 
-    pub fn close(&mut self) -> Result<()> {
-        self.window.close();
-        let _ = self.event_sender.send(Event::Quit);
-        Ok(())
+    /// Points an existing menu item to a new action, leaving its label as is.
+    pub fn set_menu_item_action<F, R>(&mut self, id: MenuItemId, action: F) -> Result<()>
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_menu_item_action(id, boxed_action(action))
     }
 
-}
+    /// Swaps the actions of all menu items at once, e.g. after reloading a plugin,
+    /// without rebuilding the menu. Items left out of `actions` no longer have one,
+    /// so clicks on them go to `WnaBuilder::on_unhandled_menu`, except for toggle,
+    /// data and quit items, which keep theirs.
+    ///
+    /// Fails with `ErrorKind::MenuItemsNotFound`, listing every id that is not
+    /// in the menu, or with `ErrorKind::ActionsNotReplaceable`, listing the ids of
+    /// toggle, data and quit items, and then leaves the actions as they were.
+    pub fn replace_actions(&mut self, actions: HashMap<MenuItemId, Action>) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.replace_actions(actions)
+    }
 
-impl Drop for Repr {
-    fn drop(&mut self) {
-        let _ = self.close();
+    pub fn set_menu_item_enabled(&mut self, id: MenuItemId, enabled: bool) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_menu_item_enabled(id, enabled)
     }
-}
 
-fn start_event_loop(receiver: Receiver<Event>, repr: Arc<Mutex<Repr>>) -> thread::JoinHandle<()> {
-    thread::Builder::new().name("wna-event-loop".into()).spawn(move || {
-        loop {
-            match receiver.recv() {
-                Ok(event) => match event {
-                    Event::Menu(id) => {
+    /// Shows `checked` and `unchecked` in the checkmark slot of the item in place of
+    /// the default checkmark and blank, e.g. on/off glyphs for a toggle. `None` restores
+    /// the default. The icons are scaled to the checkmark size, and kept until the item
+    /// is removed. Items in the indeterminate state keep their dash.
+    pub fn set_menu_item_check_bitmaps(&mut self, id: MenuItemId, checked: Option<Icon>, unchecked: Option<Icon>) -> Result<()> {
+        let lock = backend::lock(&self.repr);
+        lock.window.set_menu_item_check_bitmaps(id, checked, unchecked)
+    }
+
+    /// Disables or re-enables the whole menu, e.g. while a long operation runs.
+    ///
+    /// Re-enabling the menu keeps items disabled with `set_menu_item_enabled` disabled.
+    pub fn set_menu_enabled(&mut self, enabled: bool) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_menu_enabled(enabled)
+    }
+
+    pub fn remove_menu_item(&mut self, id: MenuItemId) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.remove_menu_item(id)
+    }
+
+    /// Removes every item for which `pred`, given the item's id and label, returns `false`,
+    /// along with its action. Separators and submenus are kept, though the items
+    /// of submenus are checked too.
+    ///
+    /// `pred` runs without the lock held, so it may call back into `Wna`.
+    ///
+    /// An item that cannot be removed does not stop the others from going: the first
+    /// error is returned once all have been tried, and the menu is then left with the
+    /// items `pred` kept plus those that failed to go, each with its action.
+    pub fn retain_menu_items<F>(&mut self, mut pred: F) -> Result<()> where F: FnMut(MenuItemId, &str) -> bool {
+        let snapshot = self.menu_snapshot()?;
+        let mut removed = Vec::new();
+        collect_not_retained(&snapshot, &mut pred, &mut removed);
+        let mut lock = backend::lock(&self.repr);
+        let mut first_error = None;
+        for id in removed {
+            if let Err(e) = lock.remove_menu_item(id) {
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Inserts a separator right below the item `id` and returns the separator's id.
+    pub fn insert_separator_after(&mut self, id: MenuItemId) -> Result<MenuItemId> {
+        let mut lock = backend::lock(&self.repr);
+        lock.insert_separator_after(id)
+    }
+
+    /// Finds a menu item by its label, ignoring `&` mnemonic markers.
+    /// If several items share the label, the topmost one is returned.
+    pub fn find_menu_item(&self, label: &str) -> Option<MenuItemId> {
+        let lock = backend::lock(&self.repr);
+        lock.window.find_menu_item(label).unwrap_or(None)
+    }
+
+    /// Reads the current menu back from the native menu, top to bottom.
+    pub fn menu_snapshot(&self) -> Result<Vec<MenuItemInfo>> {
+        let lock = backend::lock(&self.repr);
+        lock.window.menu_snapshot()
+    }
+
+    /// Collects menu changes made by `f` and applies them under a single lock.
+    ///
+    /// Nothing is applied until `f` returns, so a panic inside `f` leaves the menu untouched.
+    /// Changes are applied in order and stop at the first error.
+    pub fn batch<F>(&mut self, f: F) -> Result<()>
+            where F: FnOnce(&mut MenuBatch) {
+        let mut batch = MenuBatch {
+            operations: Vec::new(),
+        };
+        f(&mut batch);
+        let mut lock = backend::lock(&self.repr);
+        for operation in batch.operations {
+            match operation {
+                MenuOperation::Add(item) => {
+                    let mode = lock.menu_insert_mode;
+                    lock.add_menu_item(item, mode)?
+                }
+                MenuOperation::Remove(id) => lock.remove_menu_item(id)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches the icon and tooltip to the ones registered for `status`
+    /// with `WnaBuilder::status_icons`.
+    pub fn set_status<S>(&mut self, status: S) -> Result<()>
+            where S: Hash + Eq + Send + 'static {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_status(status)
+    }
+
+    pub fn show(&mut self) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_visible(true)
+    }
+
+    /// Hides the icon while keeping it registered with the shell.
+    pub fn hide(&mut self) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_visible(false)
+    }
+
+    /// Removes the tooltip.
+    ///
+    /// The shell has no way to unset `NIF_TIP` once it was given, so this sends an empty
+    /// tooltip text, which the shell treats as "no tooltip". Screen readers then have
+    /// nothing to announce for the icon, unless an accessible name is set.
+    pub fn clear_tip(&mut self) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.clear_tip()
+    }
+
+    /// Sets the text screen readers announce for the icon when no tooltip is shown,
+    /// see `WnaBuilder::accessible_name`.
+    pub fn set_accessible_name(&mut self, name: &str) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.set_accessible_name(name)
+    }
+
+    pub fn tip(&self) -> Option<String> {
+        let lock = backend::lock(&self.repr);
+        lock.tip.clone()
+    }
+
+    pub fn add_menu_item(&mut self, item: MenuItem) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        let mode = lock.menu_insert_mode;
+        lock.add_menu_item(item, mode)
+    }
+
+    /// Adds `item` at the top of the menu, whatever `WnaBuilder::menu_insert_mode` says.
+    pub fn prepend_menu_item(&mut self, item: MenuItem) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.add_menu_item(item, MenuInsertMode::Prepend)
+    }
+
+    #[cfg(feature = "balloons")]
+    pub fn show_balloon<F, R>(&mut self, title: &str, body: &str, action: F) -> Result<()>
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.show_notification(title, body, &NotificationOptions::default(), action)
+    }
+
+    /// Shows a balloon titled with `WnaBuilder::app_name`.
+    #[cfg(feature = "balloons")]
+    pub fn show_balloon_body_only<F, R>(&mut self, body: &str, action: F) -> Result<()>
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.show_notification("", body, &NotificationOptions::default(), action)
+    }
+
+    #[cfg(feature = "balloons")]
+    pub fn show_notification<F, R>(&mut self, title: &str, body: &str, options: &NotificationOptions, action: F) -> Result<()>
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.show_balloon_action(None, title, body, options, None, Box::new(move |wna, _| action(wna).into()))
+    }
+
+    /// Like `show_notification`, but the action is passed the balloon's title, body and
+    /// `data`, so that it knows which of several queued balloons the user acted on.
+    #[cfg(feature = "balloons")]
+    pub fn show_notification_with_data<T, F, R>(&mut self, title: &str, body: &str, options: &NotificationOptions, data: T, action: F) -> Result<()>
+            where T: Send + Sync + 'static,
+                  F: Fn(&mut Wna, &BalloonContext) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.show_balloon_action(None, title, body, options, Some(Box::new(data)), Box::new(move |wna, context| action(wna, context).into()))
+    }
+
+    /// Sets the tooltip and shows a balloon in a single call to the shell, e.g. to show
+    /// a new state both ways without the icon flickering in between.
+    #[cfg(feature = "balloons")]
+    pub fn notify_and_set_tip<F, R>(&mut self, tip: &str, title: &str, body: &str, action: F) -> Result<()>
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.show_balloon_action(Some(tip), title, body, &NotificationOptions::default(), None, Box::new(move |wna, _| action(wna).into()))
+    }
+
+    #[cfg(feature = "balloons")]
+    fn show_balloon_action(&mut self, tip: Option<&str>, title: &str, body: &str, options: &NotificationOptions,
+                           data: Option<Box<dyn Any + Send + Sync>>, action: BalloonAction) -> Result<()> {
+        let mut lock = backend::lock(&self.repr);
+        lock.show_balloon_and_tip(tip, title, body, options, data, action)
+    }
+
+    /// Like `show_notification`, but asks the shell whether the user accepts notifications
+    /// both before and after showing the balloon. If not before, e.g. in presentation mode
+    /// or with a full-screen application running, the balloon is not shown, so that the
+    /// application may fall back to something else; a balloon that `show_notification`
+    /// passes to the shell at such a time is silently dropped or delayed. If not after,
+    /// the balloon was passed to the shell but most likely will not be seen.
+    ///
+    /// A balloon refused by the shell is reported as `BalloonOutcome::Failed`; other
+    /// errors, such as a closed tray, are still returned as errors.
+    #[cfg(feature = "balloons")]
+    pub fn show_balloon_checked<F, R>(&mut self, title: &str, body: &str, options: &NotificationOptions, action: F) -> Result<BalloonOutcome>
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        if let Some(reason) = self.notification_suppression()? {
+            return Ok(BalloonOutcome::Suppressed(reason));
+        }
+        match self.show_notification(title, body, options, action) {
+            Ok(()) => {}
+            Err(Error(ErrorKind::Win32(Op::ShowBalloon, _), _)) => return Ok(BalloonOutcome::Failed),
+            Err(e) => return Err(e),
+        }
+        match self.notification_suppression()? {
+            Some(reason) => Ok(BalloonOutcome::Suppressed(reason)),
+            None => Ok(BalloonOutcome::Shown),
+        }
+    }
+
+    #[cfg(feature = "balloons")]
+    fn notification_suppression(&self) -> Result<Option<SuppressionReason>> {
+        let lock = backend::lock(&self.repr);
+        if lock.notifications_muted {
+            Ok(Some(SuppressionReason::Muted))
+        } else {
+            lock.window.notification_suppression()
+        }
+    }
+
+    /// Drops the balloon set with `WnaBuilder::startup_balloon`, e.g. once startup
+    /// has turned out not to be worth a notification. Works until the balloon is shown,
+    /// including from the `on_ready` action; does nothing afterwards.
+    #[cfg(feature = "balloons")]
+    pub fn cancel_startup_balloon(&mut self) {
+        let mut lock = backend::lock(&self.repr);
+        lock.startup_balloon = None;
+    }
+
+    /// While muted, balloons are not shown and their actions are dropped, whatever
+    /// the system's own notification settings. Balloons already shown are not taken back.
+    ///
+    /// Only `show_balloon_checked` tells a muted balloon apart, by returning
+    /// `BalloonOutcome::Suppressed(SuppressionReason::Muted)`; `show_balloon` and the
+    /// other ways to show one return `Ok(())` as if it had been shown.
+    #[cfg(feature = "balloons")]
+    pub fn set_notifications_muted(&mut self, muted: bool) {
+        let mut lock = backend::lock(&self.repr);
+        lock.notifications_muted = muted;
+    }
+
+    /// Lets the persistent balloons already shown go once they time out,
+    /// see `NotificationOptions::persistent`.
+    #[cfg(feature = "balloons")]
+    pub fn stop_persistent_balloons(&mut self) {
+        let mut lock = backend::lock(&self.repr);
+        lock.stop_persistent_balloons()
+    }
+
+    /// Shows a balloon asking the user to pin the icon, labeled with `Strings::pin_prompt`,
+    /// if the icon is in the overflow area. There is no API to pin an icon, so this is
+    /// as far as an application can go. The balloon is shown at most once per run;
+    /// returns whether it was shown this time.
+    #[cfg(feature = "balloons")]
+    pub fn prompt_to_pin(&mut self) -> Result<bool> {
+        let mut lock = backend::lock(&self.repr);
+        lock.prompt_to_pin()
+    }
+
+    /// Closes the tray. All clones of a `Wna` share one tray, so closing any of them
+    /// closes it for all, and later calls on any clone do nothing. The event loop then
+    /// runs the quit action and stops, so `join_event_loop` on the `Wna` returned by
+    /// `WnaBuilder::build` returns.
+    pub fn close(&mut self) -> Result<()> {
+        // checked under the lock, as the flag is only set once `Repr::close` has succeeded
+        let mut lock = backend::lock(&self.repr);
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        lock.close()
+    }
+
+    /// Whether the tray has been closed, by this `Wna`, a clone of it or an action.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst) || backend::lock(&self.repr).window.is_closed()
+    }
+
+    /// Cycles the icon through `frames`, one every `interval`, e.g. for a "working"
+    /// spinner. The frames are loaded up front. A new animation replaces the running one.
+    pub fn animate_icon(&mut self, frames: Vec<Icon>, interval: Duration) -> Result<AnimationHandle> {
+        let lock = backend::lock(&self.repr);
+        let id = lock.window.start_animation(frames, interval)?;
+        Ok(AnimationHandle {
+            id,
+        })
+    }
+
+    /// Stops the animation and restores the static icon. Does nothing if
+    /// the animation was already replaced by another one.
+    pub fn stop_animation(&mut self, animation: AnimationHandle) -> Result<()> {
+        let lock = backend::lock(&self.repr);
+        lock.window.stop_animation(animation.id)
+    }
+
+    /// Runs `action` on the event loop thread every `interval` until the returned
+    /// handle is dropped. Use `IntervalHandle::detach` to keep the timer running.
+    pub fn set_interval<F, R>(&mut self, interval: Duration, action: F) -> Result<IntervalHandle>
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        let mut lock = backend::lock(&self.repr);
+        let id = lock.set_timer(interval, boxed_action(action))?;
+        Ok(IntervalHandle {
+            id,
+            repr: Some(Arc::downgrade(&self.repr)),
+        })
+    }
+
+    /// Runs `f` on the window thread, where Win32 calls on the hidden window
+    /// and its menu are safe to make. Returns without waiting for `f` to run,
+    /// unless called from the window thread itself, in which case `f` runs inline.
+    pub fn run_on_window_thread<F>(&self, f: F) -> Result<()>
+            where F: FnOnce() + Send + 'static {
+        let on_window_thread = {
+            let lock = backend::lock(&self.repr);
+            lock.window.is_window_thread()
+        };
+        if on_window_thread {
+            f();
+            return Ok(());
+        }
+        let lock = backend::lock(&self.repr);
+        lock.window.run_on_thread(Box::new(f))
+    }
+
+    /// Runs `f` on the window thread and waits for its result.
+    pub fn invoke<F, R>(&self, f: F) -> Result<R>
+            where F: FnOnce() -> R + Send + 'static, R: Send + 'static {
+        let (sender, receiver) = channel();
+        self.run_on_window_thread(move || {
+            let _ = sender.send(f());
+        })?;
+        receiver.recv().map_err(|_| "Window thread terminated before running the closure".into())
+    }
+
+    /// Tells whether the icon is shown on the taskbar rather than tucked away in
+    /// the overflow area, e.g. to suggest the user pins it.
+    ///
+    /// This is a heuristic: the icon counts as visible if its rect, as reported by
+    /// `Shell_NotifyIconGetRect`, lies on the primary taskbar. Icons on secondary
+    /// taskbars are reported as hidden, and while the overflow flyout is open
+    /// its icons may be reported as visible. Fails if the icon is not registered
+    /// with the shell, e.g. while hidden with `hide`.
+    pub fn is_icon_visible(&self) -> Result<bool> {
+        let lock = backend::lock(&self.repr);
+        lock.window.is_icon_visible()
+    }
+
+    /// Sets the window flashed by `request_attention`; `None` restores the default,
+    /// the hidden tray window.
+    pub fn set_attention_window(&mut self, hwnd: Option<HWND>) {
+        let mut lock = backend::lock(&self.repr);
+        lock.window.set_attention_window(hwnd);
+    }
+
+    /// Binds the main window of the application to the icon: a left click, or Enter
+    /// or Space on the focused icon, toggles it instead of showing the menu, which is
+    /// then only shown on a right click. A shown window is hidden; a hidden or minimized
+    /// one is restored and brought to the foreground. `None` unbinds it.
+    ///
+    /// With `hide_on_focus_loss`, the window is also hidden once another window comes
+    /// to the foreground, except for its own dialogs, the menu and the taskbar.
+    pub fn bind_main_window(&mut self, hwnd: Option<HWND>, hide_on_focus_loss: bool) -> Result<()> {
+        let lock = backend::lock(&self.repr);
+        lock.window.set_main_window(hwnd, hide_on_focus_loss)
+    }
+
+    /// Flashes the attention window in the taskbar until it comes to the foreground.
+    ///
+    /// The hidden tray window has no taskbar button, so flashing it has little visible
+    /// effect; this is meant for apps that also have a visible main window, set with
+    /// `set_attention_window`.
+    pub fn request_attention(&self) -> Result<()> {
+        let lock = backend::lock(&self.repr);
+        lock.window.request_attention()
+    }
+
+    /// Takes the receiving end of the channel on which errors from the background
+    /// threads are reported, e.g. a failure to reload the icon or to show the startup
+    /// balloon. Returns `None` if the receiver was already taken.
+    pub fn take_error_receiver(&mut self) -> Option<Receiver<Error>> {
+        let mut lock = backend::lock(&self.repr);
+        lock.error_receiver.take()
+    }
+
+    /// Reports `error` on the channel of `take_error_receiver`, along with the errors
+    /// of the background threads, e.g. from an action, which has no caller to return it to.
+    pub fn report_error(&self, error: Error) {
+        let lock = backend::lock(&self.repr);
+        lock.report_error(error);
+    }
+
+    /// Id of the thread that owns the hidden window and runs its message loop,
+    /// for APIs like `AttachThreadInput` or `PostThreadMessageW`.
+    /// Returns `None` once the window is closed.
+    pub fn window_thread_id(&self) -> Option<u32> {
+        let lock = backend::lock(&self.repr);
+        lock.window.thread_id()
+    }
+
+    /// Waits until the window thread has applied every update made so far, including
+    /// icon and tooltip changes held back by `WnaBuilder::update_debounce`, which are
+    /// applied right away. Useful before a `menu_snapshot` or a balloon that should
+    /// come after the latest changes.
+    pub fn flush(&self) -> Result<()> {
+        let lock = backend::lock(&self.repr);
+        lock.window.flush()
+    }
+
+    /// The DPI of the monitor the icon is shown on, for drawing icons at the size
+    /// the taskbar shows them: 16 pixels at 96 DPI, scaled in proportion.
+    ///
+    /// Unless the process is DPI aware (see `WnaBuilder::dpi_aware`), the system
+    /// scales for it and this is 96. Before Windows 8.1, which has no per-monitor DPI,
+    /// this is 96 as well.
+    pub fn icon_dpi(&self) -> Result<u32> {
+        let lock = backend::lock(&self.repr);
+        lock.window.icon_dpi()
+    }
+
+    /// Opens the menu at a point on the screen instead of at the cursor,
+    /// e.g. at a corner of the application's window.
+    ///
+    /// The coordinates are passed to `TrackPopupMenu` as they are: they are physical
+    /// pixels if the process is DPI aware (see `WnaBuilder::dpi_aware`), and scaled
+    /// logical pixels otherwise, the same as those `GetWindowRect` returns.
+    /// The system moves the menu if it would not fit on the screen.
+    pub fn open_menu_at(&mut self, x: i32, y: i32, align: MenuAlignment) -> Result<()> {
+        let lock = backend::lock(&self.repr);
+        lock.window.open_menu_at(x, y, align)
+    }
+
+    /// Dispatches at most one pending message of the calling thread, for hosts
+    /// that process tray messages from their own frame or idle cycle.
+    /// Returns `true` if the message was `WM_QUIT`, which is posted again so
+    /// that the host's loop sees it too.
+    ///
+    /// Only works with `with_existing_window`, on the thread that owns the window;
+    /// elsewhere it does nothing and returns `false`. Pumping the messages of any other
+    /// thread would steal them from its own loop, which is why this is checked.
+    pub fn pump_once(&self) -> bool {
+        {
+            // the lock must not be held while the message is dispatched
+            let lock = backend::lock(&self.repr);
+            if !lock.window.is_attached() || !lock.window.is_window_thread() {
+                return false;
+            }
+        }
+        backend::pump_once()
+    }
+
+    /// The popup menu, for menu APIs `wna` does not wrap, e.g. `SetMenuItemBitmaps`.
+    /// Returns `None` once the window is closed.
+    ///
+    /// Items must keep their command ids, or clicks on them will run the wrong actions
+    /// or none at all. The menu belongs to the window thread, so call menu APIs through
+    /// `run_on_window_thread`. `set_menu` replaces the menu, and with it the handle.
+    pub fn hmenu(&self) -> Option<HMENU> {
+        let lock = backend::lock(&self.repr);
+        lock.window.hmenu()
+    }
+
+    /// What the fake notification area was asked to show, for a tray built with
+    /// `WnaBuilder::build_mock`; `None` for a real one.
+    #[cfg(any(test, feature = "mock"))]
+    pub fn mock(&self) -> Option<Arc<mock::MockShared>> {
+        let lock = backend::lock(&self.repr);
+        lock.window.mock()
+    }
+
+    /// Waits for the next event of a tray built with `WnaBuilder::build_with_events`.
+    /// Returns `None` once the tray is closed, after running the quit action.
+    pub fn recv_event(&self) -> Option<Event> {
+        let closed = backend::lock(&self.repr).window.is_closed();
+        let event = match self.events {
+            Some(ref receiver) if closed => receiver.try_recv().ok(),
+            Some(ref receiver) => receiver.recv().ok(),
+            None => None,
+        };
+        self.filter_event(event)
+    }
+
+    /// Like `recv_event`, but returns `None` right away if no event is pending.
+    pub fn try_recv_event(&self) -> Option<Event> {
+        let event = match self.events {
+            Some(ref receiver) => receiver.try_recv().ok(),
+            None => None,
+        };
+        self.filter_event(event)
+    }
+
+    fn filter_event(&self, event: Option<Event>) -> Option<Event> {
+        match event {
+            Some(Event::Quit) => {
+                run_quit_action(&self.repr);
+                None
+            }
+            event => event,
+        }
+    }
+
+    /// Sends `event` to the event loop, or to `recv_event` for a tray built with
+    /// `WnaBuilder::build_with_events`, e.g. an `Event::Custom` to have the tray updated
+    /// from `WnaBuilder::on_custom` once a download finishes. `Event::Quit` closes the tray.
+    /// Other events are only sent by the window, so posting one fails.
+    pub fn post_event(&self, event: Event) -> Result<()> {
+        match event {
+            Event::Quit => return self.clone().close(),
+            Event::Custom(_) => { }
+            _ => bail!("Only Event::Custom and Event::Quit can be posted"),
+        }
+        let lock = backend::lock(&self.repr);
+        lock.event_sender.send(event).chain_err(|| "Event loop is gone")
+    }
+
+    /// The message of the last panic caught in a background thread: one raised by an
+    /// action, after which the event loop goes on with the next event, one caught in
+    /// the window procedure, or one that ended the window thread.
+    pub fn last_panic(&self) -> Option<String> {
+        let lock = backend::lock(&self.repr);
+        let last_panic = backend::lock(&lock.last_panic).clone();
+        last_panic
+    }
+
+    /// Runs until the tray is closed, e.g. by a `MenuItem::Quit`, like `join_event_loop`.
+    /// Fails with the first error reported by the background threads or `report_error`,
+    /// unless the error receiver was taken, or with the message of the last panic caught
+    /// in a background thread.
+    ///
+    /// Only the `Wna` returned by `WnaBuilder::build` runs the event loop; on a clone,
+    /// this returns at once. The tray is torn down when the last `Wna` is dropped, so
+    /// `main` should keep it until it calls `run`, rather than let it go out of scope.
+    pub fn run(mut self) -> Result<()> {
+        let errors = self.take_error_receiver();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Some(message) = self.last_panic() {
+            bail!("Background thread panicked: {}", message);
+        }
+        match errors.and_then(|errors| errors.try_recv().ok()) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub fn join_event_loop(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+}
+
+// The tray goes away with its last handle, see the crate docs, so that handle runs
+// the quit action, unless the event loop holds the tray at that moment and does.
+impl Drop for Wna {
+    fn drop(&mut self) {
+        run_quit_action_if_last(&self.repr);
+    }
+}
+
+impl Clone for Wna {
+
+    fn clone(&self) -> Self {
+        Wna {
+            repr: Arc::clone(&self.repr),
+            closed: Arc::clone(&self.closed),
+            thread: None,
+            events: None,
+        }
+    }
+
+}
+
+impl WnaBuilder {
+
+    pub fn window_class(&mut self, class: &'static str) -> &mut Self {
+        self.window_class = Some(class);
+        self
+    }
+
+    pub fn icon(&mut self, icon: Icon) -> &mut Self {
+        self.icon = Some(icon);
+        self
+    }
+
+    /// Icon of the hidden window's class, used by any window UI the system shows for it.
+    pub fn window_icon(&mut self, icon: Icon) -> &mut Self {
+        self.window_icon = Some(icon);
+        self
+    }
+
+    /// Attaches the icon to a window of the application instead of creating a hidden
+    /// window with its own thread, so that the application's message loop serves it.
+    /// The window procedure is subclassed until the tray is closed or the window
+    /// is destroyed. `build` must then be called on the thread that owns the window;
+    /// `window_class` and `window_icon` are ignored.
+    ///
+    /// A thread can serve only one attached tray, as the state of the subclassed window
+    /// is kept per thread; `build` fails for a second one until the first is closed.
+    /// Menu ids of an attached tray start at `0x8000`, so the window's own `WM_COMMAND`
+    /// ids must stay below that to be passed on to it.
+    pub fn with_existing_window(&mut self, hwnd: HWND) -> &mut Self {
+        self.existing_window = Some(hwnd);
+        self
+    }
+
+    pub fn tip(&mut self, tip: &str) -> &mut Self {
+        self.tip = Some(tip.to_string());
+        self
+    }
+
+    /// A concise name for screen readers, which announce the icon by its tooltip text.
+    ///
+    /// The shell has no separate accessible name for notification area icons, so this
+    /// is passed as the tooltip text whenever no tooltip is shown: if the standard
+    /// tooltip is disabled, or the tip is empty or cleared. Every icon should have
+    /// either a tip or an accessible name to pass accessibility reviews.
+    pub fn accessible_name(&mut self, name: &str) -> &mut Self {
+        self.accessible_name = Some(name.to_string());
+        self
+    }
+
+    /// Controls whether the shell shows the standard tooltip on hover.
+    ///
+    /// The icon is registered with `NOTIFYICON_VERSION_4`, under which the shell
+    /// suppresses the standard tooltip unless `NIF_SHOWTIP` is passed. Enabled by default,
+    /// unless `open_menu_on_hover` is set; disable it when the application draws its own hover UI.
+    pub fn show_standard_tooltip(&mut self, show: bool) -> &mut Self {
+        self.show_standard_tooltip = Some(show);
+        self
+    }
+
+    /// Opens the menu once the cursor has rested on the icon for `delay`,
+    /// as well as on a click. The shell only reports hovering when the standard
+    /// tooltip is not shown, so this turns it off unless `show_standard_tooltip` is set.
+    pub fn open_menu_on_hover(&mut self, delay: Duration) -> &mut Self {
+        self.menu_hover_delay = Some(delay);
+        self
+    }
+
+    /// Where `Wna::add_menu_item` puts new items; `MenuInsertMode::Append` by default.
+    /// The items given to the builder and to `Wna::set_menu` keep their order either way.
+    pub fn menu_insert_mode(&mut self, mode: MenuInsertMode) -> &mut Self {
+        self.menu_insert_mode = mode;
+        self
+    }
+
+    /// Keeps long menus usable: past `max` items, the rest go into a submenu
+    /// labeled with `Strings::more_items`, which in turn holds at most `max` items.
+    /// Items are still found by id wherever they end up. A `max` of 0 is taken as 1.
+    pub fn menu_max_items(&mut self, max: usize) -> &mut Self {
+        self.menu_max_items = Some(::std::cmp::max(max, 1));
+        self
+    }
+
+    /// When the menu opens with items past `menu_max_items`, also shows a balloon
+    /// saying how many there are, labeled with `Strings::more_items_balloon`,
+    /// so that they are not overlooked. It is shown again only once the count has
+    /// changed, and no more than once a minute. Off by default.
+    #[cfg(feature = "balloons")]
+    pub fn overflow_balloon(&mut self, show: bool) -> &mut Self {
+        self.overflow_balloon = show;
+        self
+    }
+
+    /// Extended window styles (`WS_EX_*`) of the hidden window, `WS_EX_TOOLWINDOW`
+    /// by default, which keeps it out of Alt+Tab and window lists. `right_to_left`
+    /// adds `WS_EX_LAYOUTRTL` to them. Not used with `with_existing_window`.
+    pub fn window_ex_style(&mut self, ex_style: u32) -> &mut Self {
+        self.window_ex_style = Some(ex_style);
+        self
+    }
+
+    /// Creates the hidden window as a message-only window (`HWND_MESSAGE` as its parent),
+    /// which cannot be enumerated, painted or activated. Off by default.
+    ///
+    /// Message-only windows get no broadcasts, such as `WM_DISPLAYCHANGE`, so with it
+    /// `on_display_changed` is never called and the icon is not reloaded when the DPI
+    /// changes. Nor can they take the foreground, which the menu needs to close on a
+    /// click outside of it. Not used with `with_existing_window`.
+    pub fn message_only(&mut self, message_only: bool) -> &mut Self {
+        self.message_only = Some(message_only);
+        self
+    }
+
+    /// Lays the menu out right-to-left, for Arabic and Hebrew locales.
+    pub fn right_to_left(&mut self, rtl: bool) -> &mut Self {
+        self.right_to_left = rtl;
+        self
+    }
+
+    /// Makes the process per-monitor DPI aware (falling back to system DPI awareness
+    /// on systems older than Windows 10 1703) when `build` is called.
+    ///
+    /// DPI awareness must be set before any window is created, so prefer setting it
+    /// in the application manifest if the process creates windows before `build`.
+    /// Awareness already set by the manifest or the host process is left as is.
+    pub fn dpi_aware(&mut self, dpi_aware: bool) -> &mut Self {
+        self.dpi_aware = dpi_aware;
+        self
+    }
+
+    /// By default the window that was in the foreground before the menu was opened
+    /// gets the focus back when the menu closes. Pass `true` to keep the focus
+    /// on the hidden window instead.
+    ///
+    /// `SetForegroundWindow` is subject to the system's foreground lock rules,
+    /// so restoring the focus is best effort.
+    pub fn keep_focus_after_menu(&mut self, keep_focus: bool) -> &mut Self {
+        self.keep_focus = keep_focus;
+        self
+    }
+
+    /// Coalesces `set_tip` and `set_icon` calls that come more often than `interval`:
+    /// the latest value is applied once the interval has passed. A zero interval
+    /// disables debouncing, which is the default.
+    pub fn update_debounce(&mut self, interval: Duration) -> &mut Self {
+        self.update_debounce = Some(interval);
+        self
+    }
+
+    /// How long `build` waits for the window thread to create the window and add the icon
+    /// before failing with `ErrorKind::WindowCreationTimeout`. 10 seconds by default.
+    pub fn creation_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.creation_timeout = Some(timeout);
+        self
+    }
+
+    /// Prefix of the names of the window and event loop threads,
+    /// which are `wna-window-loop` and `wna-event-loop` by default.
+    pub fn thread_name_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.thread_name_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Priority of the window and event loop threads. They are left at
+    /// the normal priority unless this is set.
+    pub fn thread_priority(&mut self, priority: ThreadPriority) -> &mut Self {
+        self.thread_priority = Some(priority);
+        self
+    }
+
+    /// Registers the icon and tooltip to show for each status passed to `Wna::set_status`.
+    pub fn status_icons<S>(&mut self, icons: HashMap<S, (Icon, String)>) -> &mut Self
+            where S: Hash + Eq + Send + 'static {
+        self.status_icons = Some(Box::new(icons));
+        self
+    }
+
+    pub fn strings(&mut self, strings: Strings) -> &mut Self {
+        self.strings = strings;
+        self
+    }
+
+    pub fn menu_item(&mut self, item: MenuItem) -> &mut Self {
+        self.menu_items.push(item);
+        self
+    }
+
+    /// Adds an item for each of `items`, labeled by `label`. They share `action`,
+    /// which is passed the index of the item chosen, e.g. for a list of devices.
+    pub fn menu_from_slice<T, L, F, R>(&mut self, items: &[T], label: L, action: F) -> &mut Self
+            where L: Fn(&T) -> String,
+                  F: Fn(&mut Wna, usize) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        let action = Arc::new(action);
+        for (index, item) in items.iter().enumerate() {
+            let action = Arc::clone(&action);
+            self.menu_items.push(MenuItem::action(label(item), move |wna| action(wna, index)));
+        }
+        self
+    }
+
+    /// Called after the display configuration changes, e.g. to lay out
+    /// the application's own windows anew. Before that, the icon is checked
+    /// and re-added if the shell has dropped it, which some Windows builds do
+    /// when monitors are added or rearranged.
+    pub fn on_display_changed<F, R>(&mut self, action: F) -> &mut Self
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.display_changed_action = Some(boxed_action(action));
+        self
+    }
+
+    /// Called when the window thread runs out of messages to handle, for light
+    /// work that should not compete with the tray, unlike `Wna::set_interval`.
+    ///
+    /// The action is called at most once per `throttle`, which is raised to 100 ms
+    /// if shorter so that an idle tray does not keep a core busy, and only when the queue
+    /// empties after handling a message: a tray nobody touches sends no messages,
+    /// so the action is not called either. With `with_existing_window` it is only
+    /// called from `Wna::pump_once`, as the application runs the message loop.
+    pub fn on_idle<F, R>(&mut self, throttle: Duration, action: F) -> &mut Self
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.idle_action = Some((throttle, boxed_action(action)));
+        self
+    }
+
+    /// Called on a left click on the icon, or Enter or Space on the focused icon,
+    /// in place of what the click does by default.
+    ///
+    /// A handler set for a button always wins; without one, the button falls back
+    /// to its default:
+    ///
+    /// | Button | With a handler | Without one                                                        |
+    /// |--------|----------------|--------------------------------------------------------------------|
+    /// | Left   | the handler    | the window bound with `Wna::bind_main_window`, otherwise the menu  |
+    /// | Right  | the handler    | the menu                                                           |
+    ///
+    /// With handlers for both buttons, the menu only opens with `Wna::open_menu_at`
+    /// or on hover, see `open_menu_on_hover`.
+    pub fn on_left_click<F, R>(&mut self, action: F) -> &mut Self
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.left_click_action = Some(boxed_action(action));
+        self
+    }
+
+    /// Called on a right click on the icon, or Shift+F10 or the Apps key on the focused
+    /// icon, in place of the menu; see `on_left_click`.
+    pub fn on_right_click<F, R>(&mut self, action: F) -> &mut Self
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.right_click_action = Some(boxed_action(action));
+        self
+    }
+
+    /// Shown in place of the icon while a menu action runs, so that a slow action
+    /// does not leave the app looking frozen. Once the action returns, or panics,
+    /// the icon is shown again, including any `set_icon` or `animate_icon` made
+    /// meanwhile; a running animation carries on underneath the busy icon.
+    pub fn busy_icon(&mut self, icon: Icon) -> &mut Self {
+        self.busy_icon = Some(icon);
+        self
+    }
+
+    /// Called once the icon is live, e.g. to show a first balloon
+    /// without waiting an arbitrary time after `build`.
+    pub fn on_ready<F, R>(&mut self, action: F) -> &mut Self
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.ready_action = Some(boxed_action(action));
+        self
+    }
+
+    /// Called once when the tray closes, whether by `Wna::close`, an action returning
+    /// `ActionResult::Quit`, or the last `Wna` being dropped.
+    pub fn on_quit<F, R>(&mut self, action: F) -> &mut Self
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.quit_action = Some(boxed_action(action));
+        self
+    }
+
+    /// Called with the id of a menu command that has no action, e.g. one that
+    /// was removed while the command was on its way. Such commands are dropped otherwise.
+    pub fn on_unhandled_menu<F>(&mut self, action: F) -> &mut Self
+            where F: FnMut(MenuItemId) + Send + 'static {
+        self.unhandled_menu_action = Some(Box::new(action));
+        self
+    }
+
+    /// Called on the event loop thread with the value of every `Event::Custom`
+    /// posted with `Wna::post_event`.
+    pub fn on_custom<F>(&mut self, action: F) -> &mut Self
+            where F: FnMut(&mut Wna, u32) + Send + 'static {
+        self.custom_event_action = Some(Box::new(action));
+        self
+    }
+
+    /// How many balloons may wait for a click at once; the shell queues balloons
+    /// and reports each one as clicked or dismissed in turn. Past the limit, the action
+    /// of the oldest balloon is dropped and `on_balloon_dropped` is called. 16 by default.
+    /// Balloons the tray shows by itself, for `overflow_balloon` and `Wna::prompt_to_pin`, do not count
+    /// and are never dropped.
+    #[cfg(feature = "balloons")]
+    pub fn max_pending_balloons(&mut self, max: usize) -> &mut Self {
+        self.max_pending_balloons = Some(max);
+        self
+    }
+
+    /// A balloon shown once the icon is live, right after the `on_ready` action;
+    /// see `Wna::cancel_startup_balloon`.
+    #[cfg(feature = "balloons")]
+    pub fn startup_balloon(&mut self, title: &str, body: &str) -> &mut Self {
+        self.startup_balloon = Some((title.to_string(), body.to_string()));
+        self
+    }
+
+    /// The title of balloons shown with an empty one, e.g. by `Wna::show_balloon_body_only`.
+    /// The tooltip is set separately, with `tip`.
+    #[cfg(feature = "balloons")]
+    pub fn app_name(&mut self, name: &str) -> &mut Self {
+        self.app_name = Some(name.to_string());
+        self
+    }
+
+    /// Called on the event loop thread whenever the action of the oldest pending balloon
+    /// is dropped, as `max_pending_balloons` balloons were already waiting for a click.
+    #[cfg(feature = "balloons")]
+    pub fn on_balloon_dropped<F, R>(&mut self, action: F) -> &mut Self
+            where F: Fn(&mut Wna) -> R + Send + Sync + 'static,
+                  R: Into<ActionResult> {
+        self.balloon_dropped_action = Some(boxed_action(action));
+        self
+    }
+
+    /// Called right before the menu shows, so that item states can be brought up to date
+    /// just in time instead of on every change. It runs on the window thread while the
+    /// menu waits for it, and again for every submenu that opens.
+    pub fn on_menu_opening<F>(&mut self, action: F) -> &mut Self
+            where F: Fn(&mut Wna) + Send + Sync + 'static {
+        self.menu_opening_action = Some(Box::new(action));
+        self
+    }
+
+    fn thread_name(&self, role: &str) -> String {
+        format!("{}-{}", self.thread_name_prefix.as_deref().unwrap_or("wna"), role)
+    }
+
+    /// Fails with `ErrorKind::NoInteractiveSession` when run as a service,
+    /// as there is no notification area outside an interactive session.
+    pub fn build(self) -> Result<Wna> {
+        self.build_event_loop(WnaBuilder::create_window)
+    }
+
+    /// Builds the tray against `mock::MockWindow`, an in-memory fake of the notification area,
+    /// instead of the real one: nothing is shown, and what the tray was asked to show can be
+    /// read back with `Wna::mock`. Works on any platform; otherwise the same as `build`.
+    #[cfg(any(test, feature = "mock"))]
+    pub fn build_mock(self) -> Result<Wna> {
+        self.build_event_loop(WnaBuilder::create_mock_window)
+    }
+
+    fn build_event_loop<F>(mut self, create_window: F) -> Result<Wna>
+            where F: FnOnce(&WnaBuilder, &str, bool, &PanicSlot, Sender<Event>, Sender<Error>) -> Result<Box<dyn TrayBackend>> {
+        let unhandled_menu_action = self.unhandled_menu_action.take();
+        let custom_event_action = self.custom_event_action.take();
+        let thread_name = self.thread_name("event-loop");
+        #[cfg(windows)]
+        let thread_priority = self.thread_priority;
+        let (repr, receiver) = self.build_repr(create_window)?;
+        let last_panic = Arc::clone(&backend::lock(&repr).last_panic);
+        let thread = start_event_loop(receiver, Arc::downgrade(&repr), unhandled_menu_action, custom_event_action, thread_name, last_panic)?;
+        #[cfg(windows)]
+        if let Some(priority) = thread_priority {
+            window::set_thread_priority(&thread, priority)?;
+        }
+        Ok(Wna::with_repr(repr, Some(thread), None))
+    }
+
+    /// Builds a tray without the event loop thread: events are left for
+    /// `Wna::recv_event` and `Wna::try_recv_event` instead, and actions attached
+    /// to menu items and balloons are not run.
+    pub fn build_with_events(self) -> Result<Wna> {
+        let (repr, receiver) = self.build_repr(WnaBuilder::create_window)?;
+        Ok(Wna::with_repr(repr, None, Some(receiver)))
+    }
+
+    /// `build_with_events` against the in-memory fake of `build_mock`.
+    #[cfg(any(test, feature = "mock"))]
+    pub fn build_mock_with_events(self) -> Result<Wna> {
+        let (repr, receiver) = self.build_repr(WnaBuilder::create_mock_window)?;
+        Ok(Wna::with_repr(repr, None, Some(receiver)))
+    }
+
+    // `create_window` makes the backend that shows the icon, given the channels it reports on.
+    fn build_repr<F>(self, create_window: F) -> Result<(Arc<Mutex<Repr>>, Receiver<Event>)>
+            where F: FnOnce(&WnaBuilder, &str, bool, &PanicSlot, Sender<Event>, Sender<Error>) -> Result<Box<dyn TrayBackend>> {
+        let (sender, reciever) = channel();
+        let window_class = self.window_class.unwrap_or("wna_window_class");
+        if window_class.is_empty() || window_class.encode_utf16().count() > MAX_WINDOW_CLASS_LEN {
+            bail!(ErrorKind::InvalidWindowClass(window_class.to_string()));
+        }
+        let show_tip = self.show_standard_tooltip.unwrap_or(self.menu_hover_delay.is_none());
+        let last_panic = Arc::new(Mutex::new(None));
+        let (error_sender, error_receiver) = channel();
+        let window = create_window(&self, window_class, show_tip, &last_panic, sender.clone(), error_sender.clone())?;
+        let mut repr = Repr {
+            window,
+            tip: None,
+            show_tip,
+            accessible_name: self.accessible_name,
+            strings: self.strings,
+            status_icons: self.status_icons,
+            last_menu_id: if self.existing_window.is_some() { ATTACHED_FIRST_MENU_ID } else { 0 },
+            timers: HashMap::new(),
+            toggles: HashMap::new(),
+            item_data: HashMap::new(),
+            quit_items: HashSet::new(),
+            actions: HashMap::new(),
+            menu_enabled: true,
+            menu_insert_mode: self.menu_insert_mode,
+            disabled_items: HashSet::new(),
+            #[cfg(feature = "balloons")]
+            balloon_actions: VecDeque::new(),
+            #[cfg(feature = "balloons")]
+            max_pending_balloons: self.max_pending_balloons.unwrap_or(16),
+            #[cfg(feature = "balloons")]
+            balloon_dropped_action: self.balloon_dropped_action.map(Arc::new),
+            #[cfg(feature = "balloons")]
+            pin_prompted: false,
+            #[cfg(feature = "balloons")]
+            overflow_announced: None,
+            #[cfg(feature = "balloons")]
+            app_name: self.app_name,
+            #[cfg(feature = "balloons")]
+            notifications_muted: false,
+            #[cfg(feature = "balloons")]
+            startup_balloon: self.startup_balloon,
+            display_changed_action: self.display_changed_action.map(Arc::new),
+            idle_action: self.idle_action.map(|(_, action)| Arc::new(action)),
+            left_click_action: self.left_click_action.map(Arc::new),
+            right_click_action: self.right_click_action.map(Arc::new),
+            ready_action: self.ready_action,
+            busy_icon: self.busy_icon,
+            quit_action: self.quit_action,
+            event_sender: sender,
+            error_sender,
+            error_receiver: Some(error_receiver),
+            closed: Arc::new(AtomicBool::new(false)),
+            last_panic,
+        };
+        if let Some(ref icon) = self.icon {
+            repr.set_icon(icon)?;
+        }
+        match self.tip {
+            Some(ref tip) => repr.set_tip(tip)?,
+            None => repr.apply_tip()?,
+        }
+        for item in self.menu_items {
+            repr.add_menu_item(item, MenuInsertMode::Append)?;
+        }
+        // the message loop has started by the time this is run
+        let ready_sender = repr.event_sender.clone();
+        repr.window.run_on_thread(Box::new(move || {
+            let _ = ready_sender.send(Event::Ready);
+        }))?;
+        let menu_opening_action = self.menu_opening_action;
+        let repr = Arc::new(Mutex::new(repr));
+        if let Some(action) = menu_opening_action {
+            let weak = Arc::downgrade(&repr);
+            backend::lock(&repr).window.set_menu_opening_callback(Some(Box::new(move || {
+                if let Some(repr) = weak.upgrade() {
+                    let mut wna = Wna::with_repr(repr, None, None);
+                    action(&mut wna);
+                }
+            })));
+        }
+        Ok((repr, reciever))
+    }
+
+    #[cfg(windows)]
+    fn create_window(&self, window_class: &str, show_tip: bool, last_panic: &PanicSlot,
+                     sender: Sender<Event>, error_sender: Sender<Error>) -> Result<Box<dyn TrayBackend>> {
+        if self.dpi_aware {
+            window::set_process_dpi_aware()?;
+        }
+        let config = window::WindowConfig {
+            show_tip,
+            right_to_left: self.right_to_left,
+            ex_style: self.window_ex_style.unwrap_or(WS_EX_TOOLWINDOW),
+            message_only: self.message_only.unwrap_or(false),
+            class_icon: self.window_icon.clone(),
+            restore_focus: !self.keep_focus,
+            update_debounce: self.update_debounce,
+            creation_timeout: self.creation_timeout.unwrap_or(Duration::from_secs(10)),
+            thread_name: self.thread_name("window-loop"),
+            thread_priority: self.thread_priority,
+            menu_hover_delay: self.menu_hover_delay,
+            last_panic: Arc::clone(last_panic),
+            menu_max_items: self.menu_max_items,
+            more_items_label: self.strings.more_items.clone(),
+            left_click_handled: self.left_click_action.is_some(),
+            right_click_handled: self.right_click_action.is_some(),
+            #[cfg(feature = "balloons")]
+            overflow_balloon: self.overflow_balloon,
+            idle_throttle: self.idle_action.as_ref().map(|&(throttle, _)| throttle),
+        };
+        Ok(match self.existing_window {
+            Some(hwnd) => Box::new(window::Window::attach(hwnd, config, sender, error_sender)?),
+            None => Box::new(window::Window::create(window_class, config, sender, error_sender)?),
+        })
+    }
+
+    #[cfg(not(windows))]
+    fn create_window(&self, _window_class: &str, _show_tip: bool, _last_panic: &PanicSlot,
+                     _sender: Sender<Event>, _error_sender: Sender<Error>) -> Result<Box<dyn TrayBackend>> {
+        bail!("The notification area is only available on Windows")
+    }
+
+    #[cfg(any(test, feature = "mock"))]
+    fn create_mock_window(&self, _window_class: &str, _show_tip: bool, _last_panic: &PanicSlot,
+                          _sender: Sender<Event>, _error_sender: Sender<Error>) -> Result<Box<dyn TrayBackend>> {
+        Ok(Box::new(mock::MockWindow::new()))
+    }
+
+}
+
+// what `Repr` keeps about a menu item besides its action
+enum MenuItemExtra {
+    Quit,
+    Toggle(bool),
+    Data(Arc<dyn Any + Send + Sync>),
+    Help(String),
+}
+
+// a balloon waiting in the shell's queue for a click
+#[cfg(feature = "balloons")]
+struct PendingBalloon {
+    context: BalloonContext,
+    action: BalloonAction,
+    options: NotificationOptions,
+    // times left to show a persistent balloon again
+    reshows_left: u32,
+    // shown by the tray itself: not counted toward `max_pending_balloons`, and never dropped
+    internal: bool,
+}
+
+struct Repr {
+    window: Box<dyn TrayBackend>,
+    tip: Option<String>,
+    show_tip: bool,
+    accessible_name: Option<String>,
+    strings: Strings,
+    status_icons: Option<Box<dyn Any + Send>>,
+    last_menu_id: MenuItemId,
+    timers: HashMap<u32, Arc<Action>>,
+    toggles: HashMap<MenuItemId, bool>,
+    item_data: HashMap<MenuItemId, Arc<dyn Any + Send + Sync>>,
+    quit_items: HashSet<MenuItemId>,
+    actions: HashMap<MenuItemId, Arc<Action>>,
+    menu_enabled: bool,
+    menu_insert_mode: MenuInsertMode,
+    disabled_items: HashSet<MenuItemId>,
+    // in the order the shell shows the balloons
+    #[cfg(feature = "balloons")]
+    // the shell reports clicks on the oldest balloon still pending
+    balloon_actions: VecDeque<PendingBalloon>,
+    #[cfg(feature = "balloons")]
+    max_pending_balloons: usize,
+    #[cfg(feature = "balloons")]
+    balloon_dropped_action: Option<Arc<Action>>,
+    #[cfg(feature = "balloons")]
+    app_name: Option<String>,
+    #[cfg(feature = "balloons")]
+    notifications_muted: bool,
+    // shown once the tray is ready, unless cancelled before
+    #[cfg(feature = "balloons")]
+    startup_balloon: Option<(String, String)>,
+    #[cfg(feature = "balloons")]
+    pin_prompted: bool,
+    // the count last announced by the overflow balloon, and when
+    #[cfg(feature = "balloons")]
+    overflow_announced: Option<(usize, Instant)>,
+    display_changed_action: Option<Arc<Action>>,
+    idle_action: Option<Arc<Action>>,
+    left_click_action: Option<Arc<Action>>,
+    right_click_action: Option<Arc<Action>>,
+    ready_action: Option<Action>,
+    busy_icon: Option<Icon>,
+    quit_action: Option<Action>,
+    event_sender: Sender<Event>,
+    // shared with the window thread, for the errors of the event loop and the actions,
+    // which have nobody to return them to
+    error_sender: Sender<Error>,
+    error_receiver: Option<Receiver<Error>>,
+    closed: Arc<AtomicBool>,
+    last_panic: PanicSlot,
+}
+
+impl Repr {
+
+    fn next_menu_id(&mut self) -> MenuItemId {
+        let id = self.last_menu_id;
+        self.last_menu_id += 1;
+        id
+    }
+
+    pub fn set_icon(&mut self, icon: &Icon) -> Result<()> {
+        self.window.set_icon(icon)
+    }
+
+    // Returns whether the busy icon is shown, to be cleared once the action is done.
+    fn show_busy_icon(&self) -> Result<bool> {
+        match self.busy_icon {
+            Some(ref icon) => self.window.set_busy_icon(Some(icon)).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    fn set_timer(&mut self, interval: Duration, action: Action) -> Result<u32> {
+        let id = self.window.set_timer(interval)?;
+        self.timers.insert(id, Arc::new(action));
+        Ok(id)
+    }
+
+    fn kill_timer(&mut self, id: u32) -> Result<()> {
+        self.timers.remove(&id);
+        self.window.kill_timer(id)
+    }
+
+    pub fn set_tip(&mut self, tip: &str) -> Result<()> {
+        self.tip = Some(tip.to_string());
+        self.apply_tip()
+    }
+
+    pub fn set_accessible_name(&mut self, name: &str) -> Result<()> {
+        self.accessible_name = Some(name.to_string());
+        self.apply_tip()
+    }
+
+    // screen readers announce the tooltip text, so the accessible name
+    // takes its place whenever no tooltip is shown
+    fn apply_tip(&mut self) -> Result<()> {
+        match self.tip_text() {
+            Some(text) => self.window.set_tip(&text),
+            None => Ok(()),
+        }
+    }
+
+    fn tip_text(&self) -> Option<String> {
+        match (self.tip.as_ref(), self.accessible_name.as_ref()) {
+            (Some(tip), _) if self.show_tip && !tip.is_empty() => Some(tip.clone()),
+            (_, Some(name)) => Some(name.clone()),
+            (Some(tip), None) => Some(tip.clone()),
+            (None, None) => None,
+        }
+    }
+
+    pub fn set_status<S>(&mut self, status: S) -> Result<()>
+            where S: Hash + Eq + Send + 'static {
+        let (icon, tip) = match self.status_icons.as_ref().and_then(|icons| icons.downcast_ref::<HashMap<S, (Icon, String)>>()) {
+            Some(icons) => match icons.get(&status) {
+                Some((icon, tip)) => (icon.clone(), tip.clone()),
+                None => bail!(ErrorKind::UnknownStatus),
+            },
+            None => bail!(ErrorKind::UnknownStatus),
+        };
+        self.set_icon(&icon)?;
+        self.set_tip(&tip)
+    }
+
+    pub fn set_visible(&mut self, visible: bool) -> Result<()> {
+        if self.window.set_visible(visible)? {
+            self.apply_tip()?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_tip(&mut self) -> Result<()> {
+        self.tip = None;
+        match self.accessible_name {
+            Some(_) => self.apply_tip(),
+            None => self.window.set_tip(""),
+        }
+    }
+
+    // The item's extra is to be recorded with `record_menu_item` once the item is in the menu.
+    fn make_menu_entry(&mut self, item: MenuItem) -> (MenuItemId, MenuEntry, Option<Action>, Option<MenuItemExtra>) {
+        let id = self.next_menu_id();
+        match item {
+            MenuItem::Action(title, action) => (id, MenuEntry::Item(id, title), Some(action), None),
+            MenuItem::Quit => {
+                let action: Action = Box::new(|_| ActionResult::Quit);
+                (id, MenuEntry::Item(id, self.strings.quit.clone()), Some(action), Some(MenuItemExtra::Quit))
+            },
+            MenuItem::TriState(title, state, action) => (id, MenuEntry::TriState(id, title, state), Some(action), None),
+            MenuItem::Toggle(title, checked, action) => {
+                let state = if checked { MenuItemState::Checked } else { MenuItemState::Unchecked };
+                let action: Action = Box::new(move |wna| {
+                    let checked = {
+                        let mut lock = backend::lock(&wna.repr);
+                        match lock.flip_toggle(id) {
+                            Ok(checked) => checked,
+                            Err(e) => {
+                                lock.report_error(e);
+                                return ActionResult::Continue;
+                            }
+                        }
+                    };
+                    action(wna, checked)
+                });
+                (id, MenuEntry::TriState(id, title, state), Some(action), Some(MenuItemExtra::Toggle(checked)))
+            },
+            MenuItem::ActionWithData(title, data, action) => {
+                let action: Action = Box::new(move |wna| {
+                    let data = backend::lock(&wna.repr).item_data.get(&id).map(Arc::clone);
+                    match data {
+                        Some(data) => action(wna, &*data),
+                        None => ActionResult::Continue,
+                    }
+                });
+                (id, MenuEntry::Item(id, title), Some(action), Some(MenuItemExtra::Data(Arc::from(data))))
+            },
+            MenuItem::ActionWithHelp(title, help, action) => {
+                (id, MenuEntry::Item(id, title), Some(action), Some(MenuItemExtra::Help(help)))
+            },
+            MenuItem::OwnerDrawn { data, measure, draw, action } => {
+                (id, MenuEntry::OwnerDrawn(id, data, measure, draw), Some(action), None)
+            },
+            MenuItem::Separator => (id, MenuEntry::Separator(id), None, None),
+        }
+    }
+
+    fn record_menu_item(&mut self, id: MenuItemId, extra: MenuItemExtra) {
+        match extra {
+            MenuItemExtra::Quit => {
+                self.quit_items.insert(id);
+            }
+            MenuItemExtra::Toggle(checked) => {
+                self.toggles.insert(id, checked);
+            }
+            MenuItemExtra::Data(data) => {
+                self.item_data.insert(id, data);
+            }
+            MenuItemExtra::Help(help) => self.window.set_menu_item_help(id, Some(help)),
+        }
+    }
+
+    pub fn add_menu_item(&mut self, item: MenuItem, mode: MenuInsertMode) -> Result<()> {
+        let (id, entry, action, extra) = self.make_menu_entry(item);
+        self.window.add_menu_entry(entry, mode)?;
+        if let Some(extra) = extra {
+            self.record_menu_item(id, extra);
+        }
+        if let Some(action) = action {
+            self.actions.insert(id, Arc::new(action));
+        }
+        if !self.menu_enabled {
+            self.window.set_menu_item_enabled(id, false)?;
+        }
+        Ok(())
+    }
+
+    pub fn insert_separator_after(&mut self, after: MenuItemId) -> Result<MenuItemId> {
+        let id = self.next_menu_id();
+        self.window.insert_separator_after(after, id)?;
+        Ok(id)
+    }
+
+    // The old menu is kept as it was, bookkeeping and all, if the new one cannot be set.
+    pub fn set_menu(&mut self, items: Vec<MenuItem>) -> Result<()> {
+        let mut entries = Vec::with_capacity(items.len());
+        let mut actions = HashMap::new();
+        let mut extras = Vec::new();
+        for item in items {
+            let (id, entry, action, extra) = self.make_menu_entry(item);
+            entries.push(entry);
+            if let Some(action) = action {
+                actions.insert(id, Arc::new(action));
+            }
+            if let Some(extra) = extra {
+                extras.push((id, extra));
+            }
+        }
+        self.window.set_menu(entries)?;
+        self.toggles.clear();
+        self.item_data.clear();
+        self.quit_items.clear();
+        for (id, extra) in extras {
+            self.record_menu_item(id, extra);
+        }
+        self.actions = actions;
+        self.disabled_items.clear();
+        if !self.menu_enabled {
+            self.set_menu_enabled(false)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_action(&mut self, id: MenuItemId, action: Action) -> Result<()> {
+        match self.actions.get_mut(&id) {
+            Some(current) => {
+                *current = Arc::new(action);
+                Ok(())
+            }
+            None => bail!(ErrorKind::MenuItemNotFound(id)),
+        }
+    }
+
+    pub fn replace_actions(&mut self, actions: HashMap<MenuItemId, Action>) -> Result<()> {
+        let ids: HashSet<MenuItemId> = self.window.menu_item_ids()?.into_iter().collect();
+        let mut missing: Vec<MenuItemId> = actions.keys().cloned().filter(|id| !ids.contains(id)).collect();
+        if !missing.is_empty() {
+            missing.sort();
+            bail!(ErrorKind::MenuItemsNotFound(missing));
+        }
+        let mut wrapped: Vec<MenuItemId> = actions.keys().cloned().filter(|id| self.has_wrapped_action(*id)).collect();
+        if !wrapped.is_empty() {
+            wrapped.sort();
+            bail!(ErrorKind::ActionsNotReplaceable(wrapped));
+        }
+        let kept: Vec<MenuItemId> = self.actions.keys().cloned().filter(|id| self.has_wrapped_action(*id)).collect();
+        let mut replaced: HashMap<MenuItemId, Arc<Action>> = kept.into_iter()
+            .filter_map(|id| self.actions.remove(&id).map(|action| (id, action)))
+            .collect();
+        replaced.extend(actions.into_iter().map(|(id, action)| (id, Arc::new(action))));
+        self.actions = replaced;
+        Ok(())
+    }
+
+    // the actions of these items wrap the application's, which take other arguments
+    fn has_wrapped_action(&self, id: MenuItemId) -> bool {
+        self.toggles.contains_key(&id) || self.item_data.contains_key(&id) || self.quit_items.contains(&id)
+    }
+
+    pub fn set_menu_item_enabled(&mut self, id: MenuItemId, enabled: bool) -> Result<()> {
+        if self.menu_enabled {
+            self.window.set_menu_item_enabled(id, enabled)?;
+        }
+        if enabled {
+            self.disabled_items.remove(&id);
+        } else {
+            self.disabled_items.insert(id);
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_enabled(&mut self, enabled: bool) -> Result<()> {
+        for id in self.window.menu_item_ids()? {
+            let item_enabled = enabled && !self.disabled_items.contains(&id);
+            self.window.set_menu_item_enabled(id, item_enabled)?;
+        }
+        self.menu_enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_menu_item_state(&mut self, id: MenuItemId, state: MenuItemState) -> Result<()> {
+        self.window.set_menu_item_state(id, state)?;
+        if let Some(checked) = self.toggles.get_mut(&id) {
+            *checked = state == MenuItemState::Checked;
+        }
+        Ok(())
+    }
+
+    fn flip_toggle(&mut self, id: MenuItemId) -> Result<bool> {
+        let checked = match self.toggles.get(&id) {
+            Some(checked) => !checked,
+            None => bail!(ErrorKind::MenuItemNotFound(id)),
+        };
+        self.set_menu_item_state(id, if checked { MenuItemState::Checked } else { MenuItemState::Unchecked })?;
+        Ok(checked)
+    }
+
+    pub fn remove_menu_item(&mut self, id: MenuItemId) -> Result<()> {
+        self.window.remove_menu_item(id)?;
+        self.window.set_menu_item_help(id, None);
+        self.actions.remove(&id);
+        self.toggles.remove(&id);
+        self.item_data.remove(&id);
+        self.quit_items.remove(&id);
+        self.disabled_items.remove(&id);
+        Ok(())
+    }
+
+    // With a `tip`, the tooltip is set by the same call to the shell. Sends
+    // `Event::BalloonDropped` if the action of the oldest pending balloon had to be dropped.
+    #[cfg(feature = "balloons")]
+    pub fn show_balloon_and_tip(&mut self, tip: Option<&str>, title: &str, body: &str, options: &NotificationOptions,
+                                data: Option<Box<dyn Any + Send + Sync>>, action: BalloonAction) -> Result<()> {
+        if let Some(tip) = tip {
+            self.tip = Some(tip.to_string());
+        }
+        if self.notifications_muted {
+            if tip.is_some() {
+                self.apply_tip()?;
+            }
+            return Ok(());
+        }
+        let title = if options.no_title {
+            ""
+        } else if options.tip_as_title {
+            self.tip.as_deref().unwrap_or("")
+        } else if title.is_empty() {
+            self.app_name.as_deref().unwrap_or("")
+        } else {
+            title
+        };
+        match tip.and_then(|_| self.tip_text()) {
+            Some(text) => self.window.show_balloon_with_tip(&text, title, body, options)?,
+            None => self.window.show_balloon(title, body, options)?,
+        }
+        let context = BalloonContext {
+            title: title.to_string(),
+            body: body.to_string(),
+            data,
+        };
+        self.balloon_actions.push_back(PendingBalloon {
+            context,
+            action,
+            options: options.clone(),
+            reshows_left: if options.persistent { MAX_BALLOON_RESHOWS } else { 0 },
+            internal: false,
+        });
+        let pending = self.balloon_actions.iter().filter(|pending| !pending.internal).count();
+        if pending > self.max_pending_balloons {
+            if let Some(oldest) = self.balloon_actions.iter().position(|pending| !pending.internal) {
+                self.balloon_actions.remove(oldest);
+            }
+            let _ = self.event_sender.send(Event::BalloonDropped);
+        }
+        Ok(())
+    }
+
+    // Queued with the balloons of the application all the same, as the shell reports on
+    // the balloons in the order they were shown, but with no action of its own to run.
+    #[cfg(feature = "balloons")]
+    fn show_internal_balloon(&mut self, title: &str, body: &str, options: &NotificationOptions) -> Result<()> {
+        self.window.show_balloon(title, body, options)?;
+        self.balloon_actions.push_back(PendingBalloon {
+            context: BalloonContext {
+                title: title.to_string(),
+                body: body.to_string(),
+                data: None,
+            },
+            action: Box::new(|_, _| ActionResult::Continue),
+            options: options.clone(),
+            reshows_left: 0,
+            internal: true,
+        });
+        Ok(())
+    }
+
+    // A persistent balloon goes back to the end of the queue, as the shell
+    // shows it after the ones already waiting.
+    #[cfg(feature = "balloons")]
+    fn reshow_dismissed_balloon(&mut self) {
+        if let Some(mut pending) = self.balloon_actions.pop_front() {
+            if pending.reshows_left == 0 || self.notifications_muted {
+                return;
+            }
+            pending.reshows_left -= 1;
+            // if the balloon cannot be shown again, it is let go like any other
+            match self.window.show_balloon(&pending.context.title, &pending.context.body, &pending.options) {
+                Ok(()) => self.balloon_actions.push_back(pending),
+                Err(e) => self.report_error(e),
+            }
+        }
+    }
+
+    #[cfg(feature = "balloons")]
+    pub fn stop_persistent_balloons(&mut self) {
+        for pending in self.balloon_actions.iter_mut() {
+            pending.reshows_left = 0;
+        }
+    }
+
+    #[cfg(feature = "balloons")]
+    pub fn prompt_to_pin(&mut self) -> Result<bool> {
+        if self.pin_prompted || self.window.is_icon_visible()? {
+            return Ok(false);
+        }
+        let body = self.strings.pin_prompt.clone();
+        let options = NotificationOptions { no_title: true, ..NotificationOptions::default() };
+        if !self.notifications_muted {
+            self.show_internal_balloon("", &body, &options)?;
+        }
+        self.pin_prompted = true;
+        Ok(true)
+    }
+
+    #[cfg(feature = "balloons")]
+    fn show_overflow_balloon(&mut self, count: usize) -> Result<()> {
+        let interval = Duration::from_secs(OVERFLOW_BALLOON_INTERVAL_SECS);
+        let due = match self.overflow_announced {
+            Some((announced, at)) => announced != count && at.elapsed() >= interval,
+            None => true,
+        };
+        if !due || self.notifications_muted {
+            return Ok(());
+        }
+        let body = self.strings.more_items_balloon.replace("{}", &count.to_string());
+        let title = self.app_name.clone().unwrap_or_default();
+        self.show_internal_balloon(&title, &body, &NotificationOptions::default())?;
+        self.overflow_announced = Some((count, Instant::now()));
+        Ok(())
+    }
+
+    // A closed tray is no news: somebody closed it, and whatever still ran fails with it.
+    fn report_error(&self, error: Error) {
+        if let ErrorKind::WindowClosed = *error.kind() {
+            return;
+        }
+        let _ = self.error_sender.send(error);
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        if !self.window.is_closed() {
+            self.window.close();
+            let _ = self.event_sender.send(Event::Quit);
+        }
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+}
+
+impl Drop for Repr {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+fn collect_not_retained<F>(items: &[MenuItemInfo], pred: &mut F, removed: &mut Vec<MenuItemId>)
+        where F: FnMut(MenuItemId, &str) -> bool {
+    for item in items {
+        match item.kind {
+            MenuItemKind::Item | MenuItemKind::OwnerDrawn => {
+                if !pred(item.id, &item.label) {
+                    removed.push(item.id);
+                }
+            }
+            MenuItemKind::Submenu(ref children) => collect_not_retained(children, pred, removed),
+            MenuItemKind::Separator => {}
+        }
+    }
+}
+
+// The loop only holds the tray while it handles an event, so that dropping every `Wna`
+// drops `Repr`, which closes the window and disconnects the channel.
+fn start_event_loop(receiver: Receiver<Event>, repr: Weak<Mutex<Repr>>, mut unhandled_menu_action: Option<UnhandledMenuAction>,
+        mut custom_event_action: Option<CustomEventAction>, name: String, last_panic: PanicSlot) -> Result<thread::JoinHandle<()>> {
+    thread::Builder::new().name(name).spawn(move || {
+        let loop_panic = Arc::clone(&last_panic);
+        let result = panic::catch_unwind(AssertUnwindSafe(move || {
+            loop {
+                let event = receiver.recv();
+                let repr = match repr.upgrade() {
+                    Some(repr) => repr,
+                    None => return,
+                };
+                let _quit = LastHandle(&repr);
+                let event = match event {
+                    Ok(Event::Quit) | Err(_) => {
+                        run_quit_action(&repr);
+                        return;
+                    }
+                    Ok(event) => event,
+                };
+                // a panicking action only loses its event, the tray keeps serving the rest
+                let handled = panic::catch_unwind(AssertUnwindSafe(|| match event {
+                    Event::Menu(id) => {
+                        let action = {
+                            let repr = backend::lock(&repr);
+                            repr.actions.get(&id).map(|f| Arc::clone(f))
+                        };
+                        match action {
+                            Some(action) => {
+                                // a busy icon that fails to load only costs the feedback
+                                let _busy = {
+                                    let lock = backend::lock(&repr);
+                                    match lock.show_busy_icon() {
+                                        Ok(true) => Some(BusyIcon(&repr)),
+                                        Ok(false) => None,
+                                        Err(e) => {
+                                            lock.report_error(e);
+                                            None
+                                        }
+                                    }
+                                };
+                                run_action(&repr, &*action);
+                            }
+                            None => if let Some(ref mut unhandled) = unhandled_menu_action {
+                                unhandled(id);
+                            },
+                        }
+                    }
+                    #[cfg(feature = "balloons")]
+                    Event::BalloonShown => {
                         let action = {
-                            let mut repr = repr.lock().unwrap();
-                            repr.actions.get(&id).map(|f| Arc::clone(f))
+                            let repr = backend::lock(&repr);
+                            repr.balloon_actions.front().and_then(|pending| pending.options.shown_action.clone())
                         };
                         if let Some(action) = action {
-                            let mut wna = Wna {
-                                repr: Arc::clone(&repr),
-                                thread: None,
-                            };
-                            action(&mut wna);
+                            run_action(&repr, &*action);
                         }
                     }
+                    #[cfg(feature = "balloons")]
                     Event::Balloon => {
                         let action = {
-                            let mut repr = repr.lock().unwrap();
-                            repr.balloon_action.take()
+                            let mut repr = backend::lock(&repr);
+                            repr.balloon_actions.pop_front()
                         };
+                        if let Some(pending) = action {
+                            run_action(&repr, &|wna: &mut Wna| (pending.action)(wna, &pending.context));
+                        }
+                    }
+                    #[cfg(feature = "balloons")]
+                    Event::BalloonDismissed => {
+                        let mut repr = backend::lock(&repr);
+                        repr.reshow_dismissed_balloon();
+                    }
+                    #[cfg(feature = "balloons")]
+                    Event::BalloonHidden => {
+                        let mut repr = backend::lock(&repr);
+                        repr.balloon_actions.pop_front();
+                    }
+                    #[cfg(feature = "balloons")]
+                    Event::BalloonDropped => {
+                        let action = backend::lock(&repr).balloon_dropped_action.as_ref().map(|f| Arc::clone(f));
                         if let Some(action) = action {
-                            let mut wna = Wna {
-                                repr: Arc::clone(&repr),
-                                thread: None,
-                            };
-                            action(&mut wna);
+                            run_action(&repr, &*action);
                         }
                     }
-                    Event::Quit => {
-                        return;
+                    #[cfg(feature = "balloons")]
+                    Event::MenuOverflow(count) => {
+                        let mut repr = backend::lock(&repr);
+                        if let Err(e) = repr.show_overflow_balloon(count) {
+                            repr.report_error(e);
+                        }
+                    }
+                    Event::Timer(id) => {
+                        let action = {
+                            let repr = backend::lock(&repr);
+                            repr.timers.get(&id).map(|f| Arc::clone(f))
+                        };
+                        if let Some(action) = action {
+                            run_action(&repr, &*action);
+                        }
+                    }
+                    Event::DisplayChanged => {
+                        let action = {
+                            let repr = backend::lock(&repr);
+                            repr.display_changed_action.as_ref().map(|f| Arc::clone(f))
+                        };
+                        if let Some(action) = action {
+                            run_action(&repr, &*action);
+                        }
+                    }
+                    Event::Idle => {
+                        let action = {
+                            let repr = backend::lock(&repr);
+                            repr.idle_action.as_ref().map(|f| Arc::clone(f))
+                        };
+                        if let Some(action) = action {
+                            run_action(&repr, &*action);
+                        }
+                    }
+                    Event::LeftClick | Event::RightClick => {
+                        let action = {
+                            let repr = backend::lock(&repr);
+                            let action = if event == Event::LeftClick { &repr.left_click_action } else { &repr.right_click_action };
+                            action.as_ref().map(|f| Arc::clone(f))
+                        };
+                        if let Some(action) = action {
+                            run_action(&repr, &*action);
+                        }
+                    }
+                    Event::Ready => {
+                        let action = {
+                            let mut repr = backend::lock(&repr);
+                            repr.ready_action.take()
+                        };
+                        if let Some(action) = action {
+                            run_action(&repr, &*action);
+                        }
+                        #[cfg(feature = "balloons")]
+                        {
+                            let balloon = backend::lock(&repr).startup_balloon.take();
+                            if let Some((title, body)) = balloon {
+                                let mut wna = Wna::with_repr(Arc::clone(&repr), None, None);
+                                if let Err(e) = wna.show_balloon(&title, &body, |_| ()) {
+                                    wna.report_error(e);
+                                }
+                            }
+                        }
+                    }
+                    Event::Custom(value) => {
+                        if let Some(ref mut action) = custom_event_action {
+                            let mut wna = Wna::with_repr(Arc::clone(&repr), None, None);
+                            action(&mut wna, value);
+                        }
                     }
-                },
-                Err(_) => { 
-                    return;
+                    // handled above
+                    Event::Quit => {}
+                }));
+                if let Err(payload) = handled {
+                    *backend::lock(&loop_panic) = Some(panic_message(&*payload));
+                }
+            }
+        }));
+        if let Err(payload) = result {
+            *backend::lock(&last_panic) = Some(panic_message(&*payload));
+        }
+    }).map_err(|e| ErrorKind::Msg(format!("Error starting event loop: {}", e)).into())
+}
+
+// Clears the busy icon when dropped, even while a panicking action unwinds.
+struct BusyIcon<'a>(&'a Arc<Mutex<Repr>>);
+
+impl<'a> Drop for BusyIcon<'a> {
+    fn drop(&mut self) {
+        let repr = backend::lock(self.0);
+        if let Err(e) = repr.window.set_busy_icon(None) {
+            repr.report_error(e);
+        }
+    }
+}
+
+// Runs the quit action when dropped if the loop has the last handle on the tray,
+// as every `Wna` was dropped while it handled an event.
+struct LastHandle<'a>(&'a Arc<Mutex<Repr>>);
+
+impl<'a> Drop for LastHandle<'a> {
+    fn drop(&mut self) {
+        run_quit_action_if_last(self.0);
+    }
+}
+
+fn run_action(repr: &Arc<Mutex<Repr>>, action: &dyn Fn(&mut Wna) -> ActionResult) {
+    let mut wna = Wna::with_repr(Arc::clone(repr), None, None);
+    if action(&mut wna) == ActionResult::Quit {
+        if let Err(e) = wna.close() {
+            wna.report_error(e);
+        }
+    }
+}
+
+fn run_quit_action_if_last(repr: &Arc<Mutex<Repr>>) {
+    if Arc::strong_count(repr) == 1 {
+        run_quit_action(repr);
+    }
+}
+
+// the quit action is taken out of `Repr`, so it runs at most once
+// no matter how many times `close` has been called
+fn run_quit_action(repr: &Arc<Mutex<Repr>>) {
+    let action = {
+        let mut repr = backend::lock(repr);
+        repr.quit_action.take()
+    };
+    if let Some(action) = action {
+        let mut wna = Wna::with_repr(Arc::clone(repr), None, None);
+        action(&mut wna);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn mock_state(wna: &Wna) -> mock::MockState {
+        wna.mock().unwrap().state()
+    }
+
+    // as if the window had reported `event`, e.g. `Event::Menu(id)` for a click
+    fn send_event(wna: &Wna, event: Event) {
+        backend::lock(&wna.repr).event_sender.send(event).unwrap();
+    }
+
+    // runs the `on_menu_opening` action without the lock held, as opening the menu would
+    fn open_menu(wna: &Wna) {
+        let callback = wna.mock().unwrap().menu_opening_callback();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    fn labels(wna: &Wna) -> Vec<String> {
+        mock_state(wna).menu.into_iter().map(|item| item.label).collect()
+    }
+
+    fn icon_file(icon: &Option<Icon>) -> Option<String> {
+        match *icon {
+            Some(Icon::File(ref path)) => Some(path.clone()),
+            _ => None,
+        }
+    }
+
+    // polls `f`, for what the event loop does on its own thread without reporting back
+    fn wait_until<F>(mut f: F) where F: FnMut() -> bool {
+        let start = ::std::time::Instant::now();
+        while !f() {
+            assert!(start.elapsed() < TIMEOUT, "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn builder_sets_icon_tip_and_menu() {
+        let mut builder = Wna::new();
+        builder
+            .icon(Icon::file("tray.ico"))
+            .tip("Tray")
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()))
+            .menu_item(MenuItem::Separator)
+            .menu_item(MenuItem::quit());
+        let wna = builder.build_mock().unwrap();
+        let state = mock_state(&wna);
+        match state.icon {
+            Some(Icon::File(ref path)) => assert_eq!(path, "tray.ico"),
+            _ => panic!("icon not set"),
+        }
+        assert_eq!(state.tip, Some("Tray".to_string()));
+        let ids: Vec<MenuItemId> = state.menu.iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(labels(&wna), vec!["Open", "", "Quit"]);
+        assert_eq!(state.menu[1].kind, MenuItemKind::Separator);
+    }
+
+    #[test]
+    fn owned_icon_clones_share_one_handle() {
+        let icon = Icon::owned(::std::ptr::null_mut());
+        match (icon.clone(), icon) {
+            (Icon::OwnedHicon(copy), Icon::OwnedHicon(original)) => {
+                assert!(Arc::ptr_eq(&copy, &original));
+                drop(copy);
+                assert_eq!(Arc::strong_count(&original), 1);
+            }
+            _ => panic!("not an owned icon"),
+        }
+    }
+
+    #[test]
+    fn menu_max_items_is_at_least_one() {
+        let mut builder = Wna::new();
+        builder.menu_max_items(0);
+        assert_eq!(builder.menu_max_items, Some(1));
+    }
+
+    #[test]
+    fn attached_tray_numbers_items_above_the_application_ids() {
+        let mut builder = Wna::new();
+        builder
+            .with_existing_window(0x1234 as HWND)
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()))
+            .menu_item(MenuItem::quit());
+        let wna = builder.build_mock().unwrap();
+        let ids: Vec<MenuItemId> = mock_state(&wna).menu.iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![ATTACHED_FIRST_MENU_ID, ATTACHED_FIRST_MENU_ID + 1]);
+    }
+
+    #[test]
+    fn menu_event_runs_the_action_on_the_event_loop() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder.menu_item(MenuItem::action("Open".to_string(), move |_| {
+            sender.lock().unwrap().send(thread::current().name().map(|name| name.to_string())).unwrap();
+        }));
+        let wna = builder.build_mock().unwrap();
+        send_event(&wna, Event::Menu(0));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), Some("wna-event-loop".to_string()));
+    }
+
+    #[test]
+    fn busy_icon_is_shown_over_the_animation_while_the_action_runs() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder
+            .icon(Icon::file("idle.ico"))
+            .busy_icon(Icon::file("busy.ico"))
+            .menu_item(MenuItem::action("Work".to_string(), move |wna| {
+                let busy = mock_state(wna).busy_icon;
+                sender.lock().unwrap().send(icon_file(&busy)).unwrap();
+            }));
+        let mut wna = builder.build_mock().unwrap();
+        let _animation = wna.animate_icon(vec![Icon::file("frame.ico")], Duration::from_secs(1)).unwrap();
+        send_event(&wna, Event::Menu(0));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), Some("busy.ico".to_string()));
+        wait_until(|| mock_state(&wna).busy_icon.is_none());
+        let state = mock_state(&wna);
+        assert_eq!(state.animation, Some(1));
+        assert_eq!(icon_file(&state.icon), Some("idle.ico".to_string()));
+    }
+
+    #[test]
+    fn busy_icon_is_cleared_when_the_action_panics() {
+        let mut builder = Wna::new();
+        builder
+            .busy_icon(Icon::file("busy.ico"))
+            .menu_item(MenuItem::action("Fail".to_string(), |_| -> () { panic!("the action failed") }));
+        let wna = builder.build_mock().unwrap();
+        send_event(&wna, Event::Menu(0));
+        wait_until(|| wna.last_panic().is_some());
+        assert!(mock_state(&wna).busy_icon.is_none());
+    }
+
+    #[test]
+    fn event_loop_keeps_running_after_an_action_panics() {
+        let (sender, receiver) = channel();
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Fail".to_string(), |_| -> () { panic!("the action failed") }))
+            .menu_item(MenuItem::action("Open".to_string(), move |_| { let _ = sender.send(()); }));
+        let wna = builder.build_mock().unwrap();
+        send_event(&wna, Event::Menu(0));
+        send_event(&wna, Event::Menu(1));
+        receiver.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(wna.last_panic(), Some("the action failed".to_string()));
+        assert!(!mock_state(&wna).closed);
+    }
+
+    #[cfg(feature = "balloons")]
+    fn show_checked(wna: &mut Wna) -> BalloonOutcome {
+        wna.show_balloon_checked("Title", "Body", &NotificationOptions::default(), |_| ()).unwrap()
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn checked_balloon_reports_suppression_before_and_after_showing() {
+        let mut wna = Wna::new().build_mock().unwrap();
+        let mock = wna.mock().unwrap();
+        mock.update(|state| state.suppression.push_back(Some(SuppressionReason::PresentationMode)));
+        assert_eq!(show_checked(&mut wna), BalloonOutcome::Suppressed(SuppressionReason::PresentationMode));
+        assert!(mock.state().balloons.is_empty());
+        mock.update(|state| state.suppression.extend(vec![None, Some(SuppressionReason::Busy)]));
+        assert_eq!(show_checked(&mut wna), BalloonOutcome::Suppressed(SuppressionReason::Busy));
+        assert_eq!(mock.state().balloons.len(), 1);
+        assert_eq!(show_checked(&mut wna), BalloonOutcome::Shown);
+        wna.set_notifications_muted(true);
+        assert_eq!(show_checked(&mut wna), BalloonOutcome::Suppressed(SuppressionReason::Muted));
+        assert_eq!(mock.state().balloons.len(), 2);
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn checked_balloon_refused_by_the_shell_has_failed() {
+        let mut wna = Wna::new().build_mock().unwrap();
+        let mock = wna.mock().unwrap();
+        mock.update(|state| state.refuse_balloons = true);
+        assert_eq!(show_checked(&mut wna), BalloonOutcome::Failed);
+        wna.clone().close().unwrap();
+        assert!(wna.show_balloon_checked("Title", "Body", &NotificationOptions::default(), |_| ()).is_err());
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn app_name_titles_untitled_balloons_but_not_the_pin_prompt() {
+        let mut builder = Wna::new();
+        builder.app_name("App");
+        let mut wna = builder.build_mock().unwrap();
+        let mock = wna.mock().unwrap();
+        wna.show_balloon_body_only("Body", |_| ()).unwrap();
+        mock.update(|state| state.visible = false);
+        assert!(wna.prompt_to_pin().unwrap());
+        let titles: Vec<String> = mock.state().balloons.into_iter().map(|(title, _)| title).collect();
+        assert_eq!(titles, vec!["App".to_string(), String::new()]);
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn persistent_balloon_is_shown_again_on_timeout_but_not_when_hidden() {
+        let mut wna = Wna::new().build_mock().unwrap();
+        let mut options = NotificationOptions::new();
+        options.persistent(true);
+        wna.show_notification("Title", "Body", &options, |_| ()).unwrap();
+        send_event(&wna, Event::BalloonDismissed);
+        sync_event_loop(&wna, 100);
+        assert_eq!(mock_state(&wna).balloons.len(), 2);
+        send_event(&wna, Event::BalloonHidden);
+        sync_event_loop(&wna, 100);
+        assert_eq!(mock_state(&wna).balloons.len(), 2);
+        assert!(backend::lock(&wna.repr).balloon_actions.is_empty());
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn event_loop_and_action_errors_reach_the_error_receiver() {
+        let mut builder = Wna::new();
+        builder
+            .overflow_balloon(true)
+            .menu_item(MenuItem::action("Fail".to_string(), |wna| wna.report_error("the action failed".into())));
+        let mut wna = builder.build_mock().unwrap();
+        let errors = wna.take_error_receiver().unwrap();
+        wna.mock().unwrap().update(|state| state.refuse_balloons = true);
+        send_event(&wna, Event::MenuOverflow(3));
+        let error = errors.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(error.operation(), Some(&Op::ShowBalloon));
+        send_event(&wna, Event::Menu(0));
+        let error = errors.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(error.to_string(), "the action failed");
+        wna.clone().close().unwrap();
+        wna.report_error(ErrorKind::WindowClosed.into());
+        assert!(errors.try_recv().is_err());
+    }
+
+    #[test]
+    fn retained_menu_items_still_dispatch() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Expired 1".to_string(), |_| ()))
+            .menu_item(MenuItem::action("Current".to_string(), move |_| { let _ = sender.lock().unwrap().send(()); }))
+            .menu_item(MenuItem::Separator)
+            .menu_item(MenuItem::action("Expired 2".to_string(), |_| ()));
+        let mut wna = builder.build_mock().unwrap();
+        wna.retain_menu_items(|_, label| !label.starts_with("Expired")).unwrap();
+        assert_eq!(labels(&wna), vec!["Current".to_string(), String::new()]);
+        assert!(!backend::lock(&wna.repr).actions.contains_key(&0));
+        assert!(!backend::lock(&wna.repr).actions.contains_key(&3));
+        send_event(&wna, Event::Menu(1));
+        receiver.recv_timeout(TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn retain_menu_items_removes_the_rest_after_a_failure() {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("First".to_string(), |_| ()))
+            .menu_item(MenuItem::action("Second".to_string(), |_| ()))
+            .menu_item(MenuItem::toggle("Third".to_string(), false, |_, _| ()));
+        let mut wna = builder.build_mock().unwrap();
+        let mut other = wna.clone();
+        // the second item is gone by the time it is to be removed
+        let result = wna.retain_menu_items(|id, _| {
+            if id == 0 {
+                other.remove_menu_item(1).unwrap();
+            }
+            false
+        });
+        match result {
+            Err(Error(ErrorKind::MenuItemNotFound(1), _)) => {}
+            _ => panic!("the failed removal was not reported"),
+        }
+        assert!(labels(&wna).is_empty());
+        let repr = backend::lock(&wna.repr);
+        assert!(repr.actions.is_empty());
+        assert!(repr.toggles.is_empty());
+    }
+
+    #[test]
+    fn errors_display_what_failed() {
+        let display = |kind: ErrorKind| Error::from(kind).to_string();
+        assert_eq!(display(ErrorKind::ActionsNotReplaceable(vec![1, 2])),
+                   "Actions of toggle, data and quit menu items cannot be replaced: [1, 2]");
+        assert_eq!(display(ErrorKind::IconFileNotFound("app.ico".to_string())), "Icon file not found: 'app.ico'");
+        assert_eq!(display(ErrorKind::InvalidWindowClass(String::new())), "Invalid window class name: ''");
+        assert_eq!(display(ErrorKind::MenuItemNotFound(3)), "Menu item 3 not found");
+        assert_eq!(display(ErrorKind::MenuItemsNotFound(vec![3, 4])), "Menu items not found: [3, 4]");
+        assert!(display(ErrorKind::NoInteractiveSession).contains("outside an interactive session"));
+        assert!(display(ErrorKind::UnknownStatus).contains("WnaBuilder::status_icons"));
+        assert_eq!(display(ErrorKind::WindowClosed), "Window is closed");
+        assert_eq!(display(ErrorKind::WindowCreationTimeout), "Window thread did not report back in time");
+        assert!(display(ErrorKind::HResult(Op::GetIconRect, 0x8000_4005))
+            .starts_with("wna: failed to get taskbar icon rect (HRESULT 0x80004005"));
+        assert!(display(ErrorKind::Win32(Op::AddMenuItem, 1450))
+            .starts_with("wna: failed to add menu item (Win32 error 1450"));
+        assert!(display(ErrorKind::Win32(Op::LoadIconFromFile("app.ico".to_string()), 2))
+            .starts_with("wna: failed to load icon from file 'app.ico' (Win32 error 2"));
+    }
+
+    #[test]
+    fn errors_expose_the_failed_operation_and_code() {
+        let hresult = Error::from(ErrorKind::HResult(Op::QueryNotificationState, 0x8000_4005));
+        assert_eq!(hresult.operation(), Some(&Op::QueryNotificationState));
+        assert_eq!(hresult.hresult(), Some(0x8000_4005));
+        assert_eq!(hresult.win32_code(), None);
+        let win32 = Error::from(ErrorKind::Win32(Op::SetTimer, 8));
+        assert_eq!(win32.operation(), Some(&Op::SetTimer));
+        assert_eq!(win32.win32_code(), Some(8));
+        assert_eq!(win32.hresult(), None);
+    }
+
+    fn replaceable_menu() -> Wna {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()))
+            .menu_item(MenuItem::toggle("Sync".to_string(), false, |_, _| ()))
+            .menu_item(MenuItem::quit());
+        builder.build_mock().unwrap()
+    }
+
+    #[test]
+    fn replace_actions_swaps_handlers_and_keeps_wrapped_ones() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut wna = replaceable_menu();
+        let mut actions: HashMap<MenuItemId, Action> = HashMap::new();
+        actions.insert(0, boxed_action(move |_| sender.lock().unwrap().send("replaced").unwrap()));
+        wna.replace_actions(actions).unwrap();
+        send_event(&wna, Event::Menu(0));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), "replaced");
+        send_event(&wna, Event::Menu(1));
+        wait_until(|| mock_state(&wna).menu[1].checked);
+    }
+
+    #[test]
+    fn failed_add_menu_item_records_nothing() {
+        let mut wna = Wna::new().build_mock().unwrap();
+        wna.mock().unwrap().update(|state| state.refuse_menu_items = true);
+        assert!(wna.add_menu_item(MenuItem::toggle("Sync".to_string(), false, |_, _| ())).is_err());
+        assert!(wna.add_menu_item(MenuItem::quit()).is_err());
+        assert!(wna.add_menu_item(MenuItem::action_with_data("Open".to_string(), 7u32, |_, _: &u32| ())).is_err());
+        let repr = backend::lock(&wna.repr);
+        assert!(repr.toggles.is_empty());
+        assert!(repr.item_data.is_empty());
+        assert!(repr.quit_items.is_empty());
+        assert!(repr.actions.is_empty());
+    }
+
+    #[test]
+    fn failed_set_menu_keeps_the_old_menu_working() {
+        let mut wna = replaceable_menu();
+        wna.mock().unwrap().update(|state| state.refuse_menu_items = true);
+        assert!(wna.set_menu(vec![MenuItem::action("Close".to_string(), |_| ())]).is_err());
+        assert_eq!(labels(&wna), ["Open", "Sync", "Quit"]);
+        {
+            let repr = backend::lock(&wna.repr);
+            assert_eq!(repr.toggles.get(&1), Some(&false));
+            assert!(repr.quit_items.contains(&2));
+        }
+        send_event(&wna, Event::Menu(1));
+        wait_until(|| mock_state(&wna).menu[1].checked);
+    }
+
+    #[test]
+    fn replace_actions_rejects_wrapped_and_unknown_items() {
+        let mut wna = replaceable_menu();
+        let actions = |ids: &[MenuItemId]| -> HashMap<MenuItemId, Action> {
+            ids.iter().map(|&id| (id, boxed_action(|_| ()))).collect()
+        };
+        match *wna.replace_actions(actions(&[0, 2, 1])).unwrap_err().kind() {
+            ErrorKind::ActionsNotReplaceable(ref ids) => assert_eq!(ids, &vec![1, 2]),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+        match *wna.replace_actions(actions(&[9, 0, 7])).unwrap_err().kind() {
+            ErrorKind::MenuItemsNotFound(ref ids) => assert_eq!(ids, &vec![7, 9]),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+        assert_eq!(backend::lock(&wna.repr).actions.len(), 3);
+    }
+
+    // every event sent before has been handled once this returns
+    fn sync_event_loop(wna: &Wna, marker: MenuItemId) {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        backend::lock(&wna.repr).actions.insert(marker, Arc::new(boxed_action(move |_| {
+            let _ = sender.lock().unwrap().send(());
+        })));
+        send_event(wna, Event::Menu(marker));
+        receiver.recv_timeout(TIMEOUT).unwrap();
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn overflow_balloon_is_shown_once_per_count_and_queued_apart_from_the_app_balloons() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder.app_name("App").max_pending_balloons(1).overflow_balloon(true);
+        let mut wna = builder.build_mock().unwrap();
+        send_event(&wna, Event::MenuOverflow(3));
+        send_event(&wna, Event::MenuOverflow(3));
+        send_event(&wna, Event::MenuOverflow(4));
+        sync_event_loop(&wna, 100);
+        // the app balloon is not dropped for the overflow balloon ahead of it
+        wna.show_balloon("Saved", "The file is saved", move |_| {
+            let _ = sender.lock().unwrap().send(());
+        }).unwrap();
+        assert_eq!(mock_state(&wna).balloons, [
+            ("App".to_string(), "3 more items available".to_string()),
+            ("Saved".to_string(), "The file is saved".to_string()),
+        ]);
+        // the shell reports on the overflow balloon first, as it was shown first
+        send_event(&wna, Event::Balloon);
+        sync_event_loop(&wna, 101);
+        assert!(receiver.try_recv().is_err());
+        let repr = backend::lock(&wna.repr);
+        assert_eq!(repr.balloon_actions.len(), 1);
+        assert_eq!(repr.balloon_actions[0].context.title, "Saved");
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn dropped_balloon_action_runs_on_the_event_loop() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder.max_pending_balloons(1).on_balloon_dropped(move |_| {
+            sender.lock().unwrap().send(thread::current().name().map(|name| name.to_string())).unwrap();
+        });
+        let mut wna = builder.build_mock().unwrap();
+        wna.show_balloon("First", "", |_| ()).unwrap();
+        assert!(receiver.try_recv().is_err());
+        wna.show_balloon("Second", "", |_| ()).unwrap();
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), Some("wna-event-loop".to_string()));
+    }
+
+    #[test]
+    fn post_event_only_accepts_custom_and_quit() {
+        let (sender, receiver) = channel();
+        let mut builder = Wna::new();
+        builder.on_custom(move |_, value| sender.send(value).unwrap());
+        let wna = builder.build_mock().unwrap();
+        assert!(wna.post_event(Event::Menu(0)).is_err());
+        assert!(wna.post_event(Event::Ready).is_err());
+        wna.post_event(Event::Custom(7)).unwrap();
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), 7);
+        wna.post_event(Event::Quit).unwrap();
+        assert!(mock_state(&wna).closed);
+    }
+
+    fn counting_quit(builder: &mut WnaBuilder) -> Arc<AtomicUsize> {
+        let quits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&quits);
+        builder.on_quit(move |_| { counter.fetch_add(1, Ordering::SeqCst); });
+        quits
+    }
+
+    #[test]
+    fn dropping_the_last_wna_runs_on_quit_and_stops_the_event_loop() {
+        let mut builder = Wna::new();
+        let quits = counting_quit(&mut builder);
+        let mut wna = builder.build_mock().unwrap();
+        let mock = wna.mock().unwrap();
+        let thread = wna.thread.take().unwrap();
+        let clone = wna.clone();
+        drop(wna);
+        assert_eq!(quits.load(Ordering::SeqCst), 0);
+        assert!(!mock.state().closed);
+        drop(clone);
+        assert_eq!(quits.load(Ordering::SeqCst), 1);
+        assert!(mock.state().closed);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn closing_a_clone_closes_the_tray_and_ends_the_event_loop() {
+        let wna = Wna::new().build_mock().unwrap();
+        let mut clone = wna.clone();
+        thread::spawn(move || clone.close().unwrap()).join().unwrap();
+        assert!(wna.is_closed());
+        assert!(mock_state(&wna).closed);
+        wna.clone().close().unwrap();
+        wna.join_event_loop();
+    }
+
+    #[test]
+    fn on_quit_runs_once_for_close_and_drop() {
+        let mut builder = Wna::new();
+        let quits = counting_quit(&mut builder);
+        let wna = builder.build_mock().unwrap();
+        wna.clone().close().unwrap();
+        wait_until(|| quits.load(Ordering::SeqCst) == 1);
+        drop(wna);
+        assert_eq!(quits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn menu_opening_action_can_update_the_menu() {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Stale".to_string(), |_| ()))
+            .on_menu_opening(|wna| {
+                let _ = wna.set_menu(vec![MenuItem::action("Fresh".to_string(), |_| ())]);
+            });
+        let wna = builder.build_mock().unwrap();
+        open_menu(&wna);
+        assert_eq!(labels(&wna), vec!["Fresh"]);
+    }
+
+    #[test]
+    fn build_with_events_leaves_events_to_recv_event() {
+        let mut builder = Wna::new();
+        builder.menu_item(MenuItem::action("Open".to_string(), |_| -> () { panic!("actions do not run") }));
+        let wna = builder.build_mock_with_events().unwrap();
+        assert_eq!(wna.recv_event(), Some(Event::Ready));
+        send_event(&wna, Event::Menu(0));
+        assert_eq!(wna.recv_event(), Some(Event::Menu(0)));
+        assert_eq!(wna.try_recv_event(), None);
+    }
+
+    #[test]
+    fn quit_item_close_and_drop_run_on_quit_once() {
+        let mut builder = Wna::new();
+        builder.menu_item(MenuItem::quit());
+        let quits = counting_quit(&mut builder);
+        let mut wna = builder.build_mock().unwrap();
+        send_event(&wna, Event::Menu(0));
+        wait_until(|| quits.load(Ordering::SeqCst) == 1);
+        wna.close().unwrap();
+        drop(wna);
+        assert_eq!(quits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn empty_or_overlong_window_class_is_rejected() {
+        let too_long: &'static str = Box::leak("c".repeat(MAX_WINDOW_CLASS_LEN + 1).into_boxed_str());
+        for &class in &["", too_long] {
+            let mut builder = Wna::new();
+            builder.window_class(class);
+            match builder.build_mock() {
+                Err(Error(ErrorKind::InvalidWindowClass(ref name), _)) => assert_eq!(name, class),
+                _ => panic!("window class {:?} accepted", class),
+            }
+        }
+        let longest: &'static str = Box::leak("c".repeat(MAX_WINDOW_CLASS_LEN).into_boxed_str());
+        let mut builder = Wna::new();
+        builder.window_class(longest);
+        assert!(builder.build_mock().is_ok());
+    }
+
+    #[test]
+    fn find_menu_item_returns_the_topmost_match() {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()))
+            .menu_item(MenuItem::action("Recent".to_string(), |_| ()))
+            .menu_item(MenuItem::action("Recent".to_string(), |_| ()));
+        let wna = builder.build_mock().unwrap();
+        assert_eq!(wna.find_menu_item("Open"), Some(0));
+        assert_eq!(wna.find_menu_item("Recent"), Some(1));
+        assert_eq!(wna.find_menu_item("Close"), None);
+    }
+
+    fn is_window_closed<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error(ErrorKind::WindowClosed, _)))
+    }
+
+    #[test]
+    fn calls_on_a_closed_tray_fail_with_window_closed() {
+        let mut wna = Wna::new().build_mock().unwrap();
+        wna.close().unwrap();
+        wna.close().unwrap();
+        assert!(is_window_closed(wna.set_tip("tip")));
+        assert!(is_window_closed(wna.set_icon(&Icon::file("tray.ico"))));
+        assert!(is_window_closed(wna.add_menu_item(MenuItem::action("Open".to_string(), |_| ()))));
+        #[cfg(feature = "balloons")]
+        assert!(is_window_closed(wna.show_balloon("Title", "Body", |_| ())));
+    }
+
+    #[test]
+    fn clicks_go_to_the_swapped_in_menu() {
+        let (sender, receiver) = channel();
+        let unhandled = Mutex::new(sender.clone());
+        let fresh = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Stale".to_string(), |_| -> () { panic!("the old action ran") }))
+            .on_unhandled_menu(move |id| { let _ = unhandled.lock().unwrap().send(format!("unhandled {}", id)); });
+        let mut wna = builder.build_mock().unwrap();
+        wna.set_menu(vec![MenuItem::action("Fresh".to_string(), move |_| { let _ = fresh.lock().unwrap().send("fresh".to_string()); })]).unwrap();
+        assert_eq!(labels(&wna), vec!["Fresh"]);
+        send_event(&wna, Event::Menu(0));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), "unhandled 0");
+        send_event(&wna, Event::Menu(1));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), "fresh");
+    }
+
+    #[test]
+    fn tri_state_item_takes_every_state() {
+        let mut builder = Wna::new();
+        builder.menu_item(MenuItem::tri_state("Sync".to_string(), MenuItemState::Indeterminate, |_| ()));
+        let mut wna = builder.build_mock().unwrap();
+        let checked = |wna: &Wna| mock_state(wna).menu[0].checked;
+        assert!(checked(&wna));
+        wna.set_menu_item_state(0, MenuItemState::Unchecked).unwrap();
+        assert!(!checked(&wna));
+        wna.set_menu_item_state(0, MenuItemState::Checked).unwrap();
+        assert!(checked(&wna));
+        assert!(wna.set_menu_item_state(1, MenuItemState::Checked).is_err());
+    }
+
+    #[test]
+    fn reenabling_the_menu_keeps_disabled_items_disabled() {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()))
+            .menu_item(MenuItem::action("Sync".to_string(), |_| ()));
+        let mut wna = builder.build_mock().unwrap();
+        let enabled = |wna: &Wna| mock_state(wna).menu.iter().map(|item| item.enabled).collect::<Vec<_>>();
+        wna.set_menu_item_enabled(1, false).unwrap();
+        wna.set_menu_enabled(false).unwrap();
+        assert_eq!(enabled(&wna), vec![false, false]);
+        wna.add_menu_item(MenuItem::action("Added".to_string(), |_| ())).unwrap();
+        wna.set_menu_item_enabled(1, true).unwrap();
+        assert_eq!(enabled(&wna), vec![false, false, false]);
+        wna.set_menu_item_enabled(0, false).unwrap();
+        wna.set_menu_enabled(true).unwrap();
+        assert_eq!(enabled(&wna), vec![false, true, true]);
+    }
+
+    #[test]
+    fn updated_action_fires_in_place_of_the_old_one() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder.menu_item(MenuItem::action("Open".to_string(), |_| -> () { panic!("the old action ran") }));
+        let mut wna = builder.build_mock().unwrap();
+        wna.set_menu_item_action(0, move |_| { let _ = sender.lock().unwrap().send(()); }).unwrap();
+        send_event(&wna, Event::Menu(0));
+        receiver.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(labels(&wna), vec!["Open"]);
+        match wna.set_menu_item_action(1, |_| ()) {
+            Err(Error(ErrorKind::MenuItemNotFound(1), _)) => {}
+            _ => panic!("missing item accepted"),
+        }
+    }
+
+    #[test]
+    fn quit_item_takes_its_label_from_the_strings() {
+        let mut builder = Wna::new();
+        builder
+            .strings(Strings { quit: "Beenden".to_string(), ..Strings::default() })
+            .menu_item(MenuItem::quit());
+        let wna = builder.build_mock().unwrap();
+        assert_eq!(labels(&wna), vec!["Beenden"]);
+    }
+
+    #[test]
+    fn hidden_icon_stays_registered_and_can_be_shown_again() {
+        let mut builder = Wna::new();
+        builder.menu_item(MenuItem::action("Open".to_string(), |_| ()));
+        let mut wna = builder.build_mock().unwrap();
+        wna.hide().unwrap();
+        assert!(!wna.is_icon_visible().unwrap());
+        wna.set_tip("Hidden").unwrap();
+        wna.show().unwrap();
+        assert!(wna.is_icon_visible().unwrap());
+        let state = mock_state(&wna);
+        assert!(!state.closed);
+        assert_eq!(state.tip, Some("Hidden".to_string()));
+        assert_eq!(labels(&wna), vec!["Open"]);
+    }
+
+    #[test]
+    fn status_switches_icon_and_tip() {
+        let mut icons = HashMap::new();
+        icons.insert("idle", (Icon::file("idle.ico"), "Idle".to_string()));
+        icons.insert("syncing", (Icon::file("sync.ico"), "Syncing".to_string()));
+        let mut builder = Wna::new();
+        builder.status_icons(icons);
+        let mut wna = builder.build_mock().unwrap();
+        wna.set_status("syncing").unwrap();
+        let state = mock_state(&wna);
+        assert_eq!(icon_file(&state.icon), Some("sync.ico".to_string()));
+        assert_eq!(state.tip, Some("Syncing".to_string()));
+        wna.set_status("idle").unwrap();
+        let state = mock_state(&wna);
+        assert_eq!(icon_file(&state.icon), Some("idle.ico".to_string()));
+        assert_eq!(state.tip, Some("Idle".to_string()));
+        match wna.set_status("offline") {
+            Err(Error(ErrorKind::UnknownStatus, _)) => {}
+            _ => panic!("unknown status accepted"),
+        }
+        match wna.set_status(1) {
+            Err(Error(ErrorKind::UnknownStatus, _)) => {}
+            _ => panic!("status of another type accepted"),
+        }
+    }
+
+    #[test]
+    fn action_returning_quit_ends_the_event_loop() {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Stay".to_string(), |_| ActionResult::Continue))
+            .menu_item(MenuItem::action("Leave".to_string(), |_| ActionResult::Quit));
+        let quits = counting_quit(&mut builder);
+        let mut wna = builder.build_mock().unwrap();
+        let thread = wna.thread.take().unwrap();
+        send_event(&wna, Event::Menu(0));
+        send_event(&wna, Event::Menu(1));
+        thread.join().unwrap();
+        assert!(wna.is_closed());
+        assert!(mock_state(&wna).closed);
+        assert_eq!(quits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn menu_snapshot_reads_the_menu_from_another_thread() {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::toggle("Sync".to_string(), true, |_, _| ()))
+            .menu_item(MenuItem::Separator)
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()));
+        let mut wna = builder.build_mock().unwrap();
+        wna.set_menu_item_enabled(2, false).unwrap();
+        let clone = wna.clone();
+        let snapshot = thread::spawn(move || clone.menu_snapshot()).join().unwrap().unwrap();
+        let item = |id, label: &str, kind, enabled, checked| MenuItemInfo { id, label: label.to_string(), kind, enabled, checked };
+        assert_eq!(snapshot, vec![
+            item(0, "Sync", MenuItemKind::Item, true, true),
+            item(1, "", MenuItemKind::Separator, true, false),
+            item(2, "Open", MenuItemKind::Item, false, false),
+        ]);
+    }
+
+    #[test]
+    fn dropping_the_interval_handle_stops_the_timer() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let mut wna = Wna::new().build_mock().unwrap();
+        let counter = Arc::clone(&ticks);
+        let handle = wna.set_interval(Duration::from_secs(60), move |_| { counter.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        let id = handle.id;
+        send_event(&wna, Event::Timer(id));
+        wait_until(|| ticks.load(Ordering::SeqCst) == 1);
+        drop(handle);
+        assert!(backend::lock(&wna.repr).timers.is_empty());
+        send_event(&wna, Event::Timer(id));
+        sync_event_loop(&wna, 100);
+        assert_eq!(ticks.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn toggle_item_flips_its_check_on_every_click() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder.menu_item(MenuItem::toggle("Sync".to_string(), false, move |_, checked| {
+            let _ = sender.lock().unwrap().send(checked);
+        }));
+        let wna = builder.build_mock().unwrap();
+        send_event(&wna, Event::Menu(0));
+        assert!(receiver.recv_timeout(TIMEOUT).unwrap());
+        assert!(mock_state(&wna).menu[0].checked);
+        send_event(&wna, Event::Menu(0));
+        assert!(!receiver.recv_timeout(TIMEOUT).unwrap());
+        assert!(!mock_state(&wna).menu[0].checked);
+    }
+
+    #[test]
+    fn command_for_an_unknown_id_goes_to_the_fallback() {
+        let (sender, receiver) = channel();
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Open".to_string(), |_| -> () { panic!("the wrong action ran") }))
+            .on_unhandled_menu(move |id| { let _ = sender.send(id); });
+        let wna = builder.build_mock().unwrap();
+        send_event(&wna, Event::Menu(42));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), 42);
+        assert!(!wna.is_closed());
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn pin_prompt_is_shown_once_and_only_for_an_overflowed_icon() {
+        let mut wna = Wna::new().build_mock().unwrap();
+        assert!(!wna.prompt_to_pin().unwrap());
+        // the mock reports a hidden icon as not on the taskbar, as in the overflow area
+        wna.hide().unwrap();
+        assert!(wna.prompt_to_pin().unwrap());
+        assert!(!wna.prompt_to_pin().unwrap());
+        assert_eq!(mock_state(&wna).balloons.len(), 1);
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn pin_prompt_does_not_push_an_app_balloon_out_of_the_queue() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&dropped);
+        let mut builder = Wna::new();
+        builder.max_pending_balloons(1).on_balloon_dropped(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut wna = builder.build_mock().unwrap();
+        wna.show_balloon("Saved", "The file is saved", |_| ()).unwrap();
+        wna.hide().unwrap();
+        assert!(wna.prompt_to_pin().unwrap());
+        sync_event_loop(&wna, 100);
+        assert_eq!(dropped.load(Ordering::SeqCst), 0);
+        let repr = backend::lock(&wna.repr);
+        let titles: Vec<&str> = repr.balloon_actions.iter().map(|pending| pending.context.title.as_str()).collect();
+        assert_eq!(titles, ["Saved", ""]);
+    }
+
+    #[test]
+    fn item_data_reaches_the_action_and_goes_with_the_item() {
+        let (sender, receiver) = channel();
+        let sender = Arc::new(Mutex::new(sender));
+        let mut builder = Wna::new();
+        for path in &["a.txt", "b.txt"] {
+            let sender = Arc::clone(&sender);
+            builder.menu_item(MenuItem::action_with_data(path.to_string(), path.to_string(), move |_, path: &String| {
+                let _ = sender.lock().unwrap().send(path.clone());
+            }));
+        }
+        let mut wna = builder.build_mock().unwrap();
+        send_event(&wna, Event::Menu(1));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), "b.txt");
+        send_event(&wna, Event::Menu(0));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), "a.txt");
+        wna.remove_menu_item(1).unwrap();
+        assert_eq!(backend::lock(&wna.repr).item_data.keys().collect::<Vec<_>>(), vec![&0]);
+    }
+
+    #[test]
+    fn item_states_set_while_the_menu_opens_are_shown() {
+        let connected = Arc::new(AtomicBool::new(false));
+        let online = Arc::clone(&connected);
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Disconnect".to_string(), |_| ()))
+            .menu_item(MenuItem::tri_state("Online".to_string(), MenuItemState::Unchecked, |_| ()))
+            .on_menu_opening(move |wna| {
+                let online = online.load(Ordering::SeqCst);
+                let state = if online { MenuItemState::Checked } else { MenuItemState::Unchecked };
+                let _ = wna.set_menu_item_enabled(0, online);
+                let _ = wna.set_menu_item_state(1, state);
+            });
+        let wna = builder.build_mock().unwrap();
+        let shown = |wna: &Wna| mock_state(wna).menu.iter().map(|item| (item.enabled, item.checked)).collect::<Vec<_>>();
+        open_menu(&wna);
+        assert_eq!(shown(&wna), vec![(false, false), (true, false)]);
+        connected.store(true, Ordering::SeqCst);
+        open_menu(&wna);
+        assert_eq!(shown(&wna), vec![(true, false), (true, true)]);
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn balloon_past_the_cap_evicts_the_oldest_action() {
+        let (sender, receiver) = channel();
+        let sender = Arc::new(Mutex::new(sender));
+        let mut builder = Wna::new();
+        builder.max_pending_balloons(2);
+        let mut wna = builder.build_mock().unwrap();
+        for title in &["First", "Second", "Third"] {
+            let sender = Arc::clone(&sender);
+            wna.show_balloon(title, "", move |_| { let _ = sender.lock().unwrap().send(title.to_string()); }).unwrap();
+        }
+        let titles: Vec<String> = backend::lock(&wna.repr).balloon_actions.iter().map(|pending| pending.context.title.clone()).collect();
+        assert_eq!(titles, vec!["Second", "Third"]);
+        send_event(&wna, Event::Balloon);
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), "Second");
+    }
+
+    #[test]
+    fn setters_called_from_several_threads_all_apply() {
+        let wna = Wna::new().build_mock().unwrap();
+        let threads: Vec<_> = (0..4).map(|n| {
+            let mut wna = wna.clone();
+            thread::spawn(move || {
+                for i in 0..5 {
+                    wna.set_tip(&format!("Tip {}", n)).unwrap();
+                    wna.set_icon(&Icon::file(format!("{}.ico", n))).unwrap();
+                    wna.add_menu_item(MenuItem::action(format!("{}-{}", n, i), |_| ())).unwrap();
                 }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let state = mock_state(&wna);
+        let mut ids: Vec<MenuItemId> = state.menu.iter().map(|item| item.id).collect();
+        ids.sort();
+        assert_eq!(ids, (0..20).collect::<Vec<_>>());
+        for n in 0..4 {
+            let labels: Vec<String> = state.menu.iter().map(|item| item.label.clone()).filter(|label| label.starts_with(&format!("{}-", n))).collect();
+            assert_eq!(labels, (0..5).map(|i| format!("{}-{}", n, i)).collect::<Vec<_>>());
+        }
+        assert!(state.tip.unwrap().starts_with("Tip "));
+        assert!(icon_file(&state.icon).unwrap().ends_with(".ico"));
+    }
+
+    #[test]
+    fn animation_runs_until_stopped_or_replaced() {
+        let frames = || vec![Icon::file("frame1.ico"), Icon::file("frame2.ico")];
+        let mut wna = Wna::new().build_mock().unwrap();
+        let first = wna.animate_icon(frames(), Duration::from_millis(100)).unwrap();
+        assert_eq!(mock_state(&wna).animation, Some(1));
+        let second = wna.animate_icon(frames(), Duration::from_millis(100)).unwrap();
+        wna.stop_animation(first).unwrap();
+        assert_eq!(mock_state(&wna).animation, Some(2));
+        wna.stop_animation(second).unwrap();
+        assert_eq!(mock_state(&wna).animation, None);
+        assert!(wna.animate_icon(Vec::new(), Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn removed_ids_are_not_handed_out_again() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()))
+            .menu_item(MenuItem::action("Close".to_string(), |_| -> () { panic!("a removed action ran") }));
+        let mut wna = builder.build_mock().unwrap();
+        wna.remove_menu_item(1).unwrap();
+        wna.add_menu_item(MenuItem::action("Save".to_string(), move |_| { let _ = sender.lock().unwrap().send(()); })).unwrap();
+        let ids: Vec<MenuItemId> = mock_state(&wna).menu.iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![0, 2]);
+        send_event(&wna, Event::Menu(2));
+        receiver.recv_timeout(TIMEOUT).unwrap();
+        assert!(!backend::lock(&wna.repr).actions.contains_key(&1));
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn queued_balloons_keep_their_own_options() {
+        let mut wna = Wna::new().build_mock().unwrap();
+        let mut warning = NotificationOptions::new();
+        warning.level(NotificationLevel::Warning).icon(Icon::file("warning.ico")).persistent(true);
+        wna.show_notification("Disk", "Almost full", &warning, |_| ()).unwrap();
+        wna.show_notification("Backup", "Done", &NotificationOptions::new(), |_| ()).unwrap();
+        send_event(&wna, Event::BalloonDismissed);
+        sync_event_loop(&wna, 100);
+        let state = mock_state(&wna);
+        let titles: Vec<&str> = state.balloons.iter().map(|(title, _)| title.as_str()).collect();
+        assert_eq!(titles, vec!["Disk", "Backup", "Disk"]);
+        let levels: Vec<Option<NotificationLevel>> = state.balloon_options.iter().map(|options| options.level).collect();
+        assert_eq!(levels, vec![Some(NotificationLevel::Warning), None, Some(NotificationLevel::Warning)]);
+        let icons: Vec<Option<String>> = state.balloon_options.iter().map(|options| icon_file(&options.icon)).collect();
+        assert_eq!(icons, vec![Some("warning.ico".to_string()), None, Some("warning.ico".to_string())]);
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn empty_or_left_out_title_gives_a_titleless_balloon() {
+        let mut builder = Wna::new();
+        builder.tip("Tray");
+        let mut wna = builder.build_mock().unwrap();
+        wna.show_balloon("", "Body", |_| ()).unwrap();
+        let mut untitled = NotificationOptions::new();
+        untitled.no_title();
+        wna.show_notification("Ignored", "Body", &untitled, |_| ()).unwrap();
+        let mut tip_title = NotificationOptions::new();
+        tip_title.tip_as_title();
+        wna.show_notification("Ignored", "Body", &tip_title, |_| ()).unwrap();
+        let titles: Vec<String> = mock_state(&wna).balloons.into_iter().map(|(title, _)| title).collect();
+        assert_eq!(titles, vec!["", "", "Tray"]);
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn on_ready_can_show_a_balloon_right_away() {
+        let mut builder = Wna::new();
+        builder.on_ready(|wna| wna.show_balloon("Welcome", "The tray is up", |_| ()).unwrap());
+        let wna = builder.build_mock().unwrap();
+        wait_until(|| !mock_state(&wna).balloons.is_empty());
+        assert_eq!(mock_state(&wna).balloons, vec![("Welcome".to_string(), "The tray is up".to_string())]);
+    }
+
+    #[test]
+    fn separator_lands_right_after_the_named_item() {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()))
+            .menu_item(MenuItem::action("Settings".to_string(), |_| ()))
+            .menu_item(MenuItem::quit());
+        let mut wna = builder.build_mock().unwrap();
+        let settings = wna.find_menu_item("Settings").unwrap();
+        let separator = wna.insert_separator_after(settings).unwrap();
+        let kinds: Vec<(MenuItemId, MenuItemKind)> = mock_state(&wna).menu.into_iter().map(|item| (item.id, item.kind)).collect();
+        assert_eq!(kinds, vec![
+            (0, MenuItemKind::Item),
+            (1, MenuItemKind::Item),
+            (separator, MenuItemKind::Separator),
+            (2, MenuItemKind::Item),
+        ]);
+        match wna.insert_separator_after(42) {
+            Err(Error(ErrorKind::MenuItemNotFound(42), _)) => {}
+            _ => panic!("separator inserted after a missing item"),
+        }
+    }
+
+    #[test]
+    fn custom_event_from_another_thread_reaches_the_handler_on_the_event_loop() {
+        let (sender, receiver) = channel();
+        let mut builder = Wna::new();
+        builder.on_custom(move |_, value| {
+            let _ = sender.send((value, thread::current().name().map(|name| name.to_string())));
+        });
+        let wna = builder.build_mock().unwrap();
+        let clone = wna.clone();
+        thread::spawn(move || clone.post_event(Event::Custom(42)).unwrap()).join().unwrap();
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), (42, Some("wna-event-loop".to_string())));
+        let wna = Wna::new().build_mock_with_events().unwrap();
+        assert_eq!(wna.recv_event(), Some(Event::Ready));
+        wna.post_event(Event::Custom(43)).unwrap();
+        assert_eq!(wna.recv_event(), Some(Event::Custom(43)));
+    }
+
+    #[test]
+    fn dropping_the_wna_frees_the_tray_once_the_event_loop_ends() {
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Open".to_string(), |_| ()))
+            .on_ready(|_| ());
+        let mut wna = builder.build_mock().unwrap();
+        let mock = wna.mock().unwrap();
+        let repr = Arc::downgrade(&wna.repr);
+        let thread = wna.thread.take().unwrap();
+        drop(wna);
+        thread.join().unwrap();
+        assert!(mock.state().closed);
+        assert!(repr.upgrade().is_none());
+    }
+
+    #[test]
+    fn menu_opens_at_the_point_as_given() {
+        for &dpi_aware in &[false, true] {
+            let mut builder = Wna::new();
+            builder.dpi_aware(dpi_aware).menu_item(MenuItem::action("Open".to_string(), |_| ()));
+            let mut wna = builder.build_mock().unwrap();
+            wna.open_menu_at(-120, 2160, MenuAlignment::BottomRight).unwrap();
+            assert_eq!(mock_state(&wna).menu_opened_at, Some((-120, 2160, MenuAlignment::BottomRight)));
+        }
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn clicked_balloon_gets_its_own_context() {
+        let (sender, receiver) = channel();
+        let sender = Arc::new(Mutex::new(sender));
+        let mut wna = Wna::new().build_mock().unwrap();
+        for &(title, id) in &[("A", 1u32), ("B", 2u32)] {
+            let sender = Arc::clone(&sender);
+            wna.show_notification_with_data(title, "Body", &NotificationOptions::new(), id, move |_, context: &BalloonContext| {
+                let _ = sender.lock().unwrap().send((context.title.clone(), context.data::<u32>().cloned(), context.data::<String>().is_some()));
+            }).unwrap();
+        }
+        // A times out unclicked, so B comes up and is clicked
+        send_event(&wna, Event::BalloonDismissed);
+        send_event(&wna, Event::Balloon);
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), ("B".to_string(), Some(2), false));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn balloon_shown_event_runs_the_on_shown_action_of_the_oldest_balloon() {
+        let (sender, receiver) = channel();
+        let sender = Arc::new(Mutex::new(sender));
+        let mut wna = Wna::new().build_mock().unwrap();
+        for title in &["First", "Second"] {
+            let sender = Arc::clone(&sender);
+            let mut options = NotificationOptions::new();
+            options.on_shown(move |_| { let _ = sender.lock().unwrap().send(title.to_string()); });
+            wna.show_notification(title, "Body", &options, |_| ()).unwrap();
+        }
+        send_event(&wna, Event::BalloonShown);
+        assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), "First");
+        assert_eq!(backend::lock(&wna.repr).balloon_actions.len(), 2);
+    }
+
+    #[test]
+    fn insert_modes_put_new_items_where_documented() {
+        for &(mode, expected) in &[
+            (MenuInsertMode::Append, ["Pinned", "First", "Second", "Third"]),
+            (MenuInsertMode::Prepend, ["Third", "Pinned", "First", "Second"]),
+        ] {
+            let mut builder = Wna::new();
+            builder
+                .menu_insert_mode(mode)
+                .menu_item(MenuItem::action("First".to_string(), |_| ()))
+                .menu_item(MenuItem::action("Second".to_string(), |_| ()));
+            let mut wna = builder.build_mock().unwrap();
+            wna.prepend_menu_item(MenuItem::action("Pinned".to_string(), |_| ())).unwrap();
+            wna.add_menu_item(MenuItem::action("Third".to_string(), |_| ())).unwrap();
+            assert_eq!(labels(&wna), expected);
+        }
+    }
+
+    #[test]
+    fn run_returns_once_a_quit_action_fires() {
+        let mut builder = Wna::new();
+        builder.menu_item(MenuItem::quit());
+        let quits = counting_quit(&mut builder);
+        let wna = builder.build_mock().unwrap();
+        let clone = wna.clone();
+        let clicker = thread::spawn(move || send_event(&clone, Event::Menu(0)));
+        wna.run().unwrap();
+        clicker.join().unwrap();
+        assert_eq!(quits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn item_generated_from_a_slice_passes_its_index() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let profiles = ["Home", "Work", "Travel"];
+        let mut builder = Wna::new();
+        builder.menu_from_slice(&profiles, |name| format!("Profile: {}", name), move |_, index| {
+            let _ = sender.lock().unwrap().send(index);
+        });
+        let wna = builder.build_mock().unwrap();
+        assert_eq!(labels(&wna), vec!["Profile: Home", "Profile: Work", "Profile: Travel"]);
+        for &k in &[2, 0, 1] {
+            send_event(&wna, Event::Menu(k));
+            assert_eq!(receiver.recv_timeout(TIMEOUT).unwrap(), k as usize);
+        }
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn balloon_and_tooltip_go_to_the_shell_in_one_call() {
+        let mut builder = Wna::new();
+        builder.tip("Idle");
+        let mut wna = builder.build_mock().unwrap();
+        let before = mock_state(&wna).shell_calls;
+        wna.notify_and_set_tip("Syncing", "Sync", "Sync has started", |_| ()).unwrap();
+        let state = mock_state(&wna);
+        assert_eq!(state.shell_calls, before + 1);
+        assert_eq!(state.tip, Some("Syncing".to_string()));
+        assert_eq!(state.balloons, vec![("Sync".to_string(), "Sync has started".to_string())]);
+        assert_eq!(wna.tip(), Some("Syncing".to_string()));
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn startup_balloon_cancelled_before_ready_is_not_shown() {
+        for &cancel in &[true, false] {
+            let (sender, receiver) = channel::<()>();
+            let receiver = Mutex::new(receiver);
+            let mut builder = Wna::new();
+            builder
+                .startup_balloon("Started", "The tray is up")
+                // holds the event loop at the ready event until the test has decided
+                .on_ready(move |_| { let _ = receiver.lock().unwrap().recv_timeout(TIMEOUT); });
+            let mut wna = builder.build_mock().unwrap();
+            if cancel {
+                wna.cancel_startup_balloon();
             }
+            sender.send(()).unwrap();
+            sync_event_loop(&wna, 100);
+            assert_eq!(mock_state(&wna).balloons.len(), if cancel { 0 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn icon_is_restored_when_the_action_fails() {
+        let mut builder = Wna::new();
+        builder
+            .icon(Icon::file("idle.ico"))
+            .busy_icon(Icon::file("busy.ico"))
+            .menu_item(MenuItem::action("Fail".to_string(), |wna| {
+                if let Err(e) = wna.set_menu_item_enabled(42, false) {
+                    wna.report_error(e);
+                }
+            }));
+        let mut wna = builder.build_mock().unwrap();
+        let errors = wna.take_error_receiver().unwrap();
+        send_event(&wna, Event::Menu(0));
+        match errors.recv_timeout(TIMEOUT).unwrap() {
+            Error(ErrorKind::MenuItemNotFound(42), _) => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        wait_until(|| mock_state(&wna).busy_icon.is_none());
+        assert_eq!(icon_file(&mock_state(&wna).icon), Some("idle.ico".to_string()));
+    }
+
+    #[test]
+    fn reloaded_actions_fire_for_the_existing_items() {
+        let (sender, receiver) = channel();
+        let sender = Arc::new(Mutex::new(sender));
+        let unhandled = Arc::clone(&sender);
+        let mut builder = Wna::new();
+        builder
+            .menu_item(MenuItem::action("Open".to_string(), |_| -> () { panic!("an old action ran") }))
+            .menu_item(MenuItem::action("Save".to_string(), |_| -> () { panic!("an old action ran") }))
+            .menu_item(MenuItem::action("Help".to_string(), |_| -> () { panic!("an old action ran") }))
+            .on_unhandled_menu(move |id| { let _ = unhandled.lock().unwrap().send(format!("unhandled {}", id)); });
+        let mut wna = builder.build_mock().unwrap();
+        let actions: HashMap<MenuItemId, Action> = (0..2).map(|id| {
+            let sender = Arc::clone(&sender);
+            (id, boxed_action(move |_| { let _ = sender.lock().unwrap().send(format!("new {}", id)); }))
+        }).collect();
+        wna.replace_actions(actions).unwrap();
+        for id in 0..3 {
+            send_event(&wna, Event::Menu(id));
         }
-    }).unwrap()
+        let fired: Vec<String> = (0..3).map(|_| receiver.recv_timeout(TIMEOUT).unwrap()).collect();
+        assert_eq!(fired, vec!["new 0", "new 1", "unhandled 2"]);
+        assert_eq!(labels(&wna), vec!["Open", "Save", "Help"]);
+    }
+
 }
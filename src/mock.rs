@@ -0,0 +1,401 @@
+//! An in-memory fake of the notification area, for testing a tray without showing it.
+//! Build the tray with `WnaBuilder::build_mock` and read back what it was asked to show
+//! with `Wna::mock`.
+
+#[cfg(feature = "balloons")]
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{ErrorKind, HMENU, HWND, Result};
+use super::{Icon, MenuAlignment, MenuInsertMode, MenuItemInfo, MenuItemKind, MenuItemState, Op};
+#[cfg(feature = "balloons")]
+use super::{NotificationOptions, SuppressionReason};
+use backend::{MenuEntry, MenuOpeningCallback, ThreadCallback, TrayBackend};
+
+/// What the mock backend was asked to show.
+#[derive(Clone)]
+pub struct MockState {
+    pub icon: Option<Icon>,
+    pub busy_icon: Option<Icon>,
+    /// The id of the running animation, numbered from 1.
+    pub animation: Option<u32>,
+    pub animations_started: u32,
+    pub tip: Option<String>,
+    /// Calls that would each update the icon in the shell: icon, tooltip, balloons and visibility.
+    pub shell_calls: u32,
+    pub menu: Vec<MenuItemInfo>,
+    /// Title and body of every balloon shown, oldest first.
+    #[cfg(feature = "balloons")]
+    pub balloons: Vec<(String, String)>,
+    /// The options each of `balloons` was shown with.
+    #[cfg(feature = "balloons")]
+    pub balloon_options: Vec<NotificationOptions>,
+    /// What the next queries for the notification state answer, `None` once used up.
+    #[cfg(feature = "balloons")]
+    pub suppression: VecDeque<Option<SuppressionReason>>,
+    /// Makes the shell refuse balloons.
+    #[cfg(feature = "balloons")]
+    pub refuse_balloons: bool,
+    /// Makes the menu refuse new items, whether added one by one or with `set_menu`.
+    pub refuse_menu_items: bool,
+    /// Where and how the menu was last opened with `open_menu_at`.
+    pub menu_opened_at: Option<(i32, i32, MenuAlignment)>,
+    pub visible: bool,
+    pub closed: bool,
+}
+
+/// An in-memory stand-in for the Win32 window: nothing is shown, every call
+/// is recorded in `MockState`, and callbacks run on the calling thread.
+pub struct MockWindow {
+    shared: Arc<MockShared>,
+    last_timer_id: u32,
+}
+
+/// The state of a mock backend, shared with the tray built on it.
+pub struct MockShared {
+    state: Mutex<MockState>,
+    menu_opening: Mutex<Option<Arc<MenuOpeningCallback>>>,
+}
+
+impl MockShared {
+
+    /// A snapshot of what the mock was asked to show so far.
+    pub fn state(&self) -> MockState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Sets up what the mock answers, as the user or the shell would change it.
+    pub fn update<F>(&self, f: F) where F: FnOnce(&mut MockState) {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    /// The callback the tray runs before its menu opens; call it before opening the menu
+    /// as the real window does.
+    pub fn menu_opening_callback(&self) -> Option<Arc<MenuOpeningCallback>> {
+        self.menu_opening.lock().unwrap().clone()
+    }
+
+}
+
+impl MockWindow {
+
+    pub fn new() -> MockWindow {
+        MockWindow {
+            shared: Arc::new(MockShared {
+                state: Mutex::new(MockState {
+                    icon: None,
+                    busy_icon: None,
+                    animation: None,
+                    animations_started: 0,
+                    tip: None,
+                    shell_calls: 0,
+                    menu: Vec::new(),
+                    #[cfg(feature = "balloons")]
+                    balloons: Vec::new(),
+                    #[cfg(feature = "balloons")]
+                    balloon_options: Vec::new(),
+                    #[cfg(feature = "balloons")]
+                    suppression: VecDeque::new(),
+                    #[cfg(feature = "balloons")]
+                    refuse_balloons: false,
+                    refuse_menu_items: false,
+                    menu_opened_at: None,
+                    visible: true,
+                    closed: false,
+                }),
+                menu_opening: Mutex::new(None),
+            }),
+            last_timer_id: 0,
+        }
+    }
+
+    pub fn shared(&self) -> Arc<MockShared> {
+        Arc::clone(&self.shared)
+    }
+
+    fn with_open_state<F, R>(&self, f: F) -> Result<R> where F: FnOnce(&mut MockState) -> Result<R> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            bail!(ErrorKind::WindowClosed);
+        }
+        f(&mut state)
+    }
+
+    fn with_menu_item<F>(&self, id: u32, f: F) -> Result<()> where F: FnOnce(&mut MenuItemInfo) {
+        self.with_open_state(|state| {
+            match state.menu.iter_mut().find(|item| item.id == id) {
+                Some(item) => {
+                    f(item);
+                    Ok(())
+                }
+                None => bail!(ErrorKind::MenuItemNotFound(id)),
+            }
+        })
+    }
+
+}
+
+fn menu_item_info(entry: MenuEntry) -> MenuItemInfo {
+    let (id, label, kind, checked) = match entry {
+        MenuEntry::Item(id, label) => (id, label, MenuItemKind::Item, false),
+        MenuEntry::TriState(id, label, state) => (id, label, MenuItemKind::Item, state != MenuItemState::Unchecked),
+        MenuEntry::OwnerDrawn(id, _, _, _) => (id, String::new(), MenuItemKind::OwnerDrawn, false),
+        MenuEntry::Separator(id) => (id, String::new(), MenuItemKind::Separator, false),
+    };
+    MenuItemInfo {
+        id,
+        label,
+        kind,
+        enabled: true,
+        checked,
+    }
+}
+
+impl Default for MockWindow {
+
+    fn default() -> MockWindow {
+        MockWindow::new()
+    }
+
+}
+
+impl TrayBackend for MockWindow {
+
+    fn thread_id(&self) -> Option<u32> {
+        None
+    }
+
+    fn hmenu(&self) -> Option<HMENU> {
+        None
+    }
+
+    fn is_window_thread(&self) -> bool {
+        true
+    }
+
+    fn is_attached(&self) -> bool {
+        false
+    }
+
+    fn run_on_thread(&self, f: ThreadCallback) -> Result<()> {
+        self.with_open_state(|_| Ok(()))?;
+        f();
+        Ok(())
+    }
+
+    fn set_icon(&self, icon: &Icon) -> Result<()> {
+        self.with_open_state(|state| {
+            state.icon = Some(icon.clone());
+            state.shell_calls += 1;
+            Ok(())
+        })
+    }
+
+    fn set_tip(&self, tip: &str) -> Result<()> {
+        self.with_open_state(|state| {
+            state.tip = Some(tip.to_string());
+            state.shell_calls += 1;
+            Ok(())
+        })
+    }
+
+    fn clear_icon_cache(&self) -> Result<()> {
+        self.with_open_state(|_| Ok(()))
+    }
+
+    fn start_animation(&self, frames: Vec<Icon>, _interval: Duration) -> Result<u32> {
+        self.with_open_state(|state| {
+            if frames.is_empty() {
+                bail!("No animation frames");
+            }
+            state.animations_started += 1;
+            state.animation = Some(state.animations_started);
+            Ok(state.animations_started)
+        })
+    }
+
+    fn stop_animation(&self, id: u32) -> Result<()> {
+        self.with_open_state(|state| {
+            if state.animation == Some(id) {
+                state.animation = None;
+            }
+            Ok(())
+        })
+    }
+
+    fn set_busy_icon(&self, icon: Option<&Icon>) -> Result<()> {
+        self.with_open_state(|state| {
+            state.busy_icon = icon.cloned();
+            Ok(())
+        })
+    }
+
+    fn set_timer(&mut self, _interval: Duration) -> Result<u32> {
+        self.with_open_state(|_| Ok(()))?;
+        self.last_timer_id += 1;
+        Ok(self.last_timer_id)
+    }
+
+    fn kill_timer(&self, _id: u32) -> Result<()> {
+        self.with_open_state(|_| Ok(()))
+    }
+
+    fn add_menu_entry(&mut self, entry: MenuEntry, mode: MenuInsertMode) -> Result<()> {
+        self.with_open_state(|state| {
+            if state.refuse_menu_items {
+                bail!(ErrorKind::Win32(Op::AddMenuItem, 0));
+            }
+            match mode {
+                MenuInsertMode::Append => state.menu.push(menu_item_info(entry)),
+                MenuInsertMode::Prepend => state.menu.insert(0, menu_item_info(entry)),
+            }
+            Ok(())
+        })
+    }
+
+    fn insert_separator_after(&mut self, after: u32, id: u32) -> Result<()> {
+        self.with_open_state(|state| {
+            match state.menu.iter().position(|item| item.id == after) {
+                Some(index) => {
+                    state.menu.insert(index + 1, menu_item_info(MenuEntry::Separator(id)));
+                    Ok(())
+                }
+                None => bail!(ErrorKind::MenuItemNotFound(after)),
+            }
+        })
+    }
+
+    fn set_menu(&mut self, entries: Vec<MenuEntry>) -> Result<()> {
+        self.with_open_state(|state| {
+            if state.refuse_menu_items {
+                bail!(ErrorKind::Win32(Op::AddMenuItem, 0));
+            }
+            state.menu = entries.into_iter().map(menu_item_info).collect();
+            Ok(())
+        })
+    }
+
+    fn set_menu_item_state(&mut self, id: u32, state: MenuItemState) -> Result<()> {
+        self.with_menu_item(id, |item| item.checked = state != MenuItemState::Unchecked)
+    }
+
+    fn set_menu_item_enabled(&self, id: u32, enabled: bool) -> Result<()> {
+        self.with_menu_item(id, |item| item.enabled = enabled)
+    }
+
+    fn set_menu_item_help(&self, _id: u32, _help: Option<String>) {
+    }
+
+    fn set_menu_item_check_bitmaps(&self, id: u32, _checked: Option<Icon>, _unchecked: Option<Icon>) -> Result<()> {
+        self.with_menu_item(id, |_| ())
+    }
+
+    fn menu_item_ids(&self) -> Result<Vec<u32>> {
+        self.with_open_state(|state| Ok(state.menu.iter().map(|item| item.id).collect()))
+    }
+
+    fn menu_snapshot(&self) -> Result<Vec<MenuItemInfo>> {
+        self.with_open_state(|state| Ok(state.menu.clone()))
+    }
+
+    fn find_menu_item(&self, label: &str) -> Result<Option<u32>> {
+        self.with_open_state(|state| Ok(state.menu.iter().find(|item| item.label == label).map(|item| item.id)))
+    }
+
+    fn remove_menu_item(&self, id: u32) -> Result<()> {
+        self.with_open_state(|state| {
+            match state.menu.iter().position(|item| item.id == id) {
+                Some(index) => {
+                    state.menu.remove(index);
+                    Ok(())
+                }
+                None => bail!(ErrorKind::MenuItemNotFound(id)),
+            }
+        })
+    }
+
+    #[cfg(feature = "balloons")]
+    fn show_balloon(&self, title: &str, body: &str, options: &NotificationOptions) -> Result<()> {
+        self.with_open_state(|state| {
+            if state.refuse_balloons {
+                bail!(ErrorKind::Win32(Op::ShowBalloon, 0));
+            }
+            state.balloons.push((title.to_string(), body.to_string()));
+            state.balloon_options.push(options.clone());
+            state.shell_calls += 1;
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "balloons")]
+    fn show_balloon_with_tip(&self, tip: &str, title: &str, body: &str, options: &NotificationOptions) -> Result<()> {
+        self.with_open_state(|state| {
+            state.tip = Some(tip.to_string());
+            state.balloons.push((title.to_string(), body.to_string()));
+            state.balloon_options.push(options.clone());
+            state.shell_calls += 1;
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "balloons")]
+    fn notification_suppression(&self) -> Result<Option<SuppressionReason>> {
+        self.with_open_state(|state| Ok(state.suppression.pop_front().and_then(|reason| reason)))
+    }
+
+    fn set_visible(&mut self, visible: bool) -> Result<bool> {
+        self.with_open_state(|state| {
+            state.visible = visible;
+            state.shell_calls += 1;
+            Ok(false)
+        })
+    }
+
+    fn is_icon_visible(&self) -> Result<bool> {
+        self.with_open_state(|state| Ok(state.visible))
+    }
+
+    fn set_menu_opening_callback(&self, callback: Option<MenuOpeningCallback>) {
+        *self.shared.menu_opening.lock().unwrap() = callback.map(Arc::new);
+    }
+
+    fn set_attention_window(&mut self, _hwnd: Option<HWND>) {
+    }
+
+    fn set_main_window(&self, _hwnd: Option<HWND>, _hide_on_focus_loss: bool) -> Result<()> {
+        self.with_open_state(|_| Ok(()))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.with_open_state(|_| Ok(()))
+    }
+
+    fn icon_dpi(&self) -> Result<u32> {
+        self.with_open_state(|_| Ok(96))
+    }
+
+    fn open_menu_at(&self, x: i32, y: i32, align: MenuAlignment) -> Result<()> {
+        self.with_open_state(|state| {
+            state.menu_opened_at = Some((x, y, align));
+            Ok(())
+        })
+    }
+
+    fn request_attention(&self) -> Result<()> {
+        self.with_open_state(|_| Ok(()))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    fn close(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+    }
+
+    fn mock(&self) -> Option<Arc<MockShared>> {
+        Some(self.shared())
+    }
+
+}
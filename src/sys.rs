@@ -0,0 +1,23 @@
+// Stand-ins for the Win32 types of the public API, so that the crate builds, and its tests
+// run, on other platforms, where winapi is empty. There is no tray to show there.
+#![allow(clippy::upper_case_acronyms)]
+
+pub enum HDC__ {}
+pub type HDC = *mut HDC__;
+
+pub enum HICON__ {}
+pub type HICON = *mut HICON__;
+
+pub enum HMENU__ {}
+pub type HMENU = *mut HMENU__;
+
+pub enum HWND__ {}
+pub type HWND = *mut HWND__;
+
+#[derive(Clone, Copy)]
+pub struct RECT {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
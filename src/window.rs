@@ -1,435 +1,3514 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::os::windows::prelude::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::ptr;
-use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use winapi::shared::basetsd::{LONG_PTR, UINT_PTR};
 use winapi::shared::minwindef::*;
 use winapi::shared::windef::*;
+use winapi::shared::windowsx::{GET_X_LPARAM, GET_Y_LPARAM};
+use winapi::um::commctrl::*;
 use winapi::um::errhandlingapi::GetLastError;
-use winapi::um::libloaderapi::GetModuleHandleW;
+use winapi::shared::winerror::{ERROR_ACCESS_DENIED, HRESULT, S_OK};
+use winapi::um::libloaderapi::{FreeLibrary, GetModuleFileNameW, GetModuleHandleW, GetProcAddress, LoadLibraryW};
+#[cfg(feature = "balloons")]
+use winapi::um::playsoundapi::*;
+use winapi::um::processthreadsapi::{GetCurrentProcessId, GetCurrentThreadId, ProcessIdToSessionId, SetThreadPriority};
 use winapi::um::shellapi::*;
+use winapi::um::winbase::{FormatMessageW, FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS};
+use winapi::um::winbase::{THREAD_PRIORITY_ABOVE_NORMAL, THREAD_PRIORITY_BELOW_NORMAL, THREAD_PRIORITY_HIGHEST};
+use winapi::um::winbase::{THREAD_PRIORITY_IDLE, THREAD_PRIORITY_LOWEST, THREAD_PRIORITY_NORMAL};
+use winapi::um::wingdi::*;
+use winapi::um::winnt::{HANDLE, LONG};
 use winapi::um::winuser::*;
 
-use super::{ErrorKind, Result};
-use super::{Event, Icon};
+use super::{Error, ErrorKind, Op, PanicSlot, Result, MIN_IDLE_THROTTLE};
+use super::panic_message;
+use super::{Event, Icon, OwnedIconHandle, MenuAlignment, MenuInsertMode, MenuItemInfo, MenuItemKind, MenuItemState, ThreadPriority};
+#[cfg(feature = "balloons")]
+use super::{NotificationLevel, NotificationOptions, SuppressionReason};
+use super::{DrawAction, DrawContext, MeasureAction, MeasureContext};
+use backend::{MenuEntry, MenuOpeningCallback, ThreadCallback, TrayBackend};
 
 const TASKBAR_ICON_ID: UINT = 1;
 const NOTIFICATION_MESSAGE_ID: UINT = WM_USER + 1;
+const RUN_ON_THREAD_MESSAGE_ID: UINT = WM_USER + 2;
+// marks the submenus that hold the items past `WindowConfig::menu_max_items`
+const OVERFLOW_MENU_DATA: usize = 0x776e_6121;
+const SMALL_ICON_SIZE_AT_96_DPI: i32 = 16;
+// DI_IMAGE | DI_MASK, missing from winapi
+const DI_NORMAL: UINT = 0x0003;
+// timer ids start high, so as not to clash with the timers of a subclassed window
+const DEBOUNCE_TIMER_ID: UINT_PTR = 0x776E_6100;
+const ANIMATION_TIMER_ID: UINT_PTR = DEBOUNCE_TIMER_ID + 1;
+const HOVER_TIMER_ID: UINT_PTR = ANIMATION_TIMER_ID + 1;
 
-thread_local!(static WINDOW_LOOP_DATA: RefCell<Option<WindowLoopData>> = RefCell::new(None));
+// how long the window thread waits for a contended lock before trying it again
+const LOCK_RETRY_MS: DWORD = 10;
 
+type WindowProc = unsafe extern "system" fn(HWND, UINT, WPARAM, LPARAM) -> LRESULT;
+
+thread_local!(static WINDOW_LOOP_DATA: RefCell<Option<WindowLoopData>> = const { RefCell::new(None) });
+
+// The handles are passed around as identifiers. Messages are posted to the window from
+// any thread, and Shell_NotifyIconW takes the window handle from any thread as well,
+// but the window is only destroyed and its procedure only runs on its own thread.
 #[derive(Clone)]
 struct WindowHandle {
     pub hwnd: HWND,
     pub hmenu: HMENU,
+    pub thread_id: DWORD,
+    pub notification_message: UINT,
+    pub run_message: UINT,
 }
 
 unsafe impl Send for WindowHandle { }
-unsafe impl Sync for WindowHandle { }
+
+struct OwnerDrawnItem {
+    measure: Arc<MeasureAction>,
+    draw: Arc<DrawAction>,
+}
+
+struct InstalledIcon {
+    icon: Icon,
+    hicon: HICON,
+    // the handle belongs to the icon cache
+    cached: bool,
+}
+
+unsafe impl Send for InstalledIcon { }
+
+type CurrentIcon = Mutex<Option<InstalledIcon>>;
+
+const ICON_CACHE_CAPACITY: usize = 16;
+
+#[derive(PartialEq, Eq)]
+enum IconKey {
+    File(String, i32),
+    ResourceByName(String, i32),
+    ResourceByOrd(u16, i32),
+    SelfExe(i32),
+}
+
+// Icons loaded from files and resources, so that switching between a few of them
+// does not load them again. The handles are destroyed when evicted or on close.
+struct IconCache {
+    // least recently used first
+    entries: Vec<(IconKey, HICON)>,
+}
+
+unsafe impl Send for IconCache { }
+
+impl IconCache {
+
+    // application-provided handles are not cached
+    fn key(icon: &Icon, size: i32) -> Option<IconKey> {
+        match *icon {
+            Icon::File(ref file_name) => Some(IconKey::File(file_name.clone(), size)),
+            Icon::ResourceByName(ref name) => Some(IconKey::ResourceByName(name.clone(), size)),
+            Icon::ResourceByOrd(ord) => Some(IconKey::ResourceByOrd(ord, size)),
+            Icon::SelfExe => Some(IconKey::SelfExe(size)),
+            Icon::Hicon(_) | Icon::OwnedHicon(_) => None,
+        }
+    }
+
+    // `shown` is never evicted, as it is in the notification area
+    unsafe fn get_or_load(&mut self, key: IconKey, icon: &Icon, size: i32, shown: Option<HICON>) -> Result<HICON> {
+        if let Some(index) = self.entries.iter().position(|entry| entry.0 == key) {
+            let entry = self.entries.remove(index);
+            let hicon = entry.1;
+            self.entries.push(entry);
+            return Ok(hicon);
+        }
+        let hicon = load_icon(icon, size)?;
+        self.entries.push((key, hicon));
+        if self.entries.len() > ICON_CACHE_CAPACITY {
+            if let Some(index) = self.entries.iter().position(|entry| Some(entry.1) != shown) {
+                DestroyIcon(self.entries.remove(index).1);
+            }
+        }
+        Ok(hicon)
+    }
+
+    // Destroys all the handles but `shown`, which is handed over to its `InstalledIcon`.
+    unsafe fn clear(&mut self, shown: Option<&mut InstalledIcon>) {
+        let shown = shown.map(|installed| {
+            installed.cached = false;
+            installed.hicon
+        });
+        for (_, hicon) in self.entries.drain(..) {
+            if Some(hicon) != shown {
+                DestroyIcon(hicon);
+            }
+        }
+    }
+
+}
+
+struct PendingUpdates {
+    interval: Option<Duration>,
+    last_update: Option<Instant>,
+    timer_set: bool,
+    tip: Option<String>,
+    icon: Option<Icon>,
+}
+
+struct Animation {
+    id: u32,
+    frames: Vec<InstalledIcon>,
+    current: usize,
+}
+
+// state shared by `Window` and the window thread
+struct SharedState {
+    owner_drawn_items: Mutex<HashMap<u32, OwnerDrawnItem>>,
+    icon: CurrentIcon,
+    icon_cache: Mutex<IconCache>,
+    pending_updates: Mutex<PendingUpdates>,
+    last_timer_id: AtomicUsize,
+    indeterminate_bitmap: Mutex<Option<Bitmap>>,
+    icon_deleted: AtomicBool,
+    icon_hidden: AtomicBool,
+    // the tooltip last passed to the shell, to restore it if the icon is re-added
+    tip: Mutex<Option<String>>,
+    animation: Mutex<Option<Animation>>,
+    last_animation_id: AtomicUsize,
+    // shown over both the static icon and any animation while set
+    busy_icon: Mutex<Option<InstalledIcon>>,
+    menu_opening: Mutex<Option<Arc<MenuOpeningCallback>>>,
+    // shown on a left click in place of the menu
+    main_window: Mutex<Option<MainWindow>>,
+    help_texts: Mutex<HashMap<u32, String>>,
+    check_bitmaps: Mutex<HashMap<u32, CheckBitmaps>>,
+}
+
+impl SharedState {
+
+    fn new(update_debounce: Option<Duration>) -> SharedState {
+        SharedState {
+            owner_drawn_items: Mutex::new(HashMap::new()),
+            icon: Mutex::new(None),
+            icon_cache: Mutex::new(IconCache {
+                entries: Vec::new(),
+            }),
+            pending_updates: Mutex::new(PendingUpdates {
+                interval: update_debounce,
+                last_update: None,
+                timer_set: false,
+                tip: None,
+                icon: None,
+            }),
+            last_timer_id: AtomicUsize::new(HOVER_TIMER_ID),
+            indeterminate_bitmap: Mutex::new(None),
+            icon_deleted: AtomicBool::new(false),
+            icon_hidden: AtomicBool::new(false),
+            tip: Mutex::new(None),
+            animation: Mutex::new(None),
+            last_animation_id: AtomicUsize::new(0),
+            busy_icon: Mutex::new(None),
+            menu_opening: Mutex::new(None),
+            main_window: Mutex::new(None),
+            help_texts: Mutex::new(HashMap::new()),
+            check_bitmaps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn indeterminate_bitmap(&self) -> Result<HBITMAP> {
+        let mut bitmap = self.indeterminate_bitmap.lock().unwrap();
+        if bitmap.is_none() {
+            *bitmap = Some(Bitmap(unsafe { create_indeterminate_bitmap()? }));
+        }
+        Ok(bitmap.as_ref().unwrap().0)
+    }
+
+    // null for the default checkmark and blank
+    fn check_bitmaps(&self, id: u32) -> (HBITMAP, HBITMAP) {
+        let bitmaps = self.check_bitmaps.lock().unwrap();
+        match bitmaps.get(&id) {
+            Some(bitmaps) => (
+                bitmaps.checked.as_ref().map(|bitmap| bitmap.0).unwrap_or(ptr::null_mut()),
+                bitmaps.unchecked.as_ref().map(|bitmap| bitmap.0).unwrap_or(ptr::null_mut()),
+            ),
+            None => (ptr::null_mut(), ptr::null_mut()),
+        }
+    }
+
+}
 
 struct WindowLoopData {
     pub handle: WindowHandle,
+    pub config: WindowConfig,
+    pub shared: Arc<SharedState>,
     pub event_sender: Sender<Event>,
+    pub error_sender: Sender<Error>,
+    pub menu_open: bool,
+    pub stale_menus: Vec<HMENU>,
+    // where the cursor was when NIN_POPUPOPEN came
+    pub hover_point: Option<POINT>,
+    // created the first time a menu item with a help text is selected
+    pub help_tooltip: Option<HWND>,
+    // set for a window of the application that `wna` has subclassed
+    pub original_proc: Option<LONG_PTR>,
+    pub last_idle: Option<Instant>,
+    // installed when a main window that hides on focus loss is first bound
+    pub focus_hook: Option<HWINEVENTHOOK>,
+}
+
+#[derive(Clone)]
+pub struct WindowConfig {
+    pub show_tip: bool,
+    pub right_to_left: bool,
+    pub ex_style: DWORD,
+    pub message_only: bool,
+    pub class_icon: Option<Icon>,
+    pub restore_focus: bool,
+    pub update_debounce: Option<Duration>,
+    pub creation_timeout: Duration,
+    pub thread_name: String,
+    pub thread_priority: Option<ThreadPriority>,
+    // open the menu once the cursor rests on the icon this long
+    pub menu_hover_delay: Option<Duration>,
+    pub last_panic: PanicSlot,
+    // past this many items, the rest go into a submenu labeled `more_items_label`
+    pub menu_max_items: Option<usize>,
+    pub more_items_label: String,
+    // clicks with a handler are sent as events instead of showing the menu
+    pub left_click_handled: bool,
+    pub right_click_handled: bool,
+    // send `Event::MenuOverflow` when the menu opens with overflowing items
+    #[cfg(feature = "balloons")]
+    pub overflow_balloon: bool,
+    // `Event::Idle` is sent at most this often, and not at all without it
+    pub idle_throttle: Option<Duration>,
 }
 
 pub struct Window {
     handle: Option<WindowHandle>,
     thread: Option<thread::JoinHandle<()>>,
+    config: WindowConfig,
+    shared: Arc<SharedState>,
+    attention_window: Option<WindowRef>,
+}
+
+// a window handle is only an identifier, so it may be passed between threads
+#[derive(Clone, Copy)]
+struct WindowRef(HWND);
+
+unsafe impl Send for WindowRef { }
+
+#[derive(Clone, Copy)]
+struct MainWindow {
+    window: WindowRef,
+    hide_on_focus_loss: bool,
+}
+
+// menu handles are not tied to a thread either
+struct MenuRef(HMENU);
+
+unsafe impl Send for MenuRef { }
+
+struct Bitmap(HBITMAP);
+
+unsafe impl Send for Bitmap { }
+
+impl Drop for Bitmap {
+    fn drop(&mut self) {
+        unsafe { DeleteObject(self.0 as HGDIOBJ); }
+    }
+}
+
+impl Drop for OwnedIconHandle {
+    fn drop(&mut self) {
+        unsafe { DestroyIcon(self.hicon()); }
+    }
+}
+
+struct CheckBitmaps {
+    checked: Option<Bitmap>,
+    unchecked: Option<Bitmap>,
 }
 
 impl Window {
 
-    pub fn create(window_class_name: &str, event_sender: Sender<Event>) -> Result<Window> {
+    pub fn create(window_class_name: &str, config: WindowConfig, event_sender: Sender<Event>, error_sender: Sender<Error>) -> Result<Window> {
+        unsafe { check_interactive_session()?; }
         let window_class_name = str_to_wchar_str(window_class_name);
         let (sender, receiver) = channel();
-        let thread = thread::Builder::new().name("wna-window-loop".into()).spawn(move || {
-            unsafe {
-                match init_window(&window_class_name) {
-                    Ok(w) => {
-                        let _ = sender.send(Ok(w.clone()));
-                        drop(sender);
-                        WINDOW_LOOP_DATA.with(|data| {
-                            (*data.borrow_mut()) = Some(WindowLoopData {
-                                handle: w,
-                                event_sender: event_sender,
+        let thread_config = config.clone();
+        let shared = Arc::new(SharedState::new(config.update_debounce));
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new().name(config.thread_name.clone()).spawn(move || {
+            let last_panic = Arc::clone(&thread_config.last_panic);
+            let result = panic::catch_unwind(AssertUnwindSafe(move || {
+                unsafe {
+                    match init_window(&window_class_name, &thread_config) {
+                        Ok(w) => {
+                            if sender.send(Ok(w.clone())).is_err() {
+                                // `create` has timed out and nobody is going to use the window
+                                let _ = delete_notification_area_icon(w.hwnd);
+                                DestroyMenu(w.hmenu);
+                                DestroyWindow(w.hwnd);
+                                return;
+                            }
+                            drop(sender);
+                            WINDOW_LOOP_DATA.with(|data| {
+                                (*data.borrow_mut()) = Some(WindowLoopData {
+                                    handle: w,
+                                    config: thread_config,
+                                    shared: thread_shared,
+                                    event_sender,
+                                    error_sender,
+                                    menu_open: false,
+                                    stale_menus: Vec::new(),
+                                    hover_point: None,
+                                    help_tooltip: None,
+                                    original_proc: None,
+                                    last_idle: None,
+                                    focus_hook: None,
+                                });
                             });
-                        });
-                        window_message_loop();
-                    }
-                    Err(e) => {
-                        let _ = sender.send(Err(e));
+                            window_message_loop();
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(e));
+                        }
                     }
                 }
+            }));
+            if let Err(payload) = result {
+                *last_panic.lock().unwrap() = Some(panic_message(&*payload));
             }
-        }).map_err(|e| ErrorKind::Msg(format!("Error starting window loop: {}", e.to_string())))?;
-        let handle = receiver.recv().map_err(|e| ErrorKind::Msg(format!("Error receiving window handle: {}", e)))??;
+        }).map_err(|e| ErrorKind::Msg(format!("Error starting window loop: {}", e)))?;
+        // a wedged shell may block the thread in Shell_NotifyIconW; it is then left behind
+        let handle = match receiver.recv_timeout(config.creation_timeout) {
+            Ok(handle) => handle?,
+            Err(RecvTimeoutError::Timeout) => bail!(ErrorKind::WindowCreationTimeout),
+            Err(e) => bail!(ErrorKind::Msg(format!("Error receiving window handle: {}", e))),
+        };
+        if let Some(priority) = config.thread_priority {
+            if let Err(e) = set_thread_priority(&thread, priority) {
+                unsafe { PostMessageW(handle.hwnd, WM_DESTROY, 0, 0); }
+                let _ = thread.join();
+                bail!(e);
+            }
+        }
         Ok(Window {
             handle: Some(handle),
             thread: Some(thread),
+            config,
+            shared,
+            attention_window: None,
         })
     }
 
-    pub fn set_icon(&self, icon: &Icon) -> Result<()> {
-        if let Some(ref handle) = self.handle {
-            unsafe {
-                let hicon = match icon {
-                    Icon::File(ref file_name) => load_icon_from_file(file_name),
-                    Icon::ResourceByName(ref name) => load_icon_from_resource_by_name(name),
-                    Icon::ResourceByOrd(ord) => load_icon_from_resource_by_ord(*ord),
-                }?;
-                set_icon(handle.hwnd, hicon)
+    // Subclasses a window of the application instead of creating one, so that no
+    // thread is spawned and the application's own message loop serves the icon.
+    // Must be called on the thread that owns the window.
+    pub fn attach(hwnd: HWND, config: WindowConfig, event_sender: Sender<Event>, error_sender: Sender<Error>) -> Result<Window> {
+        unsafe {
+            if IsWindow(hwnd) == 0 {
+                bail!("Invalid window handle");
             }
-        } else {
-            bail!("Window is closed")
+            if GetWindowThreadProcessId(hwnd, ptr::null_mut()) != GetCurrentThreadId() {
+                bail!("An existing window must be attached on the thread that owns it");
+            }
+            if WINDOW_LOOP_DATA.with(|data| data.borrow().is_some()) {
+                bail!("Another tray is already attached on this thread");
+            }
+            // plain WM_USER messages may already be taken by the application
+            let notification_message = RegisterWindowMessageW(str_to_wchar_str("wna_notification").as_ptr());
+            let run_message = RegisterWindowMessageW(str_to_wchar_str("wna_run_on_thread").as_ptr());
+            if notification_message == 0 || run_message == 0 {
+                bail!(last_error(Op::RegisterWindowMessage));
+            }
+            let hmenu = create_popup_menu()?;
+            if let Err(e) = create_notification_area_icon(hwnd, notification_message, config.show_tip) {
+                DestroyMenu(hmenu);
+                bail!(e);
+            }
+            let handle = WindowHandle {
+                hwnd,
+                hmenu,
+                thread_id: GetCurrentThreadId(),
+                notification_message,
+                run_message,
+            };
+            let shared = Arc::new(SharedState::new(config.update_debounce));
+            WINDOW_LOOP_DATA.with(|data| {
+                (*data.borrow_mut()) = Some(WindowLoopData {
+                    handle: handle.clone(),
+                    config: config.clone(),
+                    shared: Arc::clone(&shared),
+                    event_sender,
+                    error_sender,
+                    menu_open: false,
+                    stale_menus: Vec::new(),
+                    hover_point: None,
+                    help_tooltip: None,
+                    original_proc: None,
+                    last_idle: None,
+                    focus_hook: None,
+                });
+            });
+            let original_proc = SetWindowLongPtrW(hwnd, GWLP_WNDPROC, subclass_proc as WindowProc as usize as LONG_PTR);
+            if original_proc == 0 {
+                let e = last_error(Op::SubclassWindow);
+                let _ = delete_notification_area_icon(hwnd);
+                destroy_menus();
+                WINDOW_LOOP_DATA.with(|data| *data.borrow_mut() = None);
+                bail!(e);
+            }
+            WINDOW_LOOP_DATA.with(|data| {
+                if let Some(ref mut data) = data.borrow_mut().as_mut() {
+                    data.original_proc = Some(original_proc);
+                }
+            });
+            Ok(Window {
+                handle: Some(handle),
+                thread: None,
+                config,
+                shared,
+                attention_window: None,
+            })
         }
     }
 
-    pub fn set_tip(&self, tip: &str) -> Result<()> {
-        if let Some(ref handle) = self.handle {
-            unsafe {
-                set_tip(handle.hwnd, tip)
-            }
-        } else {
-            bail!("Window is closed")
+    // Runs `f` on the window thread and waits for its result; runs it inline
+    // if called on the window thread already.
+    fn call_on_thread<F, R>(&self, f: F) -> Result<R>
+            where F: FnOnce() -> Result<R> + Send + 'static, R: Send + 'static {
+        if self.is_window_thread() {
+            return f();
+        }
+        let (sender, receiver) = channel();
+        self.run_on_thread(Box::new(move || {
+            let _ = sender.send(f());
+        }))?;
+        match receiver.recv() {
+            Ok(result) => result,
+            // the thread has dropped the callback without running it
+            Err(_) => bail!(ErrorKind::WindowClosed),
         }
     }
 
-    pub fn add_menu_item(&self, id: u32, title: &str) -> Result<()> {
-        if let Some(ref handle) = self.handle {
-            unsafe {
-                add_menu_item(handle.hmenu, id, title)
-            }
-        } else {
-            bail!("Window is closed")
+    // Returns `true` if the update is postponed: updates that come sooner than the debounce
+    // interval after the previous one are coalesced and applied by a timer on the window thread.
+    fn defer_update<F>(&self, handle: &WindowHandle, store: F) -> Result<bool>
+            where F: FnOnce(&mut PendingUpdates) {
+        let mut pending = self.shared.pending_updates.lock().unwrap();
+        let interval = match pending.interval {
+            Some(interval) if interval > Duration::from_millis(0) => interval,
+            _ => return Ok(false),
+        };
+        let elapsed = pending.last_update.map(|t| t.elapsed()).unwrap_or(interval);
+        if !pending.timer_set && elapsed >= interval {
+            pending.last_update = Some(Instant::now());
+            return Ok(false);
+        }
+        store(&mut pending);
+        if !pending.timer_set {
+            let delay = interval - elapsed;
+            let delay = ::std::cmp::max(1, duration_to_millis(delay));
+            let handle = handle.clone();
+            self.run_on_thread(Box::new(move || {
+                unsafe { SetTimer(handle.hwnd, DEBOUNCE_TIMER_ID, delay, None); }
+            }))?;
+            pending.timer_set = true;
         }
+        Ok(true)
     }
 
-    pub fn add_menu_separator(&self, id: u32) -> Result<()> {
-        if let Some(ref handle) = self.handle {
-            unsafe {
-                add_menu_separator(handle.hmenu, id)
-            }
-        } else {
-            bail!("Window is closed")
-        }
+}
+
+impl TrayBackend for Window {
+
+    fn thread_id(&self) -> Option<u32> {
+        self.handle.as_ref().map(|h| h.thread_id)
+    }
+
+    fn hmenu(&self) -> Option<HMENU> {
+        self.handle.as_ref().map(|h| h.hmenu)
+    }
+
+    fn is_window_thread(&self) -> bool {
+        self.thread_id() == Some(unsafe { GetCurrentThreadId() })
+    }
+
+    fn is_attached(&self) -> bool {
+        self.handle.is_some() && self.thread.is_none()
     }
 
-    pub fn show_balloon(&self, title: &str, body: &str) -> Result<()> {
+    fn run_on_thread(&self, f: ThreadCallback) -> Result<()> {
         if let Some(ref handle) = self.handle {
+            let f = Box::into_raw(Box::new(f));
             unsafe {
-                show_balloon(handle.hwnd, title, body)
+                if PostMessageW(handle.hwnd, handle.run_message, 0, f as LPARAM) == 0 {
+                    drop(Box::from_raw(f));
+                    bail!(last_error(Op::PostToWindowThread));
+                }
             }
+            Ok(())
         } else {
-            bail!("Window is closed")
+            bail!(ErrorKind::WindowClosed)
         }
     }
 
-    pub fn close(&mut self) {
-        if let Some(ref h) = self.handle {
-            unsafe { PostMessageW(h.hwnd, WM_DESTROY, 0, 0); }
+    fn set_icon(&self, icon: &Icon) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        if self.defer_update(&handle, |pending| pending.icon = Some(icon.clone()))? {
+            return Ok(());
         }
-        self.handle = None;
-        if let Some(thread) = self.thread.take() {
-            let _ = thread.join();
+        let shared = Arc::clone(&self.shared);
+        let icon = icon.clone();
+        self.call_on_thread(move || unsafe {
+            install_icon(handle.hwnd, &shared, &icon, small_icon_size())
+        })
+    }
+
+    fn set_tip(&self, tip: &str) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        if self.defer_update(&handle, |pending| pending.tip = Some(tip.to_string()))? {
+            return Ok(());
         }
+        let tip = tip.to_string();
+        let show_tip = self.config.show_tip;
+        let shared = Arc::clone(&self.shared);
+        self.call_on_thread(move || unsafe {
+            set_tip(handle.hwnd, &tip, show_tip)?;
+            *shared.tip.lock().unwrap() = Some(tip);
+            Ok(())
+        })
     }
 
-}
+    fn clear_icon_cache(&self) -> Result<()> {
+        let shared = Arc::clone(&self.shared);
+        self.call_on_thread(move || unsafe {
+            let mut current = shared.icon.lock().unwrap();
+            let shown = current.as_mut().filter(|installed| installed.cached);
+            shared.icon_cache.lock().unwrap().clear(shown);
+            Ok(())
+        })
+    }
 
-unsafe extern "system" fn window_proc(hwnd: HWND, msg: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
-    match msg {
-        NOTIFICATION_MESSAGE_ID => {
-            match lparam as UINT {
-                WM_LBUTTONUP | WM_RBUTTONUP => {
-                    let mut p: POINT = POINT { x: 0, y: 0 };
-                    if GetCursorPos(&mut p) == 0 {
-                        return 0;
-                    }
-                    SetForegroundWindow(hwnd);
-                    WINDOW_LOOP_DATA.with(|data| {
-                        if let Some(ref data) = data.borrow().as_ref() {
-                            TrackPopupMenu(
-                                data.handle.hmenu,
-                                0,
-                                p.x,
-                                p.y,
-                                0,
-                                hwnd,
-                                ptr::null());
-                        }
-                    });
-                }
-                NIN_BALLOONUSERCLICK => {
-                    WINDOW_LOOP_DATA.with(|data| {
-                        if let Some(ref data) = data.borrow().as_ref() {
-                            if data.event_sender.send(Event::Balloon).is_err() {
-                                // event loop is terminated; close the window
-                                PostMessageW(hwnd, WM_DESTROY, 0, 0);
-                            }
+    // Cycles the icon through `frames`, replacing any running animation. The frames are
+    // loaded up front and destroyed once the animation stops. Returns the animation id.
+    fn start_animation(&self, frames: Vec<Icon>, interval: Duration) -> Result<u32> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        if frames.is_empty() {
+            bail!("No animation frames");
+        }
+        let shared = Arc::clone(&self.shared);
+        let id = shared.last_animation_id.fetch_add(1, Ordering::SeqCst) as u32 + 1;
+        let interval = ::std::cmp::max(1, duration_to_millis(interval));
+        self.call_on_thread(move || unsafe {
+            stop_animation(handle.hwnd, &shared)?;
+            let size = small_icon_size();
+            let mut loaded = Vec::with_capacity(frames.len());
+            for icon in frames {
+                match load_icon(&icon, size) {
+                    Ok(hicon) => loaded.push(InstalledIcon {
+                        icon,
+                        hicon,
+                        cached: false,
+                    }),
+                    Err(e) => {
+                        for frame in &loaded {
+                            destroy_icon(frame);
                         }
-                    });
+                        return Err(e);
+                    }
                 }
-                _ => { }
             }
-            return 0;
-        }
-        WM_DESTROY => {
-            let _ = delete_notification_area_icon(hwnd);
-            PostQuitMessage(0);
-            return 0;
-        }
-        WM_COMMAND => {
-            let menu_id = wparam as u32;
-            WINDOW_LOOP_DATA.with(|data| {
-                if let Some(ref data) = data.borrow().as_ref() {
-                    if data.event_sender.send(Event::Menu(menu_id)).is_err() {
-                        // event loop is terminated; close the window
-                        PostMessageW(hwnd, WM_DESTROY, 0, 0);
-                    }
+            let result = match show_unless_busy(handle.hwnd, &shared, loaded[0].hicon) {
+                Ok(()) if SetTimer(handle.hwnd, ANIMATION_TIMER_ID, interval, None) == 0 => {
+                    Err(last_error(Op::SetTimer).into())
                 }
+                result => result,
+            };
+            if let Err(e) = result {
+                for frame in &loaded {
+                    destroy_icon(frame);
+                }
+                return Err(e);
+            }
+            *shared.animation.lock().unwrap() = Some(Animation {
+                id,
+                frames: loaded,
+                current: 0,
             });
-            return 0;
-        }
-        _ => return DefWindowProcW(hwnd, msg, wparam, lparam),
+            Ok(id)
+        })
     }
-}
 
-fn str_to_wchar_str(s: &str) -> Vec<u16> {
-    let mut result: Vec<u16> = OsStr::new(s).encode_wide().collect();
-    result.push(0);
-    result
-}
+    // Stops the animation and shows the static icon again,
+    // unless the animation was replaced by another one.
+    fn stop_animation(&self, id: u32) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        self.call_on_thread(move || unsafe {
+            let running = shared.animation.lock().unwrap().as_ref().map(|a| a.id) == Some(id);
+            if running {
+                stop_animation(handle.hwnd, &shared)?;
+            }
+            Ok(())
+        })
+    }
 
-fn copy_str_to_wchar_array(arr: &mut[u16], s: &str) {
-    let s = str_to_wchar_str(s);
-    let len = ::std::cmp::min(s.len(), arr.len() - 1);
-    &arr[0..len].copy_from_slice(&s[0..len]);
-    arr[len] = 0;
-}
+    // Shows `icon` in place of the static icon and any animation, which carry on
+    // underneath and are shown again once it is cleared with `None`.
+    fn set_busy_icon(&self, icon: Option<&Icon>) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        let icon = icon.cloned();
+        self.call_on_thread(move || unsafe {
+            let installed = match icon {
+                Some(icon) => Some(InstalledIcon {
+                    hicon: load_icon(&icon, small_icon_size())?,
+                    icon,
+                    cached: false,
+                }),
+                None => None,
+            };
+            let previous = ::std::mem::replace(&mut *shared.busy_icon.lock().unwrap(), installed);
+            let result = if shared.icon_deleted.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                show_current_icon(handle.hwnd, &shared)
+            };
+            if let Some(previous) = previous {
+                destroy_icon(&previous);
+            }
+            result
+        })
+    }
 
-unsafe fn register_class(class_name: &[u16]) -> Result<()> {
-    let class: WNDCLASSW = WNDCLASSW {
-        style: 0,
-        lpfnWndProc: Some(window_proc),
-        cbClsExtra: 0,
-        cbWndExtra: 0,
-        hInstance: ptr::null_mut(),
-        hIcon: LoadIconW(ptr::null_mut(), IDI_APPLICATION),
-        hCursor: LoadCursorW(ptr::null_mut(), IDI_APPLICATION),
-        hbrBackground: COLOR_WINDOW as HBRUSH,
-        lpszMenuName: ptr::null_mut(),
-        lpszClassName: class_name.as_ptr(),
-    };
-    if RegisterClassW(&class) == 0 {
-        bail!("Error registering window class: {}", GetLastError());
+    // Returns the id of the new timer; each tick is reported as `Event::Timer`.
+    fn set_timer(&mut self, interval: Duration) -> Result<u32> {
+        let hwnd = match self.handle {
+            Some(ref handle) => WindowRef(handle.hwnd),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let id = self.shared.last_timer_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let interval = ::std::cmp::max(1, duration_to_millis(interval));
+        self.run_on_thread(Box::new(move || {
+            unsafe {
+                if SetTimer(hwnd.0, id, interval, None) == 0 {
+                    report_error(last_error(Op::SetTimer).into());
+                }
+            }
+        }))?;
+        Ok(id as u32)
     }
-    Ok(())
-}
 
-unsafe fn create_window(class_name: &[u16]) -> Result<HWND> {
-    let hwnd = CreateWindowExW(
-        0,
-        class_name.as_ptr(),
-        class_name.as_ptr(),
-        WS_OVERLAPPEDWINDOW,
-        CW_USEDEFAULT,
-        0,
-        CW_USEDEFAULT,
-        0,
-        ptr::null_mut(),
-        ptr::null_mut(),
-        ptr::null_mut(),
-        ptr::null_mut());
-    if hwnd.is_null() {
-        bail!("Error creating window: {}", GetLastError());
+    fn kill_timer(&self, id: u32) -> Result<()> {
+        let hwnd = match self.handle {
+            Some(ref handle) => WindowRef(handle.hwnd),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        self.run_on_thread(Box::new(move || {
+            unsafe { KillTimer(hwnd.0, id as UINT_PTR); }
+        }))
     }
-    Ok(hwnd)
-}
 
-unsafe fn create_popup_menu() -> Result<HMENU> {
-    let hmenu = CreatePopupMenu();
-    if hmenu.is_null() {
-        bail!("Error creating popup menu: {}", GetLastError());
+    fn add_menu_entry(&mut self, entry: MenuEntry, mode: MenuInsertMode) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        let config = self.config.clone();
+        self.call_on_thread(move || {
+            let mut owner_drawn_items = shared.owner_drawn_items.lock().unwrap();
+            unsafe { insert_menu_entry(handle.hmenu, entry, mode, &config, &shared, &mut owner_drawn_items) }
+        })
     }
-    let menu_info: MENUINFO = MENUINFO {
-        cbSize: ::std::mem::size_of::<MENUINFO>() as u32,
-        fMask: MIM_APPLYTOSUBMENUS | MIM_STYLE,
-        dwStyle: 0,
-        cyMax: 0,
-        hbrBack: COLOR_MENU as HBRUSH,
-        dwContextHelpID: 0,
-        dwMenuData: 0,
-    };
-    if SetMenuInfo(hmenu, &menu_info) == 0 {
-        bail!("Error setting popup menu info: {}", GetLastError());
+
+    fn insert_separator_after(&mut self, after: u32, id: u32) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        self.call_on_thread(move || unsafe {
+            insert_menu_separator_after(handle.hmenu, after, id)
+        })
     }
-    Ok(hmenu)
-}
 
-unsafe fn make_notify_icon_data(hwnd: HWND) -> NOTIFYICONDATAW {
-    let mut data: NOTIFYICONDATAW = ::std::mem::zeroed();
-    data.cbSize = ::std::mem::size_of::<NOTIFYICONDATAW>() as u32;
-    data.hWnd = hwnd;
-    data.uID = TASKBAR_ICON_ID;
-    data
-}
+    /// Builds a new menu from `entries` and swaps it in place of the current one.
+    fn set_menu(&mut self, entries: Vec<MenuEntry>) -> Result<()> {
+        let old_handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        let config = self.config.clone();
+        let mut new_handle = old_handle.clone();
+        new_handle.hmenu = self.call_on_thread(move || unsafe {
+            let mut owner_drawn_items = HashMap::new();
+            let hmenu = create_popup_menu()?;
+            for entry in entries {
+                if let Err(e) = insert_menu_entry(hmenu, entry, MenuInsertMode::Append, &config, &shared, &mut owner_drawn_items) {
+                    DestroyMenu(hmenu);
+                    return Err(e);
+                }
+            }
+            *shared.owner_drawn_items.lock().unwrap() = owner_drawn_items;
+            let new_hmenu = MenuRef(hmenu);
+            WINDOW_LOOP_DATA.with(|data| {
+                if let Some(ref mut data) = data.borrow_mut().as_mut() {
+                    data.handle.hmenu = hmenu;
+                    if data.menu_open {
+                        // the old menu is still being tracked; destroy it once it closes
+                        data.stale_menus.push(old_handle.hmenu);
+                    } else {
+                        DestroyMenu(old_handle.hmenu);
+                    }
+                }
+            });
+            Ok(new_hmenu)
+        })?.0;
+        self.handle = Some(new_handle);
+        Ok(())
+    }
 
-unsafe fn create_notification_area_icon(hwnd: HWND) -> Result<()> {
-    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
-    data.uFlags = NIF_MESSAGE;
-    data.uCallbackMessage = NOTIFICATION_MESSAGE_ID;
-    if Shell_NotifyIconW(NIM_ADD, &mut data) == 0 {
-        bail!("Error adding taskbar icon: {}", GetLastError());
+    fn set_menu_item_state(&mut self, id: u32, state: MenuItemState) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        self.call_on_thread(move || unsafe {
+            set_menu_item_state(handle.hmenu, id, state, &shared)
+        })
     }
-    Ok(())
-}
 
-unsafe fn delete_notification_area_icon(hwnd: HWND) -> Result<()> {
-    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
-    data.uFlags = NIF_ICON;
-    if Shell_NotifyIconW(NIM_DELETE, &mut data) == 0 {
-        bail!("Error deleting taskbar icon: {}", GetLastError());
+    fn set_menu_item_enabled(&self, id: u32, enabled: bool) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        self.call_on_thread(move || unsafe {
+            set_menu_item_enabled(handle.hmenu, id, enabled)
+        })
     }
-    Ok(())
-}
 
-unsafe fn init_window(class_name: &[u16]) -> Result<WindowHandle> {
-    register_class(class_name)?;
-    let hwnd = create_window(class_name)?;
-    let hmenu = create_popup_menu()?;
-    create_notification_area_icon(hwnd)?;
-    Ok(WindowHandle {
-        hwnd: hwnd,
-        hmenu: hmenu,
-    })
-}
+    fn set_menu_item_help(&self, id: u32, help: Option<String>) {
+        let mut help_texts = self.shared.help_texts.lock().unwrap();
+        match help {
+            Some(help) => help_texts.insert(id, help),
+            None => help_texts.remove(&id),
+        };
+    }
 
-unsafe fn window_message_loop() {
-    let mut msg: MSG = ::std::mem::uninitialized();
-    let mut result = GetMessageW(&mut msg, ptr::null_mut(), 0, 0);
-    while result != 0 {
-        if result == -1 {
-            // TODO: destroy window
-            // TODO: log error
-            return;
+    fn set_menu_item_check_bitmaps(&self, id: u32, checked: Option<Icon>, unchecked: Option<Icon>) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        self.call_on_thread(move || unsafe {
+            let bitmaps = CheckBitmaps {
+                checked: match checked {
+                    Some(ref icon) => Some(Bitmap(create_check_bitmap(icon)?)),
+                    None => None,
+                },
+                unchecked: match unchecked {
+                    Some(ref icon) => Some(Bitmap(create_check_bitmap(icon)?)),
+                    None => None,
+                },
+            };
+            // the bitmaps replaced are only deleted once the menu no longer shows them
+            let replaced = if bitmaps.checked.is_none() && bitmaps.unchecked.is_none() {
+                shared.check_bitmaps.lock().unwrap().remove(&id)
+            } else {
+                shared.check_bitmaps.lock().unwrap().insert(id, bitmaps)
+            };
+            let result = apply_check_bitmaps(handle.hmenu, id, &shared);
+            if result.is_err() {
+                shared.check_bitmaps.lock().unwrap().remove(&id);
+            }
+            drop(replaced);
+            result
+        })
+    }
+
+    fn menu_item_ids(&self) -> Result<Vec<u32>> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        self.call_on_thread(move || unsafe {
+            menu_item_ids(handle.hmenu)
+        })
+    }
+
+    fn menu_snapshot(&self) -> Result<Vec<MenuItemInfo>> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        self.call_on_thread(move || unsafe {
+            menu_snapshot(handle.hmenu)
+        })
+    }
+
+    fn find_menu_item(&self, label: &str) -> Result<Option<u32>> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let label = label.to_string();
+        self.call_on_thread(move || unsafe {
+            find_menu_item(handle.hmenu, &label)
+        })
+    }
+
+    fn remove_menu_item(&self, id: u32) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        self.call_on_thread(move || {
+            shared.owner_drawn_items.lock().unwrap().remove(&id);
+            let result = unsafe { remove_menu_item(handle.hmenu, id) };
+            shared.check_bitmaps.lock().unwrap().remove(&id);
+            result
+        })
+    }
+
+    #[cfg(feature = "balloons")]
+    fn show_balloon(&self, title: &str, body: &str, options: &NotificationOptions) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        if let Some(ref sound) = options.sound {
+            if !sound.exists() {
+                bail!("Sound file not found: {}", sound.display());
+            }
+        }
+        let title = title.to_string();
+        let body = body.to_string();
+        let options = options.clone();
+        self.call_on_thread(move || unsafe {
+            show_balloon(handle.hwnd, &title, &body, &options, None)?;
+            if let Some(ref sound) = options.sound {
+                play_sound(sound.as_os_str())?;
+            }
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "balloons")]
+    fn show_balloon_with_tip(&self, tip: &str, title: &str, body: &str, options: &NotificationOptions) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        if let Some(ref sound) = options.sound {
+            if !sound.exists() {
+                bail!("Sound file not found: {}", sound.display());
+            }
+        }
+        // a tip held back by the debounce would undo this one
+        self.shared.pending_updates.lock().unwrap().tip = None;
+        let tip = tip.to_string();
+        let title = title.to_string();
+        let body = body.to_string();
+        let options = options.clone();
+        let show_tip = self.config.show_tip;
+        let shared = Arc::clone(&self.shared);
+        self.call_on_thread(move || unsafe {
+            // should the shell refuse both at once, they are set one after the other
+            if show_balloon(handle.hwnd, &title, &body, &options, Some((&tip, show_tip))).is_err() {
+                set_tip(handle.hwnd, &tip, show_tip)?;
+                show_balloon(handle.hwnd, &title, &body, &options, None)?;
+            }
+            *shared.tip.lock().unwrap() = Some(tip);
+            if let Some(ref sound) = options.sound {
+                play_sound(sound.as_os_str())?;
+            }
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "balloons")]
+    fn notification_suppression(&self) -> Result<Option<SuppressionReason>> {
+        let mut state: QUERY_USER_NOTIFICATION_STATE = 0;
+        let result = unsafe { SHQueryUserNotificationState(&mut state) };
+        if result != S_OK {
+            bail!(ErrorKind::HResult(Op::QueryNotificationState, result as u32));
+        }
+        Ok(match state {
+            QUNS_NOT_PRESENT => Some(SuppressionReason::NotPresent),
+            QUNS_BUSY => Some(SuppressionReason::Busy),
+            QUNS_RUNNING_D3D_FULL_SCREEN => Some(SuppressionReason::FullScreenD3d),
+            QUNS_PRESENTATION_MODE => Some(SuppressionReason::PresentationMode),
+            QUNS_QUIET_TIME => Some(SuppressionReason::QuietTime),
+            QUNS_APP => Some(SuppressionReason::App),
+            _ => None,
+        })
+    }
+
+    /// Shows or hides the icon. Returns `true` if the icon had to be added anew,
+    /// in which case its tooltip needs to be set again.
+    fn set_visible(&mut self, visible: bool) -> Result<bool> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        let show_tip = self.config.show_tip;
+        self.call_on_thread(move || unsafe {
+            let hwnd = handle.hwnd;
+            shared.icon_hidden.store(!visible, Ordering::SeqCst);
+            if visible && shared.icon_deleted.load(Ordering::SeqCst) {
+                create_notification_area_icon(hwnd, handle.notification_message, show_tip)?;
+                shared.icon_deleted.store(false, Ordering::SeqCst);
+                show_current_icon(hwnd, &shared)?;
+                return Ok(true);
+            }
+            match set_icon_hidden(hwnd, !visible) {
+                Ok(()) => Ok(false),
+                Err(_) if !visible => {
+                    // older shells may not support NIF_STATE; fall back to deleting the icon
+                    delete_notification_area_icon(hwnd)?;
+                    shared.icon_deleted.store(true, Ordering::SeqCst);
+                    Ok(false)
+                }
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn is_icon_visible(&self) -> Result<bool> {
+        if let Some(ref handle) = self.handle {
+            unsafe {
+                is_notification_area_icon_visible(handle.hwnd)
+            }
+        } else {
+            bail!(ErrorKind::WindowClosed)
+        }
+    }
+
+    // Called on the window thread before the menu or any of its submenus is shown.
+    fn set_menu_opening_callback(&self, callback: Option<MenuOpeningCallback>) {
+        *self.shared.menu_opening.lock().unwrap() = callback.map(Arc::new);
+    }
+
+    fn set_attention_window(&mut self, hwnd: Option<HWND>) {
+        self.attention_window = hwnd.map(WindowRef);
+    }
+
+    fn set_main_window(&self, hwnd: Option<HWND>, hide_on_focus_loss: bool) -> Result<()> {
+        *self.shared.main_window.lock().unwrap() = hwnd.map(|hwnd| MainWindow { window: WindowRef(hwnd), hide_on_focus_loss });
+        if hwnd.is_some() && hide_on_focus_loss {
+            self.run_on_thread(Box::new(|| unsafe { install_focus_hook() }))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let shared = Arc::clone(&self.shared);
+        // posted callbacks run in order, so the ones before this have run by the time it does;
+        // on the window thread itself, they are run here
+        self.call_on_thread(move || unsafe {
+            let mut msg: MSG = ::std::mem::zeroed();
+            while PeekMessageW(&mut msg, handle.hwnd, handle.run_message, handle.run_message, PM_REMOVE) != 0 {
+                let f = Box::from_raw(msg.lParam as *mut ThreadCallback);
+                f();
+            }
+            let timer_set = shared.pending_updates.lock().unwrap().timer_set;
+            if timer_set {
+                KillTimer(handle.hwnd, DEBOUNCE_TIMER_ID);
+                flush_pending_updates(handle.hwnd);
+            }
+            Ok(())
+        })
+    }
+
+    fn icon_dpi(&self) -> Result<u32> {
+        match self.handle {
+            Some(ref handle) => Ok(unsafe { window_dpi(handle.hwnd) }),
+            None => bail!(ErrorKind::WindowClosed),
+        }
+    }
+
+    fn open_menu_at(&self, x: i32, y: i32, align: MenuAlignment) -> Result<()> {
+        let hwnd = match self.handle {
+            Some(ref handle) => WindowRef(handle.hwnd),
+            None => bail!(ErrorKind::WindowClosed),
+        };
+        let align = match align {
+            MenuAlignment::TopLeft => TPM_LEFTALIGN | TPM_TOPALIGN,
+            MenuAlignment::TopRight => TPM_RIGHTALIGN | TPM_TOPALIGN,
+            MenuAlignment::BottomLeft => TPM_LEFTALIGN | TPM_BOTTOMALIGN,
+            MenuAlignment::BottomRight => TPM_RIGHTALIGN | TPM_BOTTOMALIGN,
+            MenuAlignment::Center => TPM_CENTERALIGN | TPM_VCENTERALIGN,
+        };
+        // always posted, as the menu loop blocks until the menu is closed
+        self.run_on_thread(Box::new(move || unsafe { show_context_menu(hwnd.0, x, y, align) }))
+    }
+
+    fn request_attention(&self) -> Result<()> {
+        if let Some(ref handle) = self.handle {
+            let hwnd = self.attention_window.map(|w| w.0).unwrap_or(handle.hwnd);
+            unsafe {
+                flash_window(hwnd);
+            }
+            Ok(())
+        } else {
+            bail!(ErrorKind::WindowClosed)
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.handle.is_none()
+    }
+
+    fn close(&mut self) {
+        if self.thread.is_none() {
+            // a subclassed window belongs to the application, so it is only detached from
+            if self.is_window_thread() {
+                if let Some(ref h) = self.handle {
+                    unsafe { detach_window(h.hwnd); }
+                }
+            } else if let Some(ref h) = self.handle {
+                let hwnd = WindowRef(h.hwnd);
+                let _ = self.run_on_thread(Box::new(move || unsafe { detach_window(hwnd.0) }));
+            }
+        } else if let Some(ref h) = self.handle {
+            unsafe { PostMessageW(h.hwnd, WM_DESTROY, 0, 0); }
+        }
+        // the last `Wna` may be dropped on the window thread, e.g. in a callback,
+        // which then stops its message loop once the callback returns
+        let on_window_thread = self.is_window_thread();
+        self.handle = None;
+        if let Some(thread) = self.thread.take() {
+            if !on_window_thread {
+                let _ = thread.join();
+            }
+        }
+        if let Some(installed) = self.shared.icon.lock().unwrap().take() {
+            unsafe { destroy_icon(&installed); }
+        }
+        if let Some(installed) = self.shared.busy_icon.lock().unwrap().take() {
+            unsafe { destroy_icon(&installed); }
+        }
+        unsafe { self.shared.icon_cache.lock().unwrap().clear(None); }
+    }
+
+}
+
+// Locks `mutex` on any thread. On the window thread, the callbacks posted to it are run
+// while waiting, as the lock may be held by a thread that waits for one of them. A lock
+// poisoned by a panicking action is taken over, as the tray stays usable after it.
+pub fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    let window = WINDOW_LOOP_DATA.with(|data| {
+        data.try_borrow().ok().and_then(|data| data.as_ref().map(|data| (data.handle.hwnd, data.handle.run_message)))
+    });
+    let (hwnd, run_message) = match window {
+        Some(window) => window,
+        None => return mutex.lock().unwrap_or_else(PoisonError::into_inner),
+    };
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return guard,
+            Err(TryLockError::WouldBlock) => unsafe {
+                let mut msg: MSG = ::std::mem::zeroed();
+                if PeekMessageW(&mut msg, hwnd, run_message, run_message, PM_REMOVE) != 0 {
+                    let f = Box::from_raw(msg.lParam as *mut ThreadCallback);
+                    f();
+                } else {
+                    // sleeps until a message is posted; the release of the lock
+                    // wakes nobody, so it is checked again after a while
+                    MsgWaitForMultipleObjects(0, ptr::null(), FALSE, LOCK_RETRY_MS, QS_POSTMESSAGE);
+                }
+            },
+            Err(TryLockError::Poisoned(e)) => return e.into_inner(),
+        }
+    }
+}
+
+pub fn set_thread_priority<T>(thread: &thread::JoinHandle<T>, priority: ThreadPriority) -> Result<()> {
+    // winapi declares them as DWORD, but they are the signed values SetThreadPriority takes
+    let priority = match priority {
+        ThreadPriority::Idle => THREAD_PRIORITY_IDLE,
+        ThreadPriority::Lowest => THREAD_PRIORITY_LOWEST,
+        ThreadPriority::BelowNormal => THREAD_PRIORITY_BELOW_NORMAL,
+        ThreadPriority::Normal => THREAD_PRIORITY_NORMAL,
+        ThreadPriority::AboveNormal => THREAD_PRIORITY_ABOVE_NORMAL,
+        ThreadPriority::Highest => THREAD_PRIORITY_HIGHEST,
+    };
+    unsafe {
+        if SetThreadPriority(thread.as_raw_handle() as HANDLE, priority as INT) == 0 {
+            bail!(last_error(Op::SetThreadPriority));
+        }
+    }
+    Ok(())
+}
+
+// Services run in session 0, or in a window station of their own, where the shell
+// never shows a notification area icon. The checks are best-effort: when either
+// of them fails, the session is assumed to be interactive.
+unsafe fn check_interactive_session() -> Result<()> {
+    let mut session_id: DWORD = 0;
+    if ProcessIdToSessionId(GetCurrentProcessId(), &mut session_id) != 0 && session_id == 0 {
+        bail!(ErrorKind::NoInteractiveSession);
+    }
+    let station = GetProcessWindowStation();
+    if !station.is_null() {
+        let mut flags: USEROBJECTFLAGS = ::std::mem::zeroed();
+        let mut length: DWORD = 0;
+        let size = ::std::mem::size_of::<USEROBJECTFLAGS>() as DWORD;
+        if GetUserObjectInformationW(station as HANDLE, UOI_FLAGS as i32, &mut flags as *mut _ as *mut _, size, &mut length) != 0
+                && flags.dwFlags & WSF_VISIBLE == 0 {
+            bail!(ErrorKind::NoInteractiveSession);
+        }
+    }
+    Ok(())
+}
+
+// SetProcessDpiAwarenessContext is looked up at run time,
+// as importing it would keep the binary from loading before Windows 10
+pub fn set_process_dpi_aware() -> Result<()> {
+    unsafe {
+        let user32 = GetModuleHandleW(str_to_wchar_str("user32.dll").as_ptr());
+        if !user32.is_null() {
+            let proc = GetProcAddress(user32, b"SetProcessDpiAwarenessContext\0".as_ptr() as *const i8);
+            if !proc.is_null() {
+                let set_dpi_awareness_context: unsafe extern "system" fn(DPI_AWARENESS_CONTEXT) -> BOOL = ::std::mem::transmute(proc);
+                if set_dpi_awareness_context(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) != 0 {
+                    return Ok(());
+                }
+                if GetLastError() == ERROR_ACCESS_DENIED {
+                    // the awareness is already set by the manifest or the host process
+                    return Ok(());
+                }
+                // PER_MONITOR_AWARE_V2 needs Windows 10 1703; fall back to system awareness
+            }
+        }
+        if SetProcessDPIAware() == 0 {
+            bail!(last_error(Op::SetDpiAwareness));
+        }
+        Ok(())
+    }
+}
+
+// A panic must not unwind into the system, so it is caught here and kept for `Wna::last_panic`.
+unsafe extern "system" fn window_proc(hwnd: HWND, msg: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match panic::catch_unwind(AssertUnwindSafe(|| handle_message(hwnd, msg, wparam, lparam))) {
+        Ok(result) => result,
+        Err(payload) => {
+            let last_panic = WINDOW_LOOP_DATA.with(|data| {
+                data.try_borrow().ok().and_then(|data| data.as_ref().map(|data| Arc::clone(&data.config.last_panic)))
+            });
+            if let Some(last_panic) = last_panic {
+                if let Ok(mut last_panic) = last_panic.lock() {
+                    *last_panic = Some(panic_message(&*payload));
+                }
+            }
+            0
+        }
+    }
+}
+
+unsafe fn handle_message(hwnd: HWND, msg: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match msg {
+        NOTIFICATION_MESSAGE_ID => {
+            // NOTIFYICON_VERSION_4 packs the notification event into the low word of lparam
+            match LOWORD(lparam as DWORD) as UINT {
+                // NOTIFYICON_VERSION_4 sends NIN_SELECT for a left click or Enter
+                // and NIN_KEYSELECT for Space, with the icon anchor point in wparam
+                NIN_SELECT | NIN_KEYSELECT => {
+                    if send_click_event(Event::LeftClick) {
+                        return 0;
+                    }
+                    let main_window = WINDOW_LOOP_DATA.with(|data| {
+                        data.borrow().as_ref().and_then(|data| *data.shared.main_window.lock().unwrap())
+                    });
+                    match main_window {
+                        Some(main_window) => toggle_main_window(main_window.window.0),
+                        None => show_context_menu(hwnd, GET_X_LPARAM(wparam as LPARAM), GET_Y_LPARAM(wparam as LPARAM), 0),
+                    }
+                }
+                // sent for a right click as well as for Shift+F10 or the Apps key
+                WM_CONTEXTMENU => {
+                    if send_click_event(Event::RightClick) {
+                        return 0;
+                    }
+                    let (mut x, mut y) = (GET_X_LPARAM(wparam as LPARAM), GET_Y_LPARAM(wparam as LPARAM));
+                    if x == -1 && y == -1 {
+                        match get_notification_area_icon_rect(hwnd) {
+                            Ok(rect) => {
+                                x = rect.left;
+                                y = rect.top;
+                            }
+                            Err(e) => {
+                                report_error(e);
+                                return 0;
+                            }
+                        }
+                    }
+                    show_context_menu(hwnd, x, y, 0);
+                }
+                // only sent when the standard tooltip is not shown
+                NIN_POPUPOPEN => {
+                    let delay = WINDOW_LOOP_DATA.with(|data| {
+                        data.borrow_mut().as_mut().and_then(|data| {
+                            data.hover_point = Some(POINT { x: GET_X_LPARAM(wparam as LPARAM), y: GET_Y_LPARAM(wparam as LPARAM) });
+                            if data.menu_open { None } else { data.config.menu_hover_delay }
+                        })
+                    });
+                    if let Some(delay) = delay {
+                        let delay = ::std::cmp::max(1, duration_to_millis(delay));
+                        SetTimer(hwnd, HOVER_TIMER_ID, delay, None);
+                    }
+                }
+                NIN_POPUPCLOSE => {
+                    KillTimer(hwnd, HOVER_TIMER_ID);
+                }
+                // sent as the icon is registered with NOTIFYICON_VERSION_4
+                #[cfg(feature = "balloons")]
+                NIN_BALLOONSHOW => {
+                    WINDOW_LOOP_DATA.with(|data| {
+                        if let Some(data) = data.borrow().as_ref() {
+                            let _ = data.event_sender.send(Event::BalloonShown);
+                        }
+                    });
+                }
+                #[cfg(feature = "balloons")]
+                NIN_BALLOONUSERCLICK => {
+                    WINDOW_LOOP_DATA.with(|data| {
+                        if let Some(data) = data.borrow().as_ref() {
+                            if data.event_sender.send(Event::Balloon).is_err() {
+                                // event loop is terminated; close the window
+                                close_window(hwnd, data);
+                            }
+                        }
+                    });
+                }
+                #[cfg(feature = "balloons")]
+                NIN_BALLOONTIMEOUT | NIN_BALLOONHIDE => {
+                    let event = balloon_gone_event(LOWORD(lparam as DWORD) as UINT);
+                    WINDOW_LOOP_DATA.with(|data| {
+                        if let Some(data) = data.borrow().as_ref() {
+                            let _ = data.event_sender.send(event);
+                        }
+                    });
+                }
+                _ => { }
+            }
+            0
+        }
+        RUN_ON_THREAD_MESSAGE_ID => {
+            let f = Box::from_raw(lparam as *mut ThreadCallback);
+            f();
+            0
+        }
+        WM_INITMENUPOPUP => {
+            // the menu of a subclassed window may be opening as well
+            let callback = WINDOW_LOOP_DATA.with(|data| {
+                data.borrow().as_ref().and_then(|data| {
+                    if data.menu_open {
+                        data.shared.menu_opening.lock().unwrap().as_ref().map(Arc::clone)
+                    } else {
+                        None
+                    }
+                })
+            });
+            if let Some(callback) = callback {
+                callback();
+            }
+            0
+        }
+        WM_MENUSELECT => {
+            let help = WINDOW_LOOP_DATA.with(|data| {
+                data.borrow().as_ref().and_then(|data| {
+                    if data.menu_open {
+                        selected_item_help(&data.shared.help_texts.lock().unwrap(), wparam, lparam)
+                    } else {
+                        None
+                    }
+                })
+            });
+            show_menu_help(hwnd, lparam as HMENU, LOWORD(wparam as DWORD) as u32, help);
+            0
+        }
+        WM_MEASUREITEM => {
+            let item = &mut *(lparam as *mut MEASUREITEMSTRUCT);
+            if item.CtlType != ODT_MENU {
+                return DefWindowProcW(hwnd, msg, wparam, lparam);
+            }
+            let measure = WINDOW_LOOP_DATA.with(|data| {
+                data.borrow().as_ref().and_then(|data| {
+                    data.shared.owner_drawn_items.lock().unwrap().get(&item.itemID).map(|i| Arc::clone(&i.measure))
+                })
+            });
+            if let Some(measure) = measure {
+                let hdc = GetDC(hwnd);
+                let (width, height) = measure(&MeasureContext {
+                    hdc,
+                    data: item.itemData,
+                });
+                ReleaseDC(hwnd, hdc);
+                item.itemWidth = width;
+                item.itemHeight = height;
+            }
+            TRUE as LRESULT
+        }
+        WM_DRAWITEM => {
+            let item = &*(lparam as *const DRAWITEMSTRUCT);
+            if item.CtlType != ODT_MENU {
+                return DefWindowProcW(hwnd, msg, wparam, lparam);
+            }
+            let draw = WINDOW_LOOP_DATA.with(|data| {
+                data.borrow().as_ref().and_then(|data| {
+                    data.shared.owner_drawn_items.lock().unwrap().get(&item.itemID).map(|i| Arc::clone(&i.draw))
+                })
+            });
+            if let Some(draw) = draw {
+                draw(&DrawContext {
+                    hdc: item.hDC,
+                    rect: item.rcItem,
+                    data: item.itemData,
+                    selected: item.itemState & ODS_SELECTED != 0,
+                    disabled: item.itemState & (ODS_DISABLED | ODS_GRAYED) != 0,
+                });
+            }
+            TRUE as LRESULT
+        }
+        WM_TIMER if wparam == DEBOUNCE_TIMER_ID => {
+            KillTimer(hwnd, DEBOUNCE_TIMER_ID);
+            flush_pending_updates(hwnd);
+            0
+        }
+        WM_TIMER if wparam == HOVER_TIMER_ID => {
+            KillTimer(hwnd, HOVER_TIMER_ID);
+            // GetCursorPos fails while the input desktop is switched, e.g. to the secure
+            // desktop; the menu is then shown where the hovering started, as reported
+            // with NIN_POPUPOPEN, or failing that at the icon, like one opened from the keyboard
+            let mut point = POINT { x: 0, y: 0 };
+            if GetCursorPos(&mut point) == 0 {
+                report_error(last_error(Op::GetCursorPos).into());
+                let hover_point = WINDOW_LOOP_DATA.with(|data| data.borrow().as_ref().and_then(|data| data.hover_point));
+                match hover_point {
+                    Some(hover_point) => point = hover_point,
+                    None => match get_notification_area_icon_rect(hwnd) {
+                        Ok(rect) => {
+                            point.x = rect.left;
+                            point.y = rect.top;
+                        }
+                        Err(e) => {
+                            report_error(e);
+                            return 0;
+                        }
+                    },
+                }
+            }
+            show_context_menu(hwnd, point.x, point.y, 0);
+            0
+        }
+        WM_TIMER if wparam == ANIMATION_TIMER_ID => {
+            let shared = WINDOW_LOOP_DATA.with(|data| {
+                data.borrow().as_ref().map(|data| Arc::clone(&data.shared))
+            });
+            if let Some(shared) = shared {
+                let mut animation = shared.animation.lock().unwrap();
+                if let Some(ref mut animation) = *animation {
+                    animation.current = (animation.current + 1) % animation.frames.len();
+                    if let Err(e) = show_unless_busy(hwnd, &shared, animation.frames[animation.current].hicon) {
+                        report_error(e);
+                    }
+                }
+            }
+            0
+        }
+        WM_TIMER => {
+            WINDOW_LOOP_DATA.with(|data| {
+                if let Some(ref data) = *data.borrow() {
+                    if data.event_sender.send(Event::Timer(wparam as u32)).is_err() {
+                        KillTimer(hwnd, wparam);
+                    }
+                }
+            });
+            0
+        }
+        WM_DISPLAYCHANGE => {
+            // some Windows builds drop tray icons when the monitor layout changes
+            let data = WINDOW_LOOP_DATA.with(|data| {
+                data.borrow().as_ref().map(|data| {
+                    (data.handle.notification_message, data.config.show_tip, Arc::clone(&data.shared), data.event_sender.clone())
+                })
+            });
+            if let Some((notification_message, show_tip, shared, event_sender)) = data {
+                let shown = !shared.icon_hidden.load(Ordering::SeqCst) && !shared.icon_deleted.load(Ordering::SeqCst);
+                if shown && get_notification_area_icon_rect(hwnd).is_err() {
+                    if let Err(e) = readd_notification_area_icon(hwnd, notification_message, show_tip, &shared) {
+                        report_error(e);
+                    }
+                }
+                let _ = event_sender.send(Event::DisplayChanged);
+            }
+            0
+        }
+        WM_DPICHANGED => {
+            // reload the icon at the size matching the new DPI
+            let shared = WINDOW_LOOP_DATA.with(|data| {
+                data.borrow().as_ref().map(|data| Arc::clone(&data.shared))
+            });
+            if let Some(shared) = shared {
+                let icon = shared.icon.lock().unwrap().as_ref().map(|i| i.icon.clone());
+                match icon {
+                    // a ready-made handle cannot be reloaded at another size
+                    Some(Icon::Hicon(_)) | Some(Icon::OwnedHicon(_)) | None => { }
+                    Some(icon) => {
+                        let dpi = HIWORD(wparam as DWORD) as u32;
+                        if let Err(e) = install_icon(hwnd, &shared, &icon, small_icon_size_for_dpi(dpi)) {
+                            report_error(e);
+                        }
+                    }
+                }
+            }
+            0
+        }
+        WM_DESTROY => {
+            let shared = WINDOW_LOOP_DATA.with(|data| {
+                data.borrow().as_ref().map(|data| Arc::clone(&data.shared))
+            });
+            if let Some(shared) = shared {
+                if let Err(e) = stop_animation(hwnd, &shared) {
+                    report_error(e);
+                }
+            }
+            if let Err(e) = delete_notification_area_icon(hwnd) {
+                report_error(e);
+            }
+            destroy_menus();
+            remove_focus_hook();
+            PostQuitMessage(0);
+            0
+        }
+        WM_COMMAND => {
+            let menu_id = wparam as u32;
+            WINDOW_LOOP_DATA.with(|data| {
+                if let Some(data) = data.borrow().as_ref() {
+                    if data.event_sender.send(Event::Menu(menu_id)).is_err() {
+                        // event loop is terminated; close the window
+                        close_window(hwnd, data);
+                    }
+                }
+            });
+            0
+        }
+        _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+    }
+}
+
+// Passes the messages meant for `wna` on to `window_proc` and everything else to the
+// application's window procedure. The icon messages are registered ones here, and menu
+// commands and timers are told apart by their ids.
+unsafe extern "system" fn subclass_proc(hwnd: HWND, msg: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    let data = WINDOW_LOOP_DATA.with(|data| {
+        data.borrow().as_ref().and_then(|data| {
+            data.original_proc.map(|original_proc| (original_proc, data.handle.clone(), Arc::clone(&data.shared)))
+        })
+    });
+    let (original_proc, handle, shared) = match data {
+        Some(data) => data,
+        None => return DefWindowProcW(hwnd, msg, wparam, lparam),
+    };
+    let original_proc: WNDPROC = ::std::mem::transmute(original_proc);
+    match msg {
+        _ if msg == handle.notification_message => window_proc(hwnd, NOTIFICATION_MESSAGE_ID, wparam, lparam),
+        _ if msg == handle.run_message => window_proc(hwnd, RUN_ON_THREAD_MESSAGE_ID, wparam, lparam),
+        WM_COMMAND if lparam == 0 && HIWORD(wparam as DWORD) == 0
+                && GetMenuState(handle.hmenu, LOWORD(wparam as DWORD) as UINT, MF_BYCOMMAND) != !0 => {
+            window_proc(hwnd, msg, wparam, lparam)
+        }
+        WM_TIMER if wparam >= DEBOUNCE_TIMER_ID && wparam <= shared.last_timer_id.load(Ordering::SeqCst) => {
+            window_proc(hwnd, msg, wparam, lparam)
+        }
+        WM_MEASUREITEM | WM_DRAWITEM if is_own_owner_drawn_item(&shared, wparam, lparam) => {
+            window_proc(hwnd, msg, wparam, lparam)
+        }
+        WM_DPICHANGED | WM_DISPLAYCHANGE | WM_INITMENUPOPUP | WM_MENUSELECT => {
+            window_proc(hwnd, msg, wparam, lparam);
+            CallWindowProcW(original_proc, hwnd, msg, wparam, lparam)
+        }
+        WM_DESTROY => {
+            detach_window(hwnd);
+            CallWindowProcW(original_proc, hwnd, msg, wparam, lparam)
+        }
+        _ => CallWindowProcW(original_proc, hwnd, msg, wparam, lparam),
+    }
+}
+
+// menu items are measured and drawn with a zero wparam
+unsafe fn is_own_owner_drawn_item(shared: &SharedState, wparam: WPARAM, lparam: LPARAM) -> bool {
+    if wparam != 0 {
+        return false;
+    }
+    let item = &*(lparam as *const MEASUREITEMSTRUCT);
+    item.CtlType == ODT_MENU && shared.owner_drawn_items.lock().unwrap().contains_key(&item.itemID)
+}
+
+// Removes the icon and restores the window procedure of a subclassed window.
+unsafe fn detach_window(hwnd: HWND) {
+    let data = WINDOW_LOOP_DATA.with(|data| {
+        data.borrow().as_ref().and_then(|data| {
+            data.original_proc.map(|original_proc| (original_proc, data.handle.run_message, Arc::clone(&data.shared), data.help_tooltip))
+        })
+    });
+    if let Some((original_proc, run_message, shared, help_tooltip)) = data {
+        // the tooltip is owned by the application's window, which stays
+        if let Some(tooltip) = help_tooltip {
+            DestroyWindow(tooltip);
+        }
+        if let Err(e) = stop_animation(hwnd, &shared) {
+            report_error(e);
+        }
+        if let Err(e) = delete_notification_area_icon(hwnd) {
+            report_error(e);
+        }
+        destroy_menus();
+        remove_focus_hook();
+        for id in DEBOUNCE_TIMER_ID..shared.last_timer_id.load(Ordering::SeqCst) + 1 {
+            KillTimer(hwnd, id);
+        }
+        SetWindowLongPtrW(hwnd, GWLP_WNDPROC, original_proc);
+        let mut msg: MSG = ::std::mem::zeroed();
+        while PeekMessageW(&mut msg, hwnd, run_message, run_message, PM_REMOVE) != 0 {
+            drop(Box::from_raw(msg.lParam as *mut ThreadCallback));
+        }
+        WINDOW_LOOP_DATA.with(|data| *data.borrow_mut() = None);
+    }
+}
+
+// closes the window once the event loop is gone; a subclassed window is detached
+// from later, as `WINDOW_LOOP_DATA` is borrowed here
+unsafe fn close_window(hwnd: HWND, data: &WindowLoopData) {
+    if data.original_proc.is_some() {
+        let window = WindowRef(hwnd);
+        let f: ThreadCallback = Box::new(move || detach_window(window.0));
+        let f = Box::into_raw(Box::new(f));
+        if PostMessageW(hwnd, data.handle.run_message, 0, f as LPARAM) == 0 {
+            drop(Box::from_raw(f));
+        }
+    } else {
+        PostMessageW(hwnd, WM_DESTROY, 0, 0);
+    }
+}
+
+unsafe fn flush_pending_updates(hwnd: HWND) {
+    let data = WINDOW_LOOP_DATA.with(|data| {
+        data.borrow().as_ref().map(|data| (Arc::clone(&data.shared), data.config.show_tip))
+    });
+    if let Some((shared, show_tip)) = data {
+        let (icon, tip) = {
+            let mut pending = shared.pending_updates.lock().unwrap();
+            pending.timer_set = false;
+            pending.last_update = Some(Instant::now());
+            (pending.icon.take(), pending.tip.take())
+        };
+        if let Some(icon) = icon {
+            if let Err(e) = install_icon(hwnd, &shared, &icon, small_icon_size()) {
+                report_error(e);
+            }
+        }
+        if let Some(tip) = tip {
+            match set_tip(hwnd, &tip, show_tip) {
+                Ok(()) => *shared.tip.lock().unwrap() = Some(tip),
+                Err(e) => report_error(e),
+            }
+        }
+    }
+}
+
+// `align` is a combination of the TPM_*ALIGN flags
+unsafe fn show_context_menu(hwnd: HWND, x: i32, y: i32, align: UINT) {
+    // a click may come before the hover delay has passed
+    KillTimer(hwnd, HOVER_TIMER_ID);
+    let previous_window = GetForegroundWindow();
+    // the menu does not close on an outside click unless its owner is the foreground window
+    SetForegroundWindow(hwnd);
+    // WINDOW_LOOP_DATA must not stay borrowed while the menu is tracked,
+    // as messages dispatched from the menu loop need it too
+    let menu = WINDOW_LOOP_DATA.with(|data| {
+        data.borrow_mut().as_mut().map(|data| {
+            data.menu_open = true;
+            #[cfg(feature = "balloons")]
+            {
+                if data.config.overflow_balloon {
+                    let count = overflow_item_count(data.handle.hmenu);
+                    if count > 0 {
+                        let _ = data.event_sender.send(Event::MenuOverflow(count));
+                    }
+                }
+            }
+            let flags = if data.config.right_to_left { TPM_LAYOUTRTL | align } else { align };
+            (data.handle.hmenu, flags, data.config.restore_focus)
+        })
+    });
+    if let Some((hmenu, flags, restore_focus)) = menu {
+        TrackPopupMenu(
+            hmenu,
+            flags,
+            x,
+            y,
+            0,
+            hwnd,
+            ptr::null());
+        // see KB135788: without this the menu may not show up the next time
+        PostMessageW(hwnd, WM_NULL, 0, 0);
+        show_menu_help(hwnd, ptr::null_mut(), 0, None);
+        WINDOW_LOOP_DATA.with(|data| {
+            if let Some(ref mut data) = data.borrow_mut().as_mut() {
+                data.menu_open = false;
+                for hmenu in data.stale_menus.drain(..) {
+                    DestroyMenu(hmenu);
+                }
+            }
+        });
+        // this may be refused by the system's foreground lock rules, in which case
+        // the previous window just flashes in the taskbar
+        if restore_focus && !previous_window.is_null() && previous_window != hwnd {
+            SetForegroundWindow(previous_window);
+        }
+    }
+}
+
+// DestroyMenu also destroys the submenus of the destroyed menu
+unsafe fn destroy_menus() {
+    WINDOW_LOOP_DATA.with(|data| {
+        if let Some(ref mut data) = data.borrow_mut().as_mut() {
+            let hmenu = ::std::mem::replace(&mut data.handle.hmenu, ptr::null_mut());
+            if !hmenu.is_null() {
+                if data.menu_open {
+                    data.stale_menus.push(hmenu);
+                } else {
+                    DestroyMenu(hmenu);
+                }
+            }
+            if !data.menu_open {
+                for hmenu in data.stale_menus.drain(..) {
+                    DestroyMenu(hmenu);
+                }
+            }
+        }
+    });
+}
+
+// passes an error that happened on the window thread on to the application
+fn report_error(e: Error) {
+    WINDOW_LOOP_DATA.with(|data| {
+        if let Some(data) = data.borrow().as_ref() {
+            let _ = data.error_sender.send(e);
+        }
+    });
+}
+
+// Long durations are clamped rather than wrapped; SetTimer takes no more than this anyway.
+fn duration_to_millis(d: Duration) -> u32 {
+    let millis = d.as_secs().checked_mul(1000)
+        .map(|millis| millis.saturating_add(u64::from(d.subsec_millis())))
+        .unwrap_or(u64::MAX);
+    ::std::cmp::min(millis, u64::from(USER_TIMER_MAXIMUM)) as u32
+}
+
+unsafe fn last_error(operation: Op) -> ErrorKind {
+    ErrorKind::Win32(operation, GetLastError())
+}
+
+// The system's description of a Win32 error code or HRESULT, without the trailing line break.
+pub fn win32_message(code: u32) -> String {
+    let mut buffer = [0u16; 512];
+    let len = unsafe {
+        FormatMessageW(
+            FORMAT_MESSAGE_FROM_SYSTEM | FORMAT_MESSAGE_IGNORE_INSERTS,
+            ptr::null(),
+            code,
+            0,
+            buffer.as_mut_ptr(),
+            buffer.len() as DWORD,
+            ptr::null_mut())
+    };
+    String::from_utf16_lossy(&buffer[..len as usize]).trim_end().to_string()
+}
+
+fn str_to_wchar_str(s: &str) -> Vec<u16> {
+    let mut result: Vec<u16> = OsStr::new(s).encode_wide().collect();
+    result.push(0);
+    result
+}
+
+fn copy_str_to_wchar_array(arr: &mut[u16], s: &str) {
+    let s = str_to_wchar_str(s);
+    let mut len = ::std::cmp::min(s.len(), arr.len() - 1);
+    // don't split a surrogate pair
+    if len > 0 && s[len - 1] >= 0xD800 && s[len - 1] <= 0xDBFF {
+        len -= 1;
+    }
+    arr[0..len].copy_from_slice(&s[0..len]);
+    arr[len] = 0;
+}
+
+unsafe fn register_class(class_name: &[u16], class_icon: Option<&Icon>) -> Result<()> {
+    let hicon = match class_icon {
+        Some(icon) => load_icon(icon, 0)?,
+        None => LoadIconW(ptr::null_mut(), IDI_APPLICATION),
+    };
+    let class: WNDCLASSW = WNDCLASSW {
+        style: 0,
+        lpfnWndProc: Some(window_proc),
+        cbClsExtra: 0,
+        cbWndExtra: 0,
+        hInstance: ptr::null_mut(),
+        hIcon: hicon,
+        hCursor: LoadCursorW(ptr::null_mut(), IDC_ARROW),
+        // system colors are passed as brushes offset by one, so that COLOR_SCROLLBAR (0)
+        // is not mistaken for a null brush; the window is never shown, so this only
+        // matters if it is ever painted
+        hbrBackground: (COLOR_WINDOW + 1) as HBRUSH,
+        lpszMenuName: ptr::null_mut(),
+        lpszClassName: class_name.as_ptr(),
+    };
+    if RegisterClassW(&class) == 0 {
+        bail!(last_error(Op::RegisterWindowClass));
+    }
+    Ok(())
+}
+
+unsafe fn create_window(class_name: &[u16], config: &WindowConfig) -> Result<HWND> {
+    let ex_style = if config.right_to_left { config.ex_style | WS_EX_LAYOUTRTL } else { config.ex_style };
+    let parent = if config.message_only { HWND_MESSAGE } else { ptr::null_mut() };
+    let hwnd = CreateWindowExW(
+        ex_style,
+        class_name.as_ptr(),
+        class_name.as_ptr(),
+        WS_OVERLAPPEDWINDOW,
+        CW_USEDEFAULT,
+        0,
+        CW_USEDEFAULT,
+        0,
+        parent,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        ptr::null_mut());
+    if hwnd.is_null() {
+        bail!(last_error(Op::CreateWindow));
+    }
+    Ok(hwnd)
+}
+
+// Sends `event` if the application handles clicks with that button itself.
+fn send_click_event(event: Event) -> bool {
+    WINDOW_LOOP_DATA.with(|data| {
+        match data.borrow().as_ref() {
+            Some(data) => {
+                let handled = match event {
+                    Event::LeftClick => data.config.left_click_handled,
+                    _ => data.config.right_click_handled,
+                };
+                if handled {
+                    let _ = data.event_sender.send(event);
+                }
+                handled
+            }
+            None => false,
+        }
+    })
+}
+
+// The help text of the item selected according to the parameters of `WM_MENUSELECT`.
+fn selected_item_help(help_texts: &HashMap<u32, String>, wparam: WPARAM, lparam: LPARAM) -> Option<String> {
+    let flags = HIWORD(wparam as DWORD) as UINT;
+    // the menu is closing, or a submenu is selected
+    let closing = flags == 0xFFFF && lparam == 0;
+    if closing || flags & MF_POPUP != 0 {
+        return None;
+    }
+    help_texts.get(&(LOWORD(wparam as DWORD) as u32)).cloned()
+}
+
+// Shows `help` next to the item `id` of `hmenu` in a tracking tooltip, or hides the tooltip.
+unsafe fn show_menu_help(hwnd: HWND, hmenu: HMENU, id: u32, help: Option<String>) {
+    let tooltip = WINDOW_LOOP_DATA.with(|data| data.borrow().as_ref().and_then(|data| data.help_tooltip));
+    let help = match help {
+        Some(help) => help,
+        None => {
+            if let Some(tooltip) = tooltip {
+                SendMessageW(tooltip, TTM_TRACKACTIVATE, FALSE as WPARAM, 0);
+            }
+            return;
+        }
+    };
+    let tooltip = match tooltip {
+        Some(tooltip) => tooltip,
+        None => match create_help_tooltip(hwnd) {
+            Ok(tooltip) => {
+                WINDOW_LOOP_DATA.with(|data| {
+                    if let Some(ref mut data) = data.borrow_mut().as_mut() {
+                        data.help_tooltip = Some(tooltip);
+                    }
+                });
+                tooltip
+            }
+            Err(e) => {
+                report_error(e);
+                return;
+            }
+        },
+    };
+    let mut point = POINT { x: 0, y: 0 };
+    let mut rect: RECT = ::std::mem::zeroed();
+    match find_menu_item_position(hmenu, id) {
+        Some((hmenu, position)) if GetMenuItemRect(ptr::null_mut(), hmenu, position, &mut rect) != 0 => {
+            point.x = rect.right;
+            point.y = rect.top;
+        }
+        _ => {
+            GetCursorPos(&mut point);
+        }
+    }
+    let mut text = str_to_wchar_str(&help);
+    let mut info = help_tool_info(hwnd);
+    info.lpszText = text.as_mut_ptr();
+    SendMessageW(tooltip, TTM_UPDATETIPTEXTW, 0, &mut info as *mut _ as LPARAM);
+    SendMessageW(tooltip, TTM_TRACKPOSITION, 0, MAKELONG(point.x as u16, point.y as u16) as LPARAM);
+    SendMessageW(tooltip, TTM_TRACKACTIVATE, TRUE as WPARAM, &mut info as *mut _ as LPARAM);
+}
+
+unsafe fn help_tool_info(hwnd: HWND) -> TTTOOLINFOW {
+    let mut info: TTTOOLINFOW = ::std::mem::zeroed();
+    info.cbSize = ::std::mem::size_of::<TTTOOLINFOW>() as UINT;
+    info.uFlags = TTF_TRACK | TTF_ABSOLUTE;
+    info.hwnd = hwnd;
+    info
+}
+
+unsafe fn create_help_tooltip(hwnd: HWND) -> Result<HWND> {
+    let controls = INITCOMMONCONTROLSEX {
+        dwSize: ::std::mem::size_of::<INITCOMMONCONTROLSEX>() as DWORD,
+        dwICC: ICC_BAR_CLASSES,
+    };
+    InitCommonControlsEx(&controls);
+    let class_name = str_to_wchar_str(TOOLTIPS_CLASS);
+    let tooltip = CreateWindowExW(
+        WS_EX_TOPMOST,
+        class_name.as_ptr(),
+        ptr::null(),
+        WS_POPUP | TTS_NOPREFIX | TTS_ALWAYSTIP,
+        CW_USEDEFAULT,
+        CW_USEDEFAULT,
+        CW_USEDEFAULT,
+        CW_USEDEFAULT,
+        hwnd,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        ptr::null_mut());
+    if tooltip.is_null() {
+        bail!(last_error(Op::CreateHelpTooltip));
+    }
+    // wrap long help texts
+    SendMessageW(tooltip, TTM_SETMAXTIPWIDTH, 0, 400);
+    let mut text = str_to_wchar_str("");
+    let mut info = help_tool_info(hwnd);
+    info.lpszText = text.as_mut_ptr();
+    if SendMessageW(tooltip, TTM_ADDTOOLW, 0, &mut info as *mut _ as LPARAM) == 0 {
+        DestroyWindow(tooltip);
+        bail!(last_error(Op::AddHelpTool));
+    }
+    Ok(tooltip)
+}
+
+unsafe fn create_popup_menu() -> Result<HMENU> {
+    let hmenu = CreatePopupMenu();
+    if hmenu.is_null() {
+        bail!(last_error(Op::CreatePopupMenu));
+    }
+    let menu_info: MENUINFO = MENUINFO {
+        cbSize: ::std::mem::size_of::<MENUINFO>() as u32,
+        fMask: MIM_APPLYTOSUBMENUS | MIM_STYLE,
+        dwStyle: 0,
+        cyMax: 0,
+        hbrBack: (COLOR_MENU + 1) as HBRUSH,
+        dwContextHelpID: 0,
+        dwMenuData: 0,
+    };
+    if SetMenuInfo(hmenu, &menu_info) == 0 {
+        bail!(last_error(Op::SetMenuInfo));
+    }
+    Ok(hmenu)
+}
+
+unsafe fn make_notify_icon_data(hwnd: HWND) -> NOTIFYICONDATAW {
+    let mut data: NOTIFYICONDATAW = ::std::mem::zeroed();
+    data.cbSize = ::std::mem::size_of::<NOTIFYICONDATAW>() as u32;
+    data.hWnd = hwnd;
+    data.uID = TASKBAR_ICON_ID;
+    data
+}
+
+unsafe fn create_notification_area_icon(hwnd: HWND, callback_message: UINT, show_tip: bool) -> Result<()> {
+    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
+    data.uFlags = NIF_MESSAGE;
+    if show_tip {
+        data.uFlags |= NIF_SHOWTIP;
+    }
+    data.uCallbackMessage = callback_message;
+    if Shell_NotifyIconW(NIM_ADD, &mut data) == 0 {
+        bail!(last_error(Op::AddIcon));
+    }
+    set_notification_area_icon_version(hwnd)
+}
+
+unsafe fn set_notification_area_icon_version(hwnd: HWND) -> Result<()> {
+    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
+    *data.u.uVersion_mut() = NOTIFYICON_VERSION_4;
+    if Shell_NotifyIconW(NIM_SETVERSION, &mut data) == 0 {
+        bail!(last_error(Op::SetIconVersion));
+    }
+    Ok(())
+}
+
+unsafe fn readd_notification_area_icon(hwnd: HWND, notification_message: UINT, show_tip: bool, shared: &SharedState) -> Result<()> {
+    create_notification_area_icon(hwnd, notification_message, show_tip)?;
+    show_current_icon(hwnd, shared)?;
+    if let Some(ref tip) = *shared.tip.lock().unwrap() {
+        set_tip(hwnd, tip, show_tip)?;
+    }
+    Ok(())
+}
+
+unsafe fn delete_notification_area_icon(hwnd: HWND) -> Result<()> {
+    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
+    data.uFlags = NIF_ICON;
+    if Shell_NotifyIconW(NIM_DELETE, &mut data) == 0 {
+        bail!(last_error(Op::DeleteIcon));
+    }
+    Ok(())
+}
+
+unsafe fn get_notification_area_icon_rect(hwnd: HWND) -> Result<RECT> {
+    let mut identifier: NOTIFYICONIDENTIFIER = ::std::mem::zeroed();
+    identifier.cbSize = ::std::mem::size_of::<NOTIFYICONIDENTIFIER>() as DWORD;
+    identifier.hWnd = hwnd;
+    identifier.uID = TASKBAR_ICON_ID;
+    let mut rect: RECT = ::std::mem::zeroed();
+    let result = Shell_NotifyIconGetRect(&identifier, &mut rect);
+    if result != 0 {
+        bail!(ErrorKind::HResult(Op::GetIconRect, result as u32));
+    }
+    Ok(rect)
+}
+
+// Icons in the overflow area are laid out in a flyout of their own, which is only
+// on screen while it is open; the rect of such an icon is then outside the taskbar.
+unsafe fn is_notification_area_icon_visible(hwnd: HWND) -> Result<bool> {
+    let rect = get_notification_area_icon_rect(hwnd)?;
+    if rect.right <= rect.left || rect.bottom <= rect.top {
+        return Ok(false);
+    }
+    let taskbar = FindWindowW(str_to_wchar_str("Shell_TrayWnd").as_ptr(), ptr::null());
+    let mut taskbar_rect: RECT = ::std::mem::zeroed();
+    if taskbar.is_null() || GetWindowRect(taskbar, &mut taskbar_rect) == 0 {
+        // no taskbar to compare with, e.g. under a third-party shell
+        return Ok(true);
+    }
+    let mut intersection: RECT = ::std::mem::zeroed();
+    Ok(IntersectRect(&mut intersection, &rect, &taskbar_rect) != 0)
+}
+
+unsafe fn init_window(class_name: &[u16], config: &WindowConfig) -> Result<WindowHandle> {
+    register_class(class_name, config.class_icon.as_ref())?;
+    let hwnd = create_window(class_name, config)?;
+    let hmenu = create_popup_menu()?;
+    create_notification_area_icon(hwnd, NOTIFICATION_MESSAGE_ID, config.show_tip)?;
+    Ok(WindowHandle {
+        hwnd,
+        hmenu,
+        thread_id: GetCurrentThreadId(),
+        notification_message: NOTIFICATION_MESSAGE_ID,
+        run_message: RUN_ON_THREAD_MESSAGE_ID,
+    })
+}
+
+unsafe fn window_message_loop() {
+    let mut msg: MSG = ::std::mem::zeroed();
+    loop {
+        if PeekMessageW(&mut msg, ptr::null_mut(), 0, 0, PM_NOREMOVE) == 0 {
+            send_idle_event();
+        }
+        let result = GetMessageW(&mut msg, ptr::null_mut(), 0, 0);
+        if result == 0 {
+            break;
+        }
+        if result == -1 {
+            // TODO: destroy window
+            report_error(last_error(Op::GetMessage).into());
+            return;
+        }
+        TranslateMessage(&msg);
+        DispatchMessageW(&msg);
+    }
+    // drop the callbacks that will never run, so that their senders disconnect
+    while PeekMessageW(&mut msg, ptr::null_mut(), RUN_ON_THREAD_MESSAGE_ID, RUN_ON_THREAD_MESSAGE_ID, PM_REMOVE) != 0 {
+        drop(Box::from_raw(msg.lParam as *mut ThreadCallback));
+    }
+}
+
+// Called when the queue has run dry. The data is skipped if it is borrowed,
+// which happens when a host pumps messages from inside a window procedure.
+fn send_idle_event() {
+    WINDOW_LOOP_DATA.with(|data| {
+        if let Ok(mut data) = data.try_borrow_mut() {
+            if let Some(ref mut data) = *data {
+                if let Some(throttle) = data.config.idle_throttle {
+                    let throttle = throttle.max(MIN_IDLE_THROTTLE);
+                    let now = Instant::now();
+                    if data.last_idle.map(|last| now.duration_since(last) >= throttle).unwrap_or(true) {
+                        data.last_idle = Some(now);
+                        let _ = data.event_sender.send(Event::Idle);
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Dispatches one message of the calling thread's queue, if there is any.
+// Returns true if it was WM_QUIT, which is posted again for the host's own loop.
+pub fn pump_once() -> bool {
+    unsafe {
+        let mut msg: MSG = ::std::mem::zeroed();
+        if PeekMessageW(&mut msg, ptr::null_mut(), 0, 0, PM_REMOVE) == 0 {
+            send_idle_event();
+            return false;
+        }
+        if msg.message == WM_QUIT {
+            PostQuitMessage(msg.wParam as i32);
+            return true;
+        }
+        TranslateMessage(&msg);
+        DispatchMessageW(&msg);
+        false
+    }
+}
+
+// a zero size loads the icon at its default (large icon) size
+unsafe fn load_icon(icon: &Icon, size: i32) -> Result<HICON> {
+    match icon {
+        Icon::Hicon(hicon) => Ok(*hicon),
+        Icon::OwnedHicon(ref handle) => Ok(handle.hicon()),
+        Icon::File(ref file_name) => load_icon_from_file(file_name, size),
+        Icon::ResourceByName(ref name) => load_icon_from_resource_by_name(name, size),
+        Icon::ResourceByOrd(ord) => load_icon_from_resource_by_ord(*ord, size),
+        Icon::SelfExe => load_icon_from_self_exe(size),
+    }
+}
+
+// shows `icon` in the notification area and destroys the previous icon if it was ours
+unsafe fn install_icon(hwnd: HWND, shared: &SharedState, icon: &Icon, size: i32) -> Result<()> {
+    let mut current = shared.icon.lock().unwrap();
+    let installed = match IconCache::key(icon, size) {
+        Some(key) => {
+            let shown = current.as_ref().map(|installed| installed.hicon);
+            InstalledIcon {
+                icon: icon.clone(),
+                hicon: shared.icon_cache.lock().unwrap().get_or_load(key, icon, size, shown)?,
+                cached: true,
+            }
+        }
+        None => InstalledIcon {
+            icon: icon.clone(),
+            hicon: load_icon(icon, size)?,
+            cached: false,
+        },
+    };
+    let hicon = installed.hicon;
+    if let Err(e) = show_unless_busy(hwnd, shared, hicon) {
+        destroy_icon(&installed);
+        return Err(e);
+    }
+    if let Some(previous) = current.take() {
+        if previous.hicon != hicon {
+            destroy_icon(&previous);
+        }
+    }
+    *current = Some(installed);
+    Ok(())
+}
+
+// The static icon is shown again before the frames are destroyed, so that the shell is
+// never left with a destroyed handle; without one, the icon is shown blank.
+unsafe fn stop_animation(hwnd: HWND, shared: &SharedState) -> Result<()> {
+    let animation = match shared.animation.lock().unwrap().take() {
+        Some(animation) => animation,
+        None => return Ok(()),
+    };
+    KillTimer(hwnd, ANIMATION_TIMER_ID);
+    let result = if shared.icon_deleted.load(Ordering::SeqCst) {
+        Ok(())
+    } else {
+        show_current_icon(hwnd, shared)
+    };
+    for frame in &animation.frames {
+        destroy_icon(frame);
+    }
+    result
+}
+
+// The busy icon, else the current frame of the animation, else the static icon.
+unsafe fn show_current_icon(hwnd: HWND, shared: &SharedState) -> Result<()> {
+    let busy = shared.busy_icon.lock().unwrap().as_ref().map(|installed| installed.hicon);
+    let frame = shared.animation.lock().unwrap().as_ref().map(|animation| animation.frames[animation.current].hicon);
+    let hicon = busy.or(frame).or_else(|| shared.icon.lock().unwrap().as_ref().map(|installed| installed.hicon));
+    set_icon(hwnd, hicon.unwrap_or(ptr::null_mut()))
+}
+
+// the icon is only changed underneath while the busy icon is shown
+unsafe fn show_unless_busy(hwnd: HWND, shared: &SharedState, hicon: HICON) -> Result<()> {
+    if shared.busy_icon.lock().unwrap().is_some() {
+        return Ok(());
+    }
+    set_icon(hwnd, hicon)
+}
+
+unsafe fn destroy_icon(installed: &InstalledIcon) {
+    if installed.cached {
+        return;
+    }
+    match installed.icon {
+        // the application keeps the ownership of the handle, and an owned one is
+        // destroyed with the last clone of its icon
+        Icon::Hicon(_) | Icon::OwnedHicon(_) => { }
+        _ => { DestroyIcon(installed.hicon); }
+    }
+}
+
+// GetDpiForWindow (Windows 10) and GetDpiForMonitor (Windows 8.1) are looked up at run time,
+// like SetProcessDpiAwarenessContext; older systems scale everything from 96 DPI.
+unsafe fn window_dpi(hwnd: HWND) -> u32 {
+    let user32 = GetModuleHandleW(str_to_wchar_str("user32.dll").as_ptr());
+    if !user32.is_null() {
+        let proc = GetProcAddress(user32, b"GetDpiForWindow\0".as_ptr() as *const i8);
+        if !proc.is_null() {
+            let get_dpi_for_window: unsafe extern "system" fn(HWND) -> UINT = ::std::mem::transmute(proc);
+            let dpi = get_dpi_for_window(hwnd);
+            if dpi != 0 {
+                return dpi;
+            }
+        }
+    }
+    let shcore = LoadLibraryW(str_to_wchar_str("shcore.dll").as_ptr());
+    if !shcore.is_null() {
+        let proc = GetProcAddress(shcore, b"GetDpiForMonitor\0".as_ptr() as *const i8);
+        if !proc.is_null() {
+            let get_dpi_for_monitor: unsafe extern "system" fn(HMONITOR, INT, *mut UINT, *mut UINT) -> HRESULT = ::std::mem::transmute(proc);
+            // the monitor of the icon, which the hidden window need not be on
+            let monitor = match get_notification_area_icon_rect(hwnd) {
+                Ok(rect) => MonitorFromRect(&rect, MONITOR_DEFAULTTONEAREST),
+                Err(_) => MonitorFromWindow(hwnd, MONITOR_DEFAULTTOPRIMARY),
+            };
+            let (mut dpi_x, mut dpi_y) = (0, 0);
+            // MDT_EFFECTIVE_DPI
+            let result = get_dpi_for_monitor(monitor, 0, &mut dpi_x, &mut dpi_y);
+            FreeLibrary(shcore);
+            if result == S_OK {
+                return dpi_x;
+            }
+        } else {
+            FreeLibrary(shcore);
+        }
+    }
+    96
+}
+
+unsafe fn small_icon_size() -> i32 {
+    GetSystemMetrics(SM_CXSMICON)
+}
+
+fn small_icon_size_for_dpi(dpi: u32) -> i32 {
+    SMALL_ICON_SIZE_AT_96_DPI * dpi as i32 / 96
+}
+
+unsafe fn load_icon_from_file(file_name: &str, size: i32) -> Result<HICON> {
+    // LoadImageW fails with an obscure code for a missing file
+    if !Path::new(file_name).exists() {
+        bail!(ErrorKind::IconFileNotFound(file_name.to_string()));
+    }
+    let hicon = LoadImageW(
+        ptr::null_mut(),
+        str_to_wchar_str(file_name).as_ptr(),
+        IMAGE_ICON,
+        size,
+        size,
+        LR_LOADFROMFILE
+    ) as HICON;
+    if hicon.is_null() {
+        // read before the name is copied, which may overwrite it
+        let code = GetLastError();
+        bail!(ErrorKind::Win32(Op::LoadIconFromFile(file_name.to_string()), code));
+    }
+    Ok(hicon)
+}
+
+unsafe fn load_icon_from_resource_by_name(name: &str, size: i32) -> Result<HICON> {
+    let hmodule = GetModuleHandleW(ptr::null_mut());
+    if hmodule.is_null() {
+        bail!(last_error(Op::GetModuleHandle));
+    }
+    let hicon = LoadImageW(
+        hmodule,
+        str_to_wchar_str(name).as_ptr(),
+        IMAGE_ICON,
+        size,
+        size,
+        0
+    ) as HICON;
+    if hicon.is_null() {
+        bail!(last_error(Op::LoadIconFromResource));
+    }
+    Ok(hicon)
+}
+
+unsafe fn load_icon_from_resource_by_ord(ord: u16, size: i32) -> Result<HICON> {
+    let hmodule = GetModuleHandleW(ptr::null_mut());
+    if hmodule.is_null() {
+        bail!(last_error(Op::GetModuleHandle));
+    }
+    let hicon = LoadImageW(
+        hmodule,
+        MAKEINTRESOURCEW(ord),
+        IMAGE_ICON,
+        size,
+        size,
+        0
+    ) as HICON;
+    if hicon.is_null() {
+        bail!(last_error(Op::LoadIconFromResource));
+    }
+    Ok(hicon)
+}
+
+// The first icon of the executable, as Explorer shows it. ExtractIconExW only extracts
+// the small and the large icon sizes, and the shell scales whichever is closer.
+unsafe fn load_icon_from_self_exe(size: i32) -> Result<HICON> {
+    let mut path = vec![0u16; MAX_PATH];
+    loop {
+        let len = GetModuleFileNameW(ptr::null_mut(), path.as_mut_ptr(), path.len() as DWORD);
+        if len == 0 {
+            bail!(last_error(Op::GetModuleFileName));
+        }
+        if (len as usize) < path.len() {
+            path.truncate(len as usize);
+            path.push(0);
+            break;
+        }
+        // truncated
+        let longer = path.len() * 2;
+        path.resize(longer, 0);
+    }
+    let (mut large, mut small): (HICON, HICON) = (ptr::null_mut(), ptr::null_mut());
+    ExtractIconExW(path.as_ptr(), 0, &mut large, &mut small, 1);
+    let prefer_small = size != 0 && size <= GetSystemMetrics(SM_CXSMICON);
+    let (preferred, other) = if prefer_small { (small, large) } else { (large, small) };
+    if !preferred.is_null() {
+        if !other.is_null() {
+            DestroyIcon(other);
+        }
+        return Ok(preferred);
+    }
+    if !other.is_null() {
+        return Ok(other);
+    }
+    // the executable has no icon of its own; a copy of the system's, which is not shared
+    let hicon = LoadImageW(ptr::null_mut(), IDI_APPLICATION, IMAGE_ICON, size, size, 0) as HICON;
+    if hicon.is_null() {
+        bail!(last_error(Op::LoadIconFromResource));
+    }
+    Ok(hicon)
+}
+
+unsafe fn set_icon(hwnd: HWND, hicon: HICON) -> Result<()> {
+    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
+    data.uFlags = NIF_ICON;
+    data.hIcon = hicon;
+    if Shell_NotifyIconW(NIM_MODIFY, &mut data) == 0 {
+        bail!(last_error(Op::SetIcon));
+    }
+    Ok(())
+}
+
+// hiding the icon with NIF_STATE keeps it registered, so the shell
+// remembers the user's settings for it, unlike NIM_DELETE
+unsafe fn set_icon_hidden(hwnd: HWND, hidden: bool) -> Result<()> {
+    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
+    data.uFlags = NIF_STATE;
+    data.dwState = if hidden { NIS_HIDDEN } else { 0 };
+    data.dwStateMask = NIS_HIDDEN;
+    if Shell_NotifyIconW(NIM_MODIFY, &mut data) == 0 {
+        bail!(last_error(Op::SetIconState));
+    }
+    Ok(())
+}
+
+unsafe fn set_tip(hwnd: HWND, tip: &str, show_tip: bool) -> Result<()> {
+    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
+    data.uFlags = NIF_TIP;
+    if show_tip {
+        data.uFlags |= NIF_SHOWTIP;
+    }
+    copy_str_to_wchar_array(&mut data.szTip[..], tip);
+    if Shell_NotifyIconW(NIM_MODIFY, &mut data) == 0 {
+        bail!(last_error(Op::SetTip));
+    }
+    Ok(())
+}
+
+// Prepended items go to the top of the menu itself, whatever `WindowConfig::menu_max_items` says.
+unsafe fn insert_menu_entry(hmenu: HMENU, entry: MenuEntry, mode: MenuInsertMode, config: &WindowConfig, shared: &SharedState,
+                            owner_drawn_items: &mut HashMap<u32, OwnerDrawnItem>) -> Result<()> {
+    let prepend = mode == MenuInsertMode::Prepend;
+    let hmenu = match config.menu_max_items {
+        Some(max_items) if !prepend => overflow_target(hmenu, max_items, &config.more_items_label, config.right_to_left)?,
+        _ => hmenu,
+    };
+    let right_to_left = config.right_to_left;
+    match entry {
+        MenuEntry::Item(id, title) => add_menu_item(hmenu, id, &title, right_to_left, prepend),
+        MenuEntry::TriState(id, title, state) => {
+            add_menu_item(hmenu, id, &title, right_to_left, prepend)?;
+            set_menu_item_state(hmenu, id, state, shared)
+        }
+        MenuEntry::OwnerDrawn(id, data, measure, draw) => {
+            owner_drawn_items.insert(id, OwnerDrawnItem {
+                measure: Arc::new(measure),
+                draw: Arc::new(draw),
+            });
+            add_menu_owner_drawn(hmenu, id, data, prepend)
+        }
+        MenuEntry::Separator(id) => add_menu_separator(hmenu, id, prepend),
+    }
+}
+
+// Where InsertMenuItemW puts a new item: an id that is not in the menu yet appends it.
+fn insert_position(id: u32, prepend: bool) -> (UINT, BOOL) {
+    if prepend { (0, TRUE) } else { (id, FALSE) }
+}
+
+// The menu a new item goes to: `hmenu` while it has room, otherwise its overflow submenu,
+// which is added as its last item once it is full. A full overflow submenu gets one in turn.
+unsafe fn overflow_target(hmenu: HMENU, max_items: usize, label: &str, right_to_left: bool) -> Result<HMENU> {
+    if let Some(submenu) = overflow_submenu(hmenu) {
+        return overflow_target(submenu, max_items, label, right_to_left);
+    }
+    let count = GetMenuItemCount(hmenu);
+    if count == -1 {
+        bail!(last_error(Op::GetMenuItemCount));
+    }
+    if (count as usize) < max_items {
+        return Ok(hmenu);
+    }
+    let submenu = CreatePopupMenu();
+    if submenu.is_null() {
+        bail!(last_error(Op::CreatePopupMenu));
+    }
+    let mut label = str_to_wchar_str(label);
+    let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+    item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_SUBMENU | MIIM_DATA;
+    item.fType = MFT_STRING;
+    if right_to_left {
+        item.fType |= MFT_RIGHTORDER | MFT_RIGHTJUSTIFY;
+    }
+    item.hSubMenu = submenu;
+    item.dwItemData = OVERFLOW_MENU_DATA;
+    item.dwTypeData = label.as_mut_ptr();
+    if InsertMenuItemW(hmenu, count as UINT, TRUE, &item) == 0 {
+        DestroyMenu(submenu);
+        bail!(last_error(Op::AddOverflowSubmenu));
+    }
+    Ok(submenu)
+}
+
+unsafe fn overflow_submenu(hmenu: HMENU) -> Option<HMENU> {
+    let count = GetMenuItemCount(hmenu);
+    if count <= 0 {
+        return None;
+    }
+    let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+    item.fMask = MIIM_SUBMENU | MIIM_DATA;
+    if GetMenuItemInfoW(hmenu, count as UINT - 1, TRUE, &mut item) == 0 {
+        return None;
+    }
+    if !item.hSubMenu.is_null() && item.dwItemData == OVERFLOW_MENU_DATA {
+        Some(item.hSubMenu)
+    } else {
+        None
+    }
+}
+
+// the items in the overflow submenu and the ones nested in it, not counting the nested submenu itself
+#[cfg(feature = "balloons")]
+unsafe fn overflow_item_count(hmenu: HMENU) -> usize {
+    match overflow_submenu(hmenu) {
+        Some(submenu) => {
+            let count = ::std::cmp::max(GetMenuItemCount(submenu), 0) as usize;
+            match overflow_submenu(submenu) {
+                Some(_) => count - 1 + overflow_item_count(submenu),
+                None => count,
+            }
+        }
+        None => 0,
+    }
+}
+
+unsafe fn add_menu_item(hmenu: HMENU, id: u32, title: &str, right_to_left: bool, prepend: bool) -> Result<()> {
+    let mut title = str_to_wchar_str(title);
+    let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+    item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
+    item.fType = MFT_STRING;
+    if right_to_left {
+        item.fType |= MFT_RIGHTORDER | MFT_RIGHTJUSTIFY;
+    }
+    item.fState = 0;
+    item.wID = id;
+    item.dwTypeData = title.as_mut_ptr();
+    let (position, by_position) = insert_position(id, prepend);
+    if InsertMenuItemW(hmenu, position, by_position, &item) == 0 {
+        bail!(last_error(Op::AddMenuItem));
+    }
+    Ok(())
+}
+
+unsafe fn add_menu_owner_drawn(hmenu: HMENU, id: u32, data: usize, prepend: bool) -> Result<()> {
+    let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+    item.fMask = MIIM_FTYPE | MIIM_ID | MIIM_DATA;
+    item.fType = MFT_OWNERDRAW;
+    item.wID = id;
+    item.dwItemData = data;
+    let (position, by_position) = insert_position(id, prepend);
+    if InsertMenuItemW(hmenu, position, by_position, &item) == 0 {
+        bail!(last_error(Op::AddOwnerDrawnMenuItem));
+    }
+    Ok(())
+}
+
+unsafe fn add_menu_separator(hmenu: HMENU, id: u32, prepend: bool) -> Result<()> {
+    let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+    item.fMask = MIIM_FTYPE | MIIM_ID;
+    item.fType = MFT_SEPARATOR;
+    item.wID = id;
+    let (position, by_position) = insert_position(id, prepend);
+    if InsertMenuItemW(hmenu, position, by_position, &item) == 0 {
+        bail!(last_error(Op::AddMenuSeparator));
+    }
+    Ok(())
+}
+
+// The menu that holds the item `id`, `hmenu` or one of its overflow submenus, and its position there.
+unsafe fn find_menu_item_position(hmenu: HMENU, id: u32) -> Option<(HMENU, UINT)> {
+    let count = ::std::cmp::max(GetMenuItemCount(hmenu), 0);
+    match (0..count).find(|&position| GetMenuItemID(hmenu, position) == id) {
+        Some(position) => Some((hmenu, position as UINT)),
+        None => overflow_submenu(hmenu).and_then(|submenu| find_menu_item_position(submenu, id)),
+    }
+}
+
+unsafe fn insert_menu_separator_after(hmenu: HMENU, after: u32, id: u32) -> Result<()> {
+    let (hmenu, position) = match find_menu_item_position(hmenu, after) {
+        Some(found) => found,
+        None => bail!(ErrorKind::MenuItemNotFound(after)),
+    };
+    let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+    item.fMask = MIIM_FTYPE | MIIM_ID;
+    item.fType = MFT_SEPARATOR;
+    item.wID = id;
+    if InsertMenuItemW(hmenu, position + 1, TRUE, &item) == 0 {
+        bail!(last_error(Op::AddMenuSeparator));
+    }
+    Ok(())
+}
+
+unsafe fn find_menu_item(hmenu: HMENU, label: &str) -> Result<Option<u32>> {
+    let count = GetMenuItemCount(hmenu);
+    if count == -1 {
+        bail!(last_error(Op::GetMenuItemCount));
+    }
+    let mut buffer = [0u16; 256];
+    for position in 0..count as UINT {
+        let len = GetMenuStringW(hmenu, position, buffer.as_mut_ptr(), buffer.len() as i32, MF_BYPOSITION);
+        if len <= 0 {
+            // separators and owner-drawn items have no text
+            continue;
         }
-        TranslateMessage(&mut msg);
-        DispatchMessageW(&mut msg);
-        result = GetMessageW(&mut msg, ptr::null_mut(), 0, 0);
+        let text = String::from_utf16_lossy(&buffer[0..len as usize]);
+        if unescape_mnemonics(&text) == label {
+            return Ok(Some(GetMenuItemID(hmenu, position as i32)));
+        }
+    }
+    match overflow_submenu(hmenu) {
+        Some(submenu) => find_menu_item(submenu, label),
+        None => Ok(None),
     }
 }
 
-unsafe fn load_icon_from_file(file_name: &str) -> Result<HICON> {
-    let hicon = LoadImageW(
-        ptr::null_mut(),
-        str_to_wchar_str(file_name).as_ptr(),
-        IMAGE_ICON,
-        0,
-        0,
-        LR_LOADFROMFILE
-    ) as HICON;
-    if hicon.is_null() {
-        bail!("Error loading icon from file: {}", GetLastError());
+// "&File" is shown as "File" with an underlined "F"; "&&" is a literal ampersand
+fn unescape_mnemonics(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '&' {
+            if let Some(next) = chars.next() {
+                result.push(next);
+            }
+        } else {
+            result.push(c);
+        }
     }
-    Ok(hicon)
+    result
 }
 
-unsafe fn load_icon_from_resource_by_name(name: &str) -> Result<HICON> {
-    let hmodule = GetModuleHandleW(ptr::null_mut());
-    if hmodule.is_null() {
-        bail!("Error getting current module handle: {}", GetLastError());
+unsafe fn set_menu_item_state(hmenu: HMENU, id: u32, state: MenuItemState, shared: &SharedState) -> Result<()> {
+    let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+    item.fMask = MIIM_STATE;
+    if GetMenuItemInfoW(hmenu, id, 0, &mut item) == 0 {
+        bail!(last_error(Op::GetMenuItemInfo));
     }
-    let hicon = LoadImageW(
-        hmodule,
-        str_to_wchar_str(name).as_ptr(),
-        IMAGE_ICON,
-        0,
-        0,
-        0
-    ) as HICON;
-    if hicon.is_null() {
-        bail!("Error loading icon from resource: {}", GetLastError());
+    item.fMask = MIIM_STATE | MIIM_CHECKMARKS;
+    item.fState &= !MFS_CHECKED;
+    // a null checked bitmap stands for the default checkmark
+    let (checked, unchecked) = shared.check_bitmaps(id);
+    item.hbmpChecked = checked;
+    item.hbmpUnchecked = unchecked;
+    match state {
+        MenuItemState::Checked => {
+            item.fState |= MFS_CHECKED;
+        }
+        MenuItemState::Unchecked => { }
+        MenuItemState::Indeterminate => {
+            item.fState |= MFS_CHECKED;
+            item.hbmpChecked = shared.indeterminate_bitmap()?;
+        }
     }
-    Ok(hicon)
+    if SetMenuItemInfoW(hmenu, id, 0, &item) == 0 {
+        bail!(last_error(Op::SetMenuItemState));
+    }
+    Ok(())
 }
 
-unsafe fn load_icon_from_resource_by_ord(ord: u16) -> Result<HICON> {
-    let hmodule = GetModuleHandleW(ptr::null_mut());
-    if hmodule.is_null() {
-        bail!("Error getting current module handle: {}", GetLastError());
+// popup menus have no native tri-state checkmark, so the indeterminate state
+// is shown as a checked item with a dash in place of the checkmark
+unsafe fn create_indeterminate_bitmap() -> Result<HBITMAP> {
+    let width = GetSystemMetrics(SM_CXMENUCHECK);
+    let height = GetSystemMetrics(SM_CYMENUCHECK);
+    let hdc = CreateCompatibleDC(ptr::null_mut());
+    if hdc.is_null() {
+        bail!(last_error(Op::CreateDeviceContext));
     }
-    let hicon = LoadImageW(
-        hmodule,
-        MAKEINTRESOURCEW(ord),
-        IMAGE_ICON,
-        0,
-        0,
-        0
-    ) as HICON;
-    if hicon.is_null() {
-        bail!("Error loading icon from resource: {}", GetLastError());
+    let bitmap = CreateBitmap(width, height, 1, 1, ptr::null());
+    if bitmap.is_null() {
+        let e = last_error(Op::CreateBitmap);
+        DeleteDC(hdc);
+        bail!(e);
     }
-    Ok(hicon)
+    let old = SelectObject(hdc, bitmap as HGDIOBJ);
+    PatBlt(hdc, 0, 0, width, height, WHITENESS);
+    PatBlt(hdc, width / 4, height / 2 - 1, width / 2, 2, BLACKNESS);
+    SelectObject(hdc, old);
+    DeleteDC(hdc);
+    Ok(bitmap)
 }
 
-unsafe fn set_icon(hwnd: HWND, hicon: HICON) -> Result<()> {
-    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
-    data.uFlags = NIF_ICON;
-    data.hIcon = hicon;
-    if Shell_NotifyIconW(NIM_MODIFY, &mut data) == 0 {
-        bail!("Error setting taskbar icon: {}", GetLastError());
+// an indeterminate item keeps its dash as the checked bitmap
+unsafe fn apply_check_bitmaps(hmenu: HMENU, id: u32, shared: &SharedState) -> Result<()> {
+    let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+    item.fMask = MIIM_CHECKMARKS;
+    if GetMenuItemInfoW(hmenu, id, 0, &mut item) == 0 {
+        bail!(last_error(Op::GetMenuItemInfo));
+    }
+    let indeterminate = shared.indeterminate_bitmap.lock().unwrap().as_ref().map(|bitmap| bitmap.0);
+    let (checked, unchecked) = shared.check_bitmaps(id);
+    if indeterminate != Some(item.hbmpChecked) {
+        item.hbmpChecked = checked;
+    }
+    item.hbmpUnchecked = unchecked;
+    if SetMenuItemInfoW(hmenu, id, 0, &item) == 0 {
+        bail!(last_error(Op::SetMenuItemState));
     }
     Ok(())
 }
 
-unsafe fn set_tip(hwnd: HWND, tip: &str) -> Result<()> {
-    let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
-    data.uFlags = NIF_TIP;
-    copy_str_to_wchar_array(&mut data.szTip[..], tip);
-    if Shell_NotifyIconW(NIM_MODIFY, &mut data) == 0 {
-        bail!("Error setting taskbar icon tooltip: {}", GetLastError());
+// The icon is drawn on a 32-bit bitmap, so that themed menus keep its transparency.
+unsafe fn create_check_bitmap(icon: &Icon) -> Result<HBITMAP> {
+    let width = GetSystemMetrics(SM_CXMENUCHECK);
+    let height = GetSystemMetrics(SM_CYMENUCHECK);
+    let hicon = load_icon(icon, width)?;
+    let hdc = CreateCompatibleDC(ptr::null_mut());
+    if hdc.is_null() {
+        let e = last_error(Op::CreateDeviceContext);
+        destroy_loaded_icon(icon, hicon);
+        bail!(e);
     }
-    Ok(())
+    let mut info: BITMAPINFO = ::std::mem::zeroed();
+    info.bmiHeader.biSize = ::std::mem::size_of::<BITMAPINFOHEADER>() as DWORD;
+    info.bmiHeader.biWidth = width;
+    // top-down
+    info.bmiHeader.biHeight = -height;
+    info.bmiHeader.biPlanes = 1;
+    info.bmiHeader.biBitCount = 32;
+    info.bmiHeader.biCompression = BI_RGB;
+    let mut bits = ptr::null_mut();
+    let bitmap = CreateDIBSection(hdc, &info, DIB_RGB_COLORS, &mut bits, ptr::null_mut(), 0);
+    if bitmap.is_null() {
+        let e = last_error(Op::CreateBitmap);
+        DeleteDC(hdc);
+        destroy_loaded_icon(icon, hicon);
+        bail!(e);
+    }
+    let old = SelectObject(hdc, bitmap as HGDIOBJ);
+    DrawIconEx(hdc, 0, 0, hicon, width, height, 0, ptr::null_mut(), DI_NORMAL);
+    SelectObject(hdc, old);
+    DeleteDC(hdc);
+    destroy_loaded_icon(icon, hicon);
+    Ok(bitmap)
 }
 
-unsafe fn add_menu_item(hmenu: HMENU, id: u32, title: &str) -> Result<()> {
-    let mut title = str_to_wchar_str(title);
-    let mut item: MENUITEMINFOW = ::std::mem::uninitialized();
-    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
-    item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
-    item.fType = MFT_STRING;
-    item.fState = 0;
-    item.wID = id;
-    item.dwTypeData = title.as_mut_ptr();
-    if InsertMenuItemW(hmenu, id, 0, &mut item) == 0 {
-        bail!("Error adding menu item: {}", GetLastError());
+// for icons loaded only to be drawn; handles passed in by the application stay theirs
+unsafe fn destroy_loaded_icon(icon: &Icon, hicon: HICON) {
+    match icon {
+        Icon::Hicon(_) | Icon::OwnedHicon(_) => { }
+        _ => { DestroyIcon(hicon); }
+    }
+}
+
+unsafe fn set_menu_item_enabled(hmenu: HMENU, id: u32, enabled: bool) -> Result<()> {
+    let flags = if enabled { MF_ENABLED } else { MF_GRAYED };
+    if EnableMenuItem(hmenu, id, MF_BYCOMMAND | flags) == -1 {
+        bail!(ErrorKind::MenuItemNotFound(id));
     }
     Ok(())
 }
 
-unsafe fn add_menu_separator(hmenu: HMENU, id: u32) -> Result<()> {
-    let mut item: MENUITEMINFOW = ::std::mem::uninitialized();
-    item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
-    item.fMask = MIIM_FTYPE | MIIM_ID;
-    item.fType = MFT_SEPARATOR;
-    item.wID = id;
-    if InsertMenuItemW(hmenu, id, 0, &mut item) == 0 {
-        bail!("Error adding menu separator: {}", GetLastError());
+unsafe fn menu_item_ids(hmenu: HMENU) -> Result<Vec<u32>> {
+    let count = GetMenuItemCount(hmenu);
+    if count == -1 {
+        bail!(last_error(Op::GetMenuItemCount));
+    }
+    // submenus have no id of their own
+    let mut ids: Vec<u32> = (0..count).map(|position| GetMenuItemID(hmenu, position)).filter(|&id| id != !0).collect();
+    if let Some(submenu) = overflow_submenu(hmenu) {
+        ids.extend(menu_item_ids(submenu)?);
+    }
+    Ok(ids)
+}
+
+unsafe fn menu_snapshot(hmenu: HMENU) -> Result<Vec<MenuItemInfo>> {
+    let count = GetMenuItemCount(hmenu);
+    if count == -1 {
+        bail!(last_error(Op::GetMenuItemCount));
+    }
+    let mut items = Vec::with_capacity(count as usize);
+    for position in 0..count as UINT {
+        let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+        item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+        item.fMask = MIIM_FTYPE | MIIM_STATE | MIIM_ID | MIIM_SUBMENU | MIIM_STRING;
+        // the first call only reports the label length
+        if GetMenuItemInfoW(hmenu, position, TRUE, &mut item) == 0 {
+            bail!(last_error(Op::GetMenuItemInfo));
+        }
+        let mut label = String::new();
+        if item.cch > 0 {
+            let mut buffer = vec![0u16; item.cch as usize + 1];
+            item.dwTypeData = buffer.as_mut_ptr();
+            item.cch += 1;
+            if GetMenuItemInfoW(hmenu, position, TRUE, &mut item) == 0 {
+                bail!(last_error(Op::GetMenuItemInfo));
+            }
+            label = String::from_utf16_lossy(&buffer[0..item.cch as usize]);
+        }
+        let kind = if !item.hSubMenu.is_null() {
+            MenuItemKind::Submenu(menu_snapshot(item.hSubMenu)?)
+        } else if item.fType & MFT_SEPARATOR != 0 {
+            MenuItemKind::Separator
+        } else if item.fType & MFT_OWNERDRAW != 0 {
+            MenuItemKind::OwnerDrawn
+        } else {
+            MenuItemKind::Item
+        };
+        items.push(MenuItemInfo {
+            id: item.wID,
+            label,
+            kind,
+            enabled: item.fState & MFS_DISABLED == 0,
+            checked: item.fState & MFS_CHECKED != 0,
+        });
+    }
+    Ok(items)
+}
+
+// flashes until the window comes to the foreground
+unsafe fn flash_window(hwnd: HWND) {
+    let mut info = FLASHWINFO {
+        cbSize: ::std::mem::size_of::<FLASHWINFO>() as UINT,
+        hwnd,
+        dwFlags: FLASHW_ALL | FLASHW_TIMERNOFG,
+        uCount: 0,
+        dwTimeout: 0,
+    };
+    FlashWindowEx(&mut info);
+}
+
+// The shell sends NIN_BALLOONTIMEOUT when the balloon times out or the user closes it,
+// and NIN_BALLOONHIDE when it goes away otherwise; only the former shows it again.
+#[cfg(feature = "balloons")]
+fn balloon_gone_event(notification: UINT) -> Event {
+    if notification == NIN_BALLOONTIMEOUT {
+        Event::BalloonDismissed
+    } else {
+        Event::BalloonHidden
+    }
+}
+
+// Hides the main window while it is shown, and shows it otherwise.
+unsafe fn toggle_main_window(hwnd: HWND) {
+    if IsWindowVisible(hwnd) != 0 && IsIconic(hwnd) == 0 {
+        ShowWindow(hwnd, SW_HIDE);
+    } else {
+        activate_window(hwnd);
+    }
+}
+
+unsafe fn install_focus_hook() {
+    let installed = WINDOW_LOOP_DATA.with(|data| {
+        data.borrow_mut().as_mut().map(|data| {
+            if data.focus_hook.is_none() {
+                let hook = SetWinEventHook(EVENT_SYSTEM_FOREGROUND, EVENT_SYSTEM_FOREGROUND, ptr::null_mut(),
+                                           Some(foreground_changed), 0, 0, WINEVENT_OUTOFCONTEXT);
+                if hook.is_null() {
+                    return false;
+                }
+                data.focus_hook = Some(hook);
+            }
+            true
+        })
+    });
+    if installed == Some(false) {
+        report_error(last_error(Op::SetFocusHook).into());
+    }
+}
+
+unsafe fn remove_focus_hook() {
+    let hook = WINDOW_LOOP_DATA.with(|data| data.borrow_mut().as_mut().and_then(|data| data.focus_hook.take()));
+    if let Some(hook) = hook {
+        UnhookWinEvent(hook);
+    }
+}
+
+// Runs on the window thread, which installed the hook, for every window that comes
+// to the foreground. The hook stays when the main window is unbound, and does nothing.
+unsafe extern "system" fn foreground_changed(_hook: HWINEVENTHOOK, _event: DWORD, hwnd: HWND, _object: LONG,
+                                             _child: LONG, _thread: DWORD, _time: DWORD) {
+    let windows = WINDOW_LOOP_DATA.with(|data| {
+        data.try_borrow().ok().and_then(|data| data.as_ref().and_then(|data| {
+            let main_window = *data.shared.main_window.lock().unwrap_or_else(PoisonError::into_inner);
+            main_window.filter(|main_window| main_window.hide_on_focus_loss)
+                .map(|main_window| (main_window.window.0, data.handle.hwnd))
+        }))
+    });
+    if let Some((main_window, tray_window)) = windows {
+        if loses_focus_to(main_window, tray_window, hwnd) {
+            ShowWindow(main_window, SW_HIDE);
+        }
+    }
+}
+
+// Windows owned by the main window, such as its dialogs, keep it, and so do the tray
+// window, which takes the foreground for the menu, and the taskbar, which takes it for
+// a click on the icon; that click then toggles the main window itself.
+unsafe fn loses_focus_to(main_window: HWND, tray_window: HWND, foreground: HWND) -> bool {
+    if foreground.is_null() || IsWindowVisible(main_window) == 0 {
+        return false;
+    }
+    let root = GetAncestor(foreground, GA_ROOTOWNER);
+    root != main_window && root != tray_window && !is_taskbar(root)
+}
+
+unsafe fn is_taskbar(hwnd: HWND) -> bool {
+    let mut class = [0u16; 64];
+    let len = GetClassNameW(hwnd, class.as_mut_ptr(), class.len() as INT);
+    let class = String::from_utf16_lossy(&class[..len.max(0) as usize]);
+    ["Shell_TrayWnd", "Shell_SecondaryTrayWnd", "NotifyIconOverflowWindow", "TopLevelWindowForOverflowXamlIsland"]
+        .contains(&class.as_str())
+}
+
+// Restores `hwnd` and brings it to the foreground. Attaching to the input of the
+// foreground thread lets SetForegroundWindow through the foreground lock.
+unsafe fn activate_window(hwnd: HWND) {
+    if IsIconic(hwnd) != 0 || IsWindowVisible(hwnd) == 0 {
+        ShowWindow(hwnd, SW_RESTORE);
+    }
+    let foreground_thread = GetWindowThreadProcessId(GetForegroundWindow(), ptr::null_mut());
+    let current_thread = GetCurrentThreadId();
+    let attached = foreground_thread != 0 && foreground_thread != current_thread
+        && AttachThreadInput(current_thread, foreground_thread, TRUE) != 0;
+    SetForegroundWindow(hwnd);
+    if attached {
+        AttachThreadInput(current_thread, foreground_thread, FALSE);
+    }
+}
+
+unsafe fn remove_menu_item(hmenu: HMENU, id: u32) -> Result<()> {
+    if DeleteMenu(hmenu, id, MF_BYCOMMAND) == 0 {
+        bail!(last_error(Op::RemoveMenuItem));
     }
     Ok(())
 }
 
-unsafe fn show_balloon(hwnd: HWND, title: &str, body: &str) -> Result<()> {
+#[cfg(feature = "balloons")]
+// With a `tip` and whether to show it, the tooltip is set as well.
+unsafe fn show_balloon(hwnd: HWND, title: &str, body: &str, options: &NotificationOptions, tip: Option<(&str, bool)>) -> Result<()> {
     let mut data: NOTIFYICONDATAW = make_notify_icon_data(hwnd);
     data.uFlags = NIF_INFO;
+    if let Some((tip, show_tip)) = tip {
+        data.uFlags |= NIF_TIP;
+        if show_tip {
+            data.uFlags |= NIF_SHOWTIP;
+        }
+        copy_str_to_wchar_array(&mut data.szTip[..], tip);
+    }
     copy_str_to_wchar_array(&mut data.szInfo[..], body);
-    *data.u.uTimeout_mut() = 30000;
+    let timeout = options.timeout.unwrap_or(Duration::from_secs(30));
+    *data.u.uTimeout_mut() = duration_to_millis(timeout);
     copy_str_to_wchar_array(&mut data.szInfoTitle[..], title);
-    data.dwInfoFlags = NIIF_INFO;
-    if Shell_NotifyIconW(NIM_MODIFY, &mut data) == 0 {
-        bail!("Error setting taskbar icon balloon: {}", GetLastError());
+    data.dwInfoFlags = match options.level.unwrap_or(NotificationLevel::Info) {
+        NotificationLevel::None => NIIF_NONE,
+        NotificationLevel::Info => NIIF_INFO,
+        NotificationLevel::Warning => NIIF_WARNING,
+        NotificationLevel::Error => NIIF_ERROR,
+    };
+    // the shell copies the balloon icon, so it is only needed for the call
+    let icon = match options.icon {
+        Some(ref icon) => {
+            let size = GetSystemMetrics(SM_CXICON);
+            Some(InstalledIcon {
+                icon: icon.clone(),
+                hicon: load_icon(icon, size)?,
+                cached: false,
+            })
+        }
+        None => None,
+    };
+    if let Some(ref icon) = icon {
+        data.dwInfoFlags = NIIF_USER | NIIF_LARGE_ICON;
+        data.hBalloonIcon = icon.hicon;
+    }
+    if options.sound.is_some() {
+        data.dwInfoFlags |= NIIF_NOSOUND;
+    }
+    let result = Shell_NotifyIconW(NIM_MODIFY, &mut data);
+    if let Some(ref icon) = icon {
+        destroy_icon(icon);
+    }
+    if result == 0 {
+        bail!(last_error(Op::ShowBalloon));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "balloons")]
+unsafe fn play_sound(file_name: &OsStr) -> Result<()> {
+    let mut file_name: Vec<u16> = file_name.encode_wide().collect();
+    file_name.push(0);
+    if PlaySoundW(file_name.as_ptr(), ptr::null_mut(), SND_FILENAME | SND_ASYNC) == 0 {
+        bail!(last_error(Op::PlaySound));
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use winapi::um::processthreadsapi::GetCurrentProcess;
+
+    // missing from winapi
+    const GR_USEROBJECTS: DWORD = 1;
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn GetGuiResources(process: HANDLE, flags: DWORD) -> DWORD;
+    }
+
+    fn config() -> WindowConfig {
+        WindowConfig {
+            show_tip: true,
+            right_to_left: false,
+            ex_style: WS_EX_TOOLWINDOW,
+            message_only: false,
+            class_icon: None,
+            restore_focus: true,
+            update_debounce: None,
+            creation_timeout: Duration::from_secs(10),
+            thread_name: "wna-test-window-loop".to_string(),
+            thread_priority: None,
+            menu_hover_delay: None,
+            last_panic: Arc::new(Mutex::new(None)),
+            menu_max_items: None,
+            more_items_label: "More".to_string(),
+            left_click_handled: false,
+            right_click_handled: false,
+            #[cfg(feature = "balloons")]
+            overflow_balloon: false,
+            idle_throttle: None,
+        }
+    }
+
+    fn create(class: &str, config: WindowConfig) -> Window {
+        let (event_sender, _) = channel();
+        let (error_sender, _) = channel();
+        Window::create(class, config, event_sender, error_sender).unwrap()
+    }
+
+    fn is_top_level(window: &Window) -> bool {
+        let hwnd = window.handle.as_ref().unwrap().hwnd;
+        unsafe { GetAncestor(hwnd, GA_PARENT) == GetDesktopWindow() }
+    }
+
+    // a copy of the system's icon, as the shared one must not be destroyed
+    fn owned_icon() -> Icon {
+        unsafe { Icon::owned(CopyIcon(LoadIconW(ptr::null_mut(), IDI_APPLICATION))) }
+    }
+
+    fn is_icon(hicon: HICON) -> bool {
+        unsafe {
+            let mut info: ICONINFO = ::std::mem::zeroed();
+            let valid = GetIconInfo(hicon, &mut info) != 0;
+            if valid {
+                DeleteObject(info.hbmColor as HGDIOBJ);
+                DeleteObject(info.hbmMask as HGDIOBJ);
+            }
+            valid
+        }
+    }
+
+    // a plain window standing in for the main window of the application
+    fn app_window(owner: HWND) -> HWND {
+        let class = str_to_wchar_str("STATIC");
+        unsafe {
+            CreateWindowExW(0, class.as_ptr(), ptr::null(), WS_OVERLAPPEDWINDOW | WS_VISIBLE,
+                            0, 0, 200, 100, owner, ptr::null_mut(), GetModuleHandleW(ptr::null()), ptr::null_mut())
+        }
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn only_a_balloon_timeout_counts_as_dismissed() {
+        assert_eq!(balloon_gone_event(NIN_BALLOONTIMEOUT), Event::BalloonDismissed);
+        assert_eq!(balloon_gone_event(NIN_BALLOONHIDE), Event::BalloonHidden);
+    }
+
+    #[test]
+    fn idle_fires_when_the_queue_drains_no_more_often_than_the_minimum_throttle() {
+        let hwnd = app_window(ptr::null_mut());
+        let (event_sender, events) = channel();
+        let (error_sender, _) = channel();
+        let mut config = config();
+        config.idle_throttle = Some(Duration::from_millis(0));
+        let mut window = Window::attach(hwnd, config, event_sender, error_sender).unwrap();
+        let idle_events = || events.try_iter().filter(|event| *event == Event::Idle).count();
+        let start = Instant::now();
+        while start.elapsed() < MIN_IDLE_THROTTLE / 2 {
+            pump_once();
+        }
+        assert_eq!(idle_events(), 1);
+        thread::sleep(MIN_IDLE_THROTTLE);
+        for _ in 0..100 {
+            pump_once();
+        }
+        assert_eq!(idle_events(), 1);
+        window.close();
+        unsafe { DestroyWindow(hwnd); }
+    }
+
+    #[test]
+    fn click_restores_a_minimized_main_window_and_hides_a_shown_one() {
+        let hwnd = app_window(ptr::null_mut());
+        unsafe {
+            ShowWindow(hwnd, SW_MINIMIZE);
+            toggle_main_window(hwnd);
+            assert_eq!(IsIconic(hwnd), 0);
+            assert_ne!(IsWindowVisible(hwnd), 0);
+            toggle_main_window(hwnd);
+            assert_eq!(IsWindowVisible(hwnd), 0);
+            toggle_main_window(hwnd);
+            assert_ne!(IsWindowVisible(hwnd), 0);
+            DestroyWindow(hwnd);
+        }
+    }
+
+    #[test]
+    fn main_window_only_loses_focus_to_unrelated_windows() {
+        let mut window = create("wna_test_focus_loss", config());
+        let tray = window.handle.as_ref().unwrap().hwnd;
+        let main_window = app_window(ptr::null_mut());
+        let dialog = app_window(main_window);
+        let other = app_window(ptr::null_mut());
+        unsafe {
+            assert!(loses_focus_to(main_window, tray, other));
+            assert!(!loses_focus_to(main_window, tray, dialog));
+            assert!(!loses_focus_to(main_window, tray, tray));
+            assert!(!loses_focus_to(main_window, tray, main_window));
+            ShowWindow(main_window, SW_HIDE);
+            assert!(!loses_focus_to(main_window, tray, other));
+            DestroyWindow(other);
+            DestroyWindow(main_window);
+        }
+        window.close();
+    }
+
+    #[test]
+    fn stopped_animation_shows_the_static_icon_before_destroying_frames() {
+        let (event_sender, _) = channel();
+        let (error_sender, errors) = channel();
+        let mut window = Window::create("wna_test_animation", config(), event_sender, error_sender).unwrap();
+        window.set_icon(&owned_icon()).unwrap();
+        let frame = owned_icon();
+        let hicon = match frame {
+            Icon::OwnedHicon(ref handle) => handle.hicon(),
+            _ => unreachable!(),
+        };
+        let id = window.start_animation(vec![frame], Duration::from_secs(60)).unwrap();
+        assert!(is_icon(hicon));
+        window.stop_animation(id).unwrap();
+        assert!(!is_icon(hicon));
+        window.close();
+        assert!(errors.try_recv().is_err());
+    }
+
+    #[test]
+    fn window_thread_serves_calls_while_waiting_for_a_lock() {
+        let mut window = create("wna_test_lock", config());
+        let shared = Arc::new(Mutex::new(0));
+        let guard = shared.lock().unwrap();
+        let (sender, receiver) = channel();
+        let window_shared = Arc::clone(&shared);
+        window.run_on_thread(Box::new(move || {
+            *lock(&window_shared) += 1;
+            let _ = sender.send(());
+        })).unwrap();
+        // waits for the window thread, which is itself waiting for `guard`
+        window.set_tip("tip").unwrap();
+        drop(guard);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(*shared.lock().unwrap(), 1);
+        window.close();
+    }
+
+    #[test]
+    fn durations_are_clamped_to_the_timer_maximum() {
+        assert_eq!(duration_to_millis(Duration::from_millis(1500)), 1500);
+        assert_eq!(duration_to_millis(Duration::new(5_000_000, 999_999_999)), USER_TIMER_MAXIMUM);
+        assert_eq!(duration_to_millis(Duration::new(u64::MAX, 0)), USER_TIMER_MAXIMUM);
+    }
+
+    #[test]
+    fn separator_goes_after_an_item_in_the_overflow_submenu() {
+        let mut config = config();
+        config.menu_max_items = Some(2);
+        let mut window = create("wna_test_overflow", config);
+        for id in 0..4 {
+            window.add_menu_entry(MenuEntry::Item(id, format!("Item {}", id)), MenuInsertMode::Append).unwrap();
+        }
+        window.insert_separator_after(3, 4).unwrap();
+        let menu = window.menu_snapshot().unwrap();
+        let overflow: Vec<(u32, MenuItemKind)> = match menu[2].kind {
+            MenuItemKind::Submenu(ref children) => children.iter().map(|item| (item.id, item.kind.clone())).collect(),
+            _ => panic!("no overflow submenu"),
+        };
+        assert_eq!(menu[0].id, 0);
+        assert_eq!(overflow, vec![(2, MenuItemKind::Item), (3, MenuItemKind::Item), (4, MenuItemKind::Separator)]);
+        window.close();
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn only_items_past_the_maximum_count_as_overflow() {
+        let mut config = config();
+        config.menu_max_items = Some(2);
+        let mut window = create("wna_test_overflow_count", config);
+        let hmenu = window.hmenu().unwrap();
+        let mut counts = Vec::new();
+        for id in 0..5 {
+            window.add_menu_entry(MenuEntry::Item(id, format!("Item {}", id)), MenuInsertMode::Append).unwrap();
+            counts.push(unsafe { overflow_item_count(hmenu) });
+        }
+        assert_eq!(counts, vec![0, 0, 1, 2, 3]);
+        window.close();
+    }
+
+    #[test]
+    fn hidden_window_is_top_level_by_default() {
+        let mut window = create("wna_test_top_level", config());
+        assert!(is_top_level(&window));
+        window.close();
+    }
+
+    #[test]
+    fn message_only_window_has_no_desktop_parent() {
+        let mut config = config();
+        config.message_only = true;
+        let mut window = create("wna_test_message_only", config);
+        assert!(!is_top_level(&window));
+        window.close();
+    }
+
+    #[test]
+    fn menu_items_are_found_by_label_without_mnemonics() {
+        let mut window = create("wna_test_find_item", config());
+        window.add_menu_entry(MenuEntry::Item(0, "&Open".to_string()), MenuInsertMode::Append).unwrap();
+        window.add_menu_entry(MenuEntry::Item(1, "Save && Quit".to_string()), MenuInsertMode::Append).unwrap();
+        window.add_menu_entry(MenuEntry::Item(2, "Save && Quit".to_string()), MenuInsertMode::Append).unwrap();
+        assert_eq!(window.find_menu_item("Open").unwrap(), Some(0));
+        assert_eq!(window.find_menu_item("Save & Quit").unwrap(), Some(1));
+        assert_eq!(window.find_menu_item("Close").unwrap(), None);
+        window.close();
+    }
+
+    #[test]
+    fn window_class_gets_the_builder_icon_and_the_arrow_cursor() {
+        let hicon = unsafe { LoadIconW(ptr::null_mut(), IDI_WARNING) };
+        let mut config = config();
+        config.class_icon = Some(Icon::Hicon(hicon));
+        let mut window = create("wna_test_class_icon", config);
+        let hwnd = window.handle.as_ref().unwrap().hwnd;
+        unsafe {
+            assert_eq!(GetClassLongPtrW(hwnd, GCLP_HICON) as HICON, hicon);
+            assert_eq!(GetClassLongPtrW(hwnd, GCLP_HCURSOR) as HCURSOR, LoadCursorW(ptr::null_mut(), IDC_ARROW));
+        }
+        window.close();
+    }
+
+    #[test]
+    fn menus_are_freed_when_the_window_closes() {
+        let cycle = || {
+            let mut window = create("wna_test_menu_handles", config());
+            for id in 0..10 {
+                window.add_menu_entry(MenuEntry::Item(id, format!("Item {}", id)), MenuInsertMode::Append).unwrap();
+            }
+            window.close();
+        };
+        let user_objects = || unsafe { GetGuiResources(GetCurrentProcess(), GR_USEROBJECTS) };
+        // the first window registers the class and loads what stays for the process
+        cycle();
+        let before = user_objects();
+        for _ in 0..20 {
+            cycle();
+        }
+        assert!(user_objects() <= before + 2);
+    }
+
+    fn checkmark(window: &Window, id: u32) -> (bool, HBITMAP) {
+        let hmenu = window.handle.as_ref().unwrap().hmenu;
+        unsafe {
+            let mut item: MENUITEMINFOW = ::std::mem::zeroed();
+            item.cbSize = ::std::mem::size_of::<MENUITEMINFOW>() as UINT;
+            item.fMask = MIIM_STATE | MIIM_CHECKMARKS;
+            assert_ne!(GetMenuItemInfoW(hmenu, id, 0, &mut item), 0);
+            (item.fState & MFS_CHECKED != 0, item.hbmpChecked)
+        }
+    }
+
+    #[test]
+    fn tri_state_item_cycles_through_all_three_states() {
+        let mut window = create("wna_test_tri_state", config());
+        window.add_menu_entry(MenuEntry::TriState(0, "Sync".to_string(), MenuItemState::Unchecked), MenuInsertMode::Append).unwrap();
+        assert_eq!(checkmark(&window, 0), (false, ptr::null_mut()));
+        window.set_menu_item_state(0, MenuItemState::Checked).unwrap();
+        assert_eq!(checkmark(&window, 0), (true, ptr::null_mut()));
+        window.set_menu_item_state(0, MenuItemState::Indeterminate).unwrap();
+        let (checked, dash) = checkmark(&window, 0);
+        assert!(checked && !dash.is_null());
+        window.set_menu_item_state(0, MenuItemState::Unchecked).unwrap();
+        assert_eq!(checkmark(&window, 0), (false, ptr::null_mut()));
+        window.close();
+    }
+
+    #[test]
+    fn borrowed_hicon_is_installed_and_left_alive() {
+        let hicon = unsafe { LoadIconW(ptr::null_mut(), IDI_APPLICATION) };
+        let mut window = create("wna_test_hicon", config());
+        window.set_icon(&Icon::Hicon(hicon)).unwrap();
+        window.set_icon(&owned_icon()).unwrap();
+        window.close();
+        assert!(is_icon(hicon));
+    }
+
+    #[test]
+    fn window_thread_not_reporting_back_in_time_is_a_timeout() {
+        let mut config = config();
+        // no window thread can report back this fast
+        config.creation_timeout = Duration::from_secs(0);
+        let (event_sender, _) = channel();
+        let (error_sender, _) = channel();
+        match Window::create("wna_test_timeout", config, event_sender, error_sender) {
+            Err(Error(ErrorKind::WindowCreationTimeout, _)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("window created within no time"),
+        }
+    }
+
+    #[test]
+    fn icon_and_tip_set_from_another_thread_are_installed_by_the_window_thread() {
+        let window = create("wna_test_cross_thread", config());
+        let mut window = thread::spawn(move || {
+            assert!(!window.is_window_thread());
+            window.set_icon(&owned_icon()).unwrap();
+            window.set_tip("Set elsewhere").unwrap();
+            window
+        }).join().unwrap();
+        assert!(window.shared.icon.lock().unwrap().is_some());
+        assert_eq!(*window.shared.tip.lock().unwrap(), Some("Set elsewhere".to_string()));
+        window.close();
+    }
+
+    #[test]
+    fn win32_window_serves_as_a_tray_backend() {
+        let mut backend: Box<dyn TrayBackend> = Box::new(create("wna_test_backend", config()));
+        backend.set_tip("tip").unwrap();
+        backend.add_menu_entry(MenuEntry::Item(0, "Open".to_string()), MenuInsertMode::Append).unwrap();
+        backend.add_menu_entry(MenuEntry::Separator(1), MenuInsertMode::Append).unwrap();
+        backend.add_menu_entry(MenuEntry::Item(2, "First".to_string()), MenuInsertMode::Prepend).unwrap();
+        assert_eq!(backend.menu_item_ids().unwrap(), vec![2, 0, 1]);
+        assert_eq!(backend.menu_snapshot().unwrap()[2].kind, MenuItemKind::Separator);
+        assert_eq!(backend.find_menu_item("Open").unwrap(), Some(0));
+        backend.close();
+        assert!(backend.is_closed());
+        assert!(backend.set_tip("tip").is_err());
+    }
+
+    #[test]
+    fn cached_icon_is_not_loaded_again() {
+        let icon = Icon::file("missing.ico");
+        let hicon = unsafe { CopyIcon(LoadIconW(ptr::null_mut(), IDI_APPLICATION)) };
+        let mut cache = IconCache { entries: vec![(IconCache::key(&icon, 16).unwrap(), hicon)] };
+        unsafe {
+            // loading would fail, as the file does not exist
+            assert_eq!(cache.get_or_load(IconCache::key(&icon, 16).unwrap(), &icon, 16, None).unwrap(), hicon);
+            assert!(cache.get_or_load(IconCache::key(&icon, 32).unwrap(), &icon, 32, None).is_err());
+            assert_eq!(cache.entries.len(), 1);
+            cache.clear(None);
+        }
+        assert!(!is_icon(hicon));
+        assert!(IconCache::key(&owned_icon(), 16).is_none());
+    }
+
+    #[test]
+    fn selected_item_gets_its_own_help_text() {
+        let mut help_texts = HashMap::new();
+        help_texts.insert(1, "Opens a file".to_string());
+        help_texts.insert(2, "Saves the file".to_string());
+        let hmenu = 0x1234 as LPARAM;
+        let select = |id: u32, flags: UINT| MAKELONG(id as u16, flags as u16) as WPARAM;
+        assert_eq!(selected_item_help(&help_texts, select(2, MF_STRING | MF_HILITE), hmenu), Some("Saves the file".to_string()));
+        assert_eq!(selected_item_help(&help_texts, select(1, MF_STRING | MF_HILITE), hmenu), Some("Opens a file".to_string()));
+        assert_eq!(selected_item_help(&help_texts, select(3, MF_STRING | MF_HILITE), hmenu), None);
+        // a submenu is identified by its position, which may equal an item id
+        assert_eq!(selected_item_help(&help_texts, select(1, MF_POPUP | MF_HILITE), hmenu), None);
+        assert_eq!(selected_item_help(&help_texts, select(0, 0xFFFF), 0), None);
+    }
+
+    #[test]
+    fn missing_and_malformed_icon_files_fail_differently() {
+        unsafe {
+            match load_icon(&Icon::file("missing.ico"), 16) {
+                Err(Error(ErrorKind::IconFileNotFound(ref path), _)) => assert_eq!(path, "missing.ico"),
+                _ => panic!("missing icon file loaded"),
+            }
+            let path = ::std::env::temp_dir().join("wna_test_malformed.ico");
+            ::std::fs::write(&path, b"not an icon").unwrap();
+            let path = path.to_str().unwrap().to_string();
+            let result = load_icon(&Icon::file(path.clone()), 16);
+            let _ = ::std::fs::remove_file(&path);
+            match result {
+                Err(Error(ErrorKind::Win32(Op::LoadIconFromFile(ref file_name), _), _)) => assert_eq!(*file_name, path),
+                _ => panic!("malformed icon file loaded"),
+            }
+        }
+    }
+
+    #[test]
+    fn items_past_the_maximum_go_into_the_more_submenu() {
+        let mut config = config();
+        config.menu_max_items = Some(2);
+        let mut window = create("wna_test_more_items", config);
+        let entries = |count: u32| (0..count).map(|id| MenuEntry::Item(id, format!("Item {}", id))).collect::<Vec<_>>();
+        window.set_menu(entries(2)).unwrap();
+        let menu = window.menu_snapshot().unwrap();
+        assert_eq!(menu.iter().map(|item| item.kind.clone()).collect::<Vec<_>>(), vec![MenuItemKind::Item, MenuItemKind::Item]);
+        window.set_menu(entries(3)).unwrap();
+        let menu = window.menu_snapshot().unwrap();
+        assert_eq!(menu.len(), 3);
+        assert_eq!(menu[2].label, "More");
+        match menu[2].kind {
+            MenuItemKind::Submenu(ref children) => assert_eq!(children.iter().map(|item| item.id).collect::<Vec<_>>(), vec![2]),
+            _ => panic!("no overflow submenu"),
+        }
+        window.close();
+    }
+
+    #[test]
+    fn flush_applies_the_updates_held_back_by_the_debounce() {
+        let mut config = config();
+        config.update_debounce = Some(Duration::from_secs(60));
+        let mut window = create("wna_test_flush", config);
+        for tip in &["First", "Second", "Third"] {
+            window.set_tip(tip).unwrap();
+        }
+        window.add_menu_entry(MenuEntry::Item(0, "Open".to_string()), MenuInsertMode::Append).unwrap();
+        assert_eq!(*window.shared.tip.lock().unwrap(), Some("First".to_string()));
+        window.flush().unwrap();
+        assert_eq!(*window.shared.tip.lock().unwrap(), Some("Third".to_string()));
+        assert!(!window.shared.pending_updates.lock().unwrap().timer_set);
+        assert_eq!(window.menu_snapshot().unwrap()[0].label, "Open");
+        window.close();
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn balloon_notifications_are_delivered_as_events() {
+        let (event_sender, events) = channel();
+        let (error_sender, _) = channel();
+        let mut window = Window::create("wna_test_balloon_events", config(), event_sender, error_sender).unwrap();
+        let hwnd = window.handle.as_ref().unwrap().hwnd;
+        for &(notification, ref event) in &[(NIN_BALLOONSHOW, Event::BalloonShown), (NIN_BALLOONTIMEOUT, Event::BalloonDismissed)] {
+            unsafe { SendMessageW(hwnd, NOTIFICATION_MESSAGE_ID, 0, notification as LPARAM); }
+            assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), *event);
+        }
+        window.close();
+    }
+
+    #[test]
+    fn handled_right_click_does_not_open_the_menu() {
+        let mut config = config();
+        config.right_click_handled = true;
+        let (event_sender, events) = channel();
+        let (error_sender, _) = channel();
+        let mut window = Window::create("wna_test_right_click", config, event_sender, error_sender).unwrap();
+        window.add_menu_entry(MenuEntry::Item(0, "Open".to_string()), MenuInsertMode::Append).unwrap();
+        let hwnd = window.handle.as_ref().unwrap().hwnd;
+        let mut result = 0;
+        // an open menu would keep the message from returning until it is closed
+        let returned = unsafe {
+            SendMessageTimeoutW(hwnd, NOTIFICATION_MESSAGE_ID, 0, WM_CONTEXTMENU as LPARAM, SMTO_ABORTIFHUNG, 2000, &mut result)
+        };
+        assert_ne!(returned, 0);
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), Event::RightClick);
+        window.close();
+    }
+
+    #[test]
+    fn truncation_does_not_split_a_surrogate_pair() {
+        let copy = |len: usize, s: &str| {
+            let mut arr = vec![0xFFFFu16; len];
+            copy_str_to_wchar_array(&mut arr, s);
+            let end = arr.iter().position(|&c| c == 0).unwrap();
+            String::from_utf16(&arr[..end]).unwrap()
+        };
+        // U+1F600 takes a surrogate pair in UTF-16
+        assert_eq!(copy(4, "ab\u{1F600}"), "ab");
+        assert_eq!(copy(5, "ab\u{1F600}"), "ab\u{1F600}");
+        assert_eq!(copy(3, "ab\u{1F600}"), "ab");
+        assert_eq!(copy(2, "\u{1F600}"), "");
+        assert_eq!(copy(1, "ab"), "");
+        assert_eq!(copy(8, ""), "");
+    }
+
+    #[test]
+    fn item_with_check_bitmaps_still_toggles() {
+        let mut window = create("wna_test_check_bitmaps", config());
+        window.add_menu_entry(MenuEntry::TriState(0, "Sync".to_string(), MenuItemState::Unchecked), MenuInsertMode::Append).unwrap();
+        window.set_menu_item_check_bitmaps(0, Some(owned_icon()), None).unwrap();
+        let (checked, bitmap) = checkmark(&window, 0);
+        assert!(!checked && !bitmap.is_null());
+        window.set_menu_item_state(0, MenuItemState::Checked).unwrap();
+        assert_eq!(checkmark(&window, 0), (true, bitmap));
+        window.set_menu_item_state(0, MenuItemState::Unchecked).unwrap();
+        assert_eq!(checkmark(&window, 0), (false, bitmap));
+        window.set_menu_item_check_bitmaps(0, None, None).unwrap();
+        assert_eq!(checkmark(&window, 0), (false, ptr::null_mut()));
+        assert!(window.set_menu_item_check_bitmaps(1, Some(owned_icon()), None).is_err());
+        window.close();
+    }
+
+    #[test]
+    fn hidden_window_gets_the_configured_extended_styles() {
+        let ex_style = |window: &Window| unsafe { GetWindowLongPtrW(window.handle.as_ref().unwrap().hwnd, GWL_EXSTYLE) as DWORD };
+        let mut config = config();
+        config.ex_style = WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE;
+        let mut window = create("wna_test_ex_style", config.clone());
+        assert_eq!(ex_style(&window) & (WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE | WS_EX_LAYOUTRTL), WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE);
+        window.close();
+        config.right_to_left = true;
+        let mut window = create("wna_test_ex_style_rtl", config);
+        assert_eq!(ex_style(&window) & WS_EX_LAYOUTRTL, WS_EX_LAYOUTRTL);
+        window.close();
+    }
+
+    #[cfg(feature = "balloons")]
+    #[test]
+    fn opening_an_overflowing_menu_asks_for_the_overflow_notification() {
+        let mut config = config();
+        config.menu_max_items = Some(2);
+        config.overflow_balloon = true;
+        let (event_sender, events) = channel();
+        let (error_sender, _) = channel();
+        let mut window = Window::create("wna_test_overflow_event", config, event_sender, error_sender).unwrap();
+        for id in 0..3 {
+            window.add_menu_entry(MenuEntry::Item(id, format!("Item {}", id)), MenuInsertMode::Append).unwrap();
+        }
+        window.open_menu_at(0, 0, MenuAlignment::TopLeft).unwrap();
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), Event::MenuOverflow(1));
+        // the menu loop dispatches posted messages, and closes the menu on this one
+        let hwnd = window.handle.as_ref().unwrap().hwnd as usize;
+        window.run_on_thread(Box::new(move || unsafe { SendMessageW(hwnd as HWND, WM_CANCELMODE, 0, 0); })).unwrap();
+        window.close();
+    }
+
+}